@@ -1,5 +1,7 @@
 use std::fmt::{self};
 
+use crate::printer::Printer;
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Operator {
     Add,
@@ -48,15 +50,112 @@ pub enum Node {
         lhs: Box<Node>,
         rhs: Box<Node>,
     },
+    // call to a host function
+    Call {
+        name: String,
+        args: Vec<Node>,
+    },
+    // string literal, only found among the arguments of a call
+    Str(String),
+    // input variable, bound to a value when the formula is evaluated
+    Var(String),
 }
 // ANCHOR_END: node
 
+impl Node {
+    // number of levels of the tree, a literal alone is 1.
+    // Walks with an explicit stack, so it is safe to call on any tree
+    pub fn depth(&self) -> usize {
+        let mut max = 0;
+        let mut stack = vec![(self, 1)];
+        while let Some((node, depth)) = stack.pop() {
+            max = max.max(depth);
+            match node {
+                Node::Number(_) | Node::Str(_) | Node::Var(_) => {}
+                Node::UnaryExpr { child, .. } => stack.push((child, depth + 1)),
+                Node::BinaryExpr { lhs, rhs, .. } => {
+                    stack.push((lhs, depth + 1));
+                    stack.push((rhs, depth + 1));
+                }
+                Node::Call { args, .. } => stack.extend(args.iter().map(|arg| (arg, depth + 1))),
+            }
+        }
+        max
+    }
+
+    // number of nodes of the tree, walked like depth
+    pub fn size(&self) -> usize {
+        let mut size = 0;
+        let mut stack = vec![self];
+        while let Some(node) = stack.pop() {
+            size += 1;
+            match node {
+                Node::Number(_) | Node::Str(_) | Node::Var(_) => {}
+                Node::UnaryExpr { child, .. } => stack.push(child),
+                Node::BinaryExpr { lhs, rhs, .. } => {
+                    stack.push(lhs);
+                    stack.push(rhs);
+                }
+                Node::Call { args, .. } => stack.extend(args),
+            }
+        }
+        size
+    }
+}
+
+// the derived drop recurses into the children and overflows the native stack
+// on a deep enough tree, this one moves them to a heap allocated stack instead
+impl Drop for Node {
+    fn drop(&mut self) {
+        let mut stack = vec![];
+        self.take_children(&mut stack);
+        while let Some(mut node) = stack.pop() {
+            node.take_children(&mut stack);
+        }
+    }
+}
+
+impl Node {
+    // moves the node out and leaves a number in its place. Node implements
+    // Drop, so a match cannot move the children out of it
+    pub fn take(&mut self) -> Node {
+        std::mem::replace(self, Node::Number(0.0))
+    }
+
+    fn take_children(&mut self, stack: &mut Vec<Node>) {
+        match self {
+            Node::Number(_) | Node::Str(_) | Node::Var(_) => {}
+            Node::UnaryExpr { child, .. } => stack.push(child.take()),
+            Node::BinaryExpr { lhs, rhs, .. } => {
+                stack.push(lhs.take());
+                stack.push(rhs.take());
+            }
+            Node::Call { args, .. } => stack.append(args),
+        }
+    }
+}
+
+// prints with the minimal parentheses needed to parse back into the same node
 impl fmt::Display for Node {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
-        match &self {
-            Node::Number(n) => write!(f, "{}", n),
-            Node::UnaryExpr { op, child } => write!(f, "{}{}", op, child),
-            Node::BinaryExpr { op, lhs, rhs } => write!(f, "{} {} {}", lhs, op, rhs),
-        }
+        Printer::default().write(f, self)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drop_deep() {
+        // the derived drop would overflow the native stack
+        let deep = crate::testing::deep(1_000_000);
+        assert_eq!(deep.size(), 1_000_000);
+        drop(deep);
+
+        let calls = (0..100_000).fold(Node::Number(1.0), |lhs, _| Node::Call {
+            name: "f".to_string(),
+            args: vec![lhs, Node::Str("a".to_string())],
+        });
+        drop(calls);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,276 @@
+use std::{fmt, thread};
+
+use crate::{ast::Node, host::Host, limits::Limits, optimizer::Optimizer, parser, visit::Visitor};
+
+// the variables a formula is declared with, in the order their values are given
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Inputs {
+    names: Vec<String>,
+}
+
+impl Inputs {
+    pub fn new<S: AsRef<str>>(names: &[S]) -> Self {
+        Self {
+            names: names.iter().map(|name| name.as_ref().to_string()).collect(),
+        }
+    }
+
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    // position of the value of a variable, the first one wins if a name is declared twice
+    pub fn index(&self, name: &str) -> Option<usize> {
+        self.names.iter().position(|declared| declared == name)
+    }
+
+    // every variable of the ast must be declared.
+    // Walks the ast recursively, check the depth of the ast first
+    pub fn check(&self, ast: &[Node]) -> Result<(), UnknownVariable> {
+        let mut checker = Checker { inputs: self, error: None };
+        for node in ast {
+            checker.visit_node(node);
+        }
+        match checker.error {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownVariable(pub String);
+
+impl fmt::Display for UnknownVariable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown variable `{}`", self.0)
+    }
+}
+
+impl std::error::Error for UnknownVariable {}
+
+struct Checker<'a> {
+    inputs: &'a Inputs,
+    // first error found
+    error: Option<UnknownVariable>,
+}
+
+impl Visitor for Checker<'_> {
+    fn visit_var(&mut self, name: &str) {
+        if self.error.is_none() && self.inputs.index(name).is_none() {
+            self.error = Some(UnknownVariable(name.to_string()));
+        }
+    }
+}
+
+// A formula parsed and compiled once by a backend, then evaluated
+// as many times as needed with different values for its inputs.
+// The compiled code is shared between threads, every thread evaluates
+// it with its own fork.
+pub trait CompiledExpr: Send + Sync {
+    fn inputs(&self) -> &Inputs;
+
+    // another evaluator of the same compiled code, with its own state
+    fn fork(&self) -> Box<dyn CompiledExpr>;
+
+    // values are given in the order of inputs(), panics if their number doesn't match
+    fn eval(&mut self, values: &[f64]) -> f64;
+
+    // one column of values per input, every column holds one value per row;
+    // returns the result of every row. Backends that can do better than
+    // evaluating row by row override it
+    fn eval_batch(&mut self, columns: &[&[f64]]) -> Vec<f64> {
+        let rows = rows(self.inputs(), columns);
+        let mut values = vec![0.0; columns.len()];
+        let mut results = Vec::with_capacity(rows);
+        for row in 0..rows {
+            for (value, column) in values.iter_mut().zip(columns) {
+                *value = column[row];
+            }
+            results.push(self.eval(&values));
+        }
+        results
+    }
+}
+
+// number of rows of a batch, panics unless there is one column per input
+// and all columns are as long. Without inputs there is no row
+pub fn rows(inputs: &Inputs, columns: &[&[f64]]) -> usize {
+    assert_eq!(columns.len(), inputs.len(), "wrong number of inputs");
+    let rows = columns.first().map_or(0, |column| column.len());
+    assert!(columns.iter().all(|column| column.len() == rows), "columns of different lengths");
+    rows
+}
+
+// a thread gets at least this many rows, fewer are not worth spawning it
+pub const MIN_ROWS_PER_THREAD: usize = 4096;
+
+// eval_batch with the rows split in contiguous ranges, one per thread.
+// Every thread runs a fork of expr over its range and the results are
+// concatenated in the order of the ranges, so they are the same as the
+// ones of eval_batch whatever the number of threads
+pub fn eval_batch_parallel(expr: &dyn CompiledExpr, columns: &[&[f64]], threads: usize) -> Vec<f64> {
+    assert!(threads > 0, "no thread to evaluate with");
+    let rows = rows(expr.inputs(), columns);
+    let per_thread = rows.div_ceil(threads).max(MIN_ROWS_PER_THREAD);
+    if rows <= per_thread {
+        return expr.fork().eval_batch(columns);
+    }
+
+    thread::scope(|scope| {
+        let handles: Vec<_> = (0..rows)
+            .step_by(per_thread)
+            .map(|start| {
+                let range = start..rows.min(start + per_thread);
+                scope.spawn(move || {
+                    let columns: Vec<&[f64]> = columns.iter().map(|column| &column[range.clone()]).collect();
+                    expr.fork().eval_batch(&columns)
+                })
+            })
+            .collect();
+        let mut results = Vec::with_capacity(rows);
+        for handle in handles {
+            match handle.join() {
+                Ok(part) => results.extend(part),
+                Err(panic) => std::panic::resume_unwind(panic),
+            }
+        }
+        results
+    })
+}
+
+// parses and optimizes the source of a CompiledExpr, the ast is checked
+// against the default limits, the functions of host and the inputs
+pub fn prepare(source: &str, inputs: &Inputs, host: &Host) -> crate::Result<Vec<Node>> {
+    let ast: Vec<Node> = parser::parse_statements(source)?.into_iter().map(|statement| statement.node).collect();
+    if ast.is_empty() {
+        anyhow::bail!("nothing to evaluate");
+    }
+    let ast = Optimizer::default().optimize(ast);
+    Limits::default().check_depth(&ast)?;
+    host.check(&ast)?;
+    inputs.check(&ast)?;
+    Ok(ast)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    #[test]
+    fn check() {
+        let inputs = Inputs::new(&["price", "qty", "discount"]);
+        assert_eq!(inputs.index("qty"), Some(1));
+        assert_eq!(inputs.index("tax"), None);
+        assert_eq!(inputs.check(&parse("price * qty * (1 - discount)").unwrap()), Ok(()));
+        assert_eq!(
+            inputs.check(&parse("price * f(tax, qty)").unwrap()),
+            Err(UnknownVariable("tax".to_string()))
+        );
+        assert_eq!(Inputs::default().check(&parse("1 + 2").unwrap()), Ok(()));
+        assert_eq!(UnknownVariable("tax".to_string()).to_string(), "unknown variable `tax`");
+    }
+
+    // evaluates the ast of a single expression without calls
+    struct Walker {
+        ast: Node,
+        inputs: Inputs,
+    }
+
+    impl Walker {
+        fn walk(&self, node: &Node, values: &[f64]) -> f64 {
+            match node {
+                Node::Number(value) => *value,
+                Node::Var(name) => values[self.inputs.index(name).unwrap()],
+                Node::UnaryExpr { op: crate::Sign::Positive, child } => self.walk(child, values),
+                Node::UnaryExpr { op: crate::Sign::Negative, child } => -self.walk(child, values),
+                Node::BinaryExpr { op, lhs, rhs } => {
+                    let (lhs, rhs) = (self.walk(lhs, values), self.walk(rhs, values));
+                    match op {
+                        crate::Operator::Add => lhs + rhs,
+                        crate::Operator::Sub => lhs - rhs,
+                        crate::Operator::Mul => lhs * rhs,
+                        crate::Operator::Div => lhs / rhs,
+                    }
+                }
+                Node::Call { .. } | Node::Str(_) => panic!("calls are not supported by Walker"),
+            }
+        }
+    }
+
+    impl CompiledExpr for Walker {
+        fn inputs(&self) -> &Inputs {
+            &self.inputs
+        }
+
+        fn fork(&self) -> Box<dyn CompiledExpr> {
+            Box::new(Walker {
+                ast: self.ast.clone(),
+                inputs: self.inputs.clone(),
+            })
+        }
+
+        fn eval(&mut self, values: &[f64]) -> f64 {
+            self.walk(&self.ast, values)
+        }
+    }
+
+    #[test]
+    fn eval_batch() {
+        let inputs = Inputs::new(&["price", "qty"]);
+        let ast = parse("price * qty - 1 + -price / +2").unwrap().remove(0);
+        let mut walker = Walker { ast, inputs };
+        let (prices, quantities) = ([1.0, 2.0, 3.0], [4.0, 5.0, 6.0]);
+        assert_eq!(walker.eval_batch(&[&prices, &quantities]), vec![2.5, 8.0, 15.5]);
+        assert_eq!(walker.eval_batch(&[&[], &[]]), vec![]);
+        assert_eq!(rows(&Inputs::default(), &[]), 0);
+    }
+
+    #[test]
+    fn eval_batch_parallel() {
+        let inputs = Inputs::new(&["x", "y"]);
+        let walker = Walker {
+            ast: parse("x * y - 1").unwrap().remove(0),
+            inputs,
+        };
+        let rows = MIN_ROWS_PER_THREAD * 3 + 5;
+        let xs: Vec<f64> = (0..rows).map(|row| row as f64).collect();
+        let ys: Vec<f64> = (0..rows).map(|row| (row % 7) as f64 - 3.0).collect();
+        let expected = walker.fork().eval_batch(&[&xs, &ys]);
+        for threads in [1, 2, 3, 4, 16] {
+            assert_eq!(super::eval_batch_parallel(&walker, &[&xs, &ys], threads), expected);
+        }
+        assert_eq!(super::eval_batch_parallel(&walker, &[&xs[..10], &ys[..10]], 4), expected[..10]);
+        assert_eq!(super::eval_batch_parallel(&walker, &[&[], &[]], 4), vec![]);
+    }
+
+    #[test]
+    #[should_panic(expected = "columns of different lengths")]
+    fn uneven_columns() {
+        rows(&Inputs::new(&["x", "y"]), &[&[1.0, 2.0], &[1.0]]);
+    }
+
+    #[test]
+    fn prepare() {
+        let inputs = Inputs::new(&["x"]);
+        assert_eq!(super::prepare("1; x * (2 + 1)", &inputs, &Host::default()).unwrap(), parse("1; x * 3").unwrap());
+        for (source, error) in [
+            ("# nothing", "nothing to evaluate"),
+            ("y", "unknown variable `y`"),
+            ("f(x)", "unknown function `f`"),
+        ] {
+            let found = super::prepare(source, &inputs, &Host::default()).unwrap_err();
+            assert_eq!(found.to_string(), error);
+        }
+        assert!(super::prepare("x +", &inputs, &Host::default()).is_err());
+    }
+}
@@ -0,0 +1,229 @@
+use std::ops::Range;
+
+use crate::{parser::parse_statements, printer::Printer, Result};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FormatOptions {
+    // statements longer than this are wrapped over several lines
+    pub max_width: usize,
+    // indentation of wrapped lines
+    pub indent: usize,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self {
+            max_width: 80,
+            indent: 4,
+        }
+    }
+}
+
+// a `# ...` comment, the range covers the text up to the end of the line
+#[derive(Debug)]
+struct Comment {
+    span: Range<usize>,
+    // nothing but whitespace precedes the comment on its line
+    own_line: bool,
+}
+
+fn comments(source: &str) -> Vec<Comment> {
+    let mut comments = vec![];
+    let mut line_start = 0;
+    let mut chars = source.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\n' => line_start = i + 1,
+            '#' => {
+                let end = source[i..].find('\n').map_or(source.len(), |n| i + n);
+                comments.push(Comment {
+                    span: i..end,
+                    own_line: source[line_start..i].trim().is_empty(),
+                });
+                // skip the rest of the comment, the newline is handled above
+                while chars.clone().next().is_some_and(|(j, _)| j < end) {
+                    chars.next();
+                }
+            }
+            // a "#" in a string does not start a comment
+            '"' => {
+                chars.by_ref().find(|(_, c)| *c == '"');
+            }
+            _ => {}
+        }
+    }
+    comments
+}
+
+// a formatted line of the output and the part of the source it came from
+enum Item<'a> {
+    Comment(&'a str, Range<usize>),
+    Statement {
+        code: String,
+        span: Range<usize>,
+        // comments found inside the statement, they are moved above it
+        inner: Vec<&'a str>,
+        // comments that follow the statement on its last line
+        trailing: Vec<&'a str>,
+    },
+}
+
+impl Item<'_> {
+    fn span(&self) -> Range<usize> {
+        match self {
+            Item::Comment(_, span) => span.clone(),
+            Item::Statement { span, .. } => span.clone(),
+        }
+    }
+}
+
+// rewrites a script in canonical style:
+// - one statement per line, terminated by ";"
+// - a single space around infix operators and none after a sign
+// - only the parentheses required by precedence and associativity
+// - numbers written as in the source: 0xFF, 1_000 and 1e300 stay as they are
+// - statements longer than max_width wrapped at their outermost operators
+// - comments kept, runs of blank lines collapsed into one
+pub fn format_source(source: &str, options: &FormatOptions) -> Result<String> {
+    let statements = parse_statements(source)?;
+    let printer = Printer::default();
+
+    let mut items: Vec<Item> = statements
+        .iter()
+        .map(|statement| Item::Statement {
+            // leave room for the terminating ";"
+            code: printer.print_wrapped_literals(
+                &statement.node,
+                &statement.literals,
+                options.max_width.saturating_sub(1),
+                options.indent,
+            ),
+            span: statement.span.clone(),
+            inner: vec![],
+            trailing: vec![],
+        })
+        .collect();
+
+    for comment in comments(source) {
+        let text = source[comment.span.clone()].trim_end();
+        let start = comment.span.start;
+        let owner = statements.iter().position(|s| s.span.contains(&start));
+        let previous = statements.iter().rposition(|s| s.span.end <= start);
+
+        match (owner, previous) {
+            (Some(index), _) => {
+                if let Item::Statement { inner, .. } = &mut items[index] {
+                    inner.push(text);
+                }
+            }
+            (None, Some(index)) if !comment.own_line => {
+                if let Item::Statement { trailing, .. } = &mut items[index] {
+                    trailing.push(text);
+                }
+            }
+            _ => items.push(Item::Comment(text, comment.span)),
+        }
+    }
+    items.sort_by_key(|item| item.span().start);
+
+    let mut out = String::new();
+    let mut previous_end = None;
+    for item in items {
+        let span = item.span();
+        // keep a single blank line wherever the source had at least one
+        // (only whitespace, ";" and trailing comments sit between two items)
+        if let Some(end) = previous_end {
+            if source[end..span.start].matches('\n').count() > 1 {
+                out.push('\n');
+            }
+        }
+
+        match item {
+            Item::Comment(text, _) => {
+                out.push_str(text);
+                out.push('\n');
+            }
+            Item::Statement { code, inner, trailing, .. } => {
+                for comment in inner {
+                    out.push_str(comment);
+                    out.push('\n');
+                }
+                out.push_str(&code);
+                out.push(';');
+
+                let mut trailing = trailing.into_iter();
+                if let Some(comment) = trailing.next() {
+                    out.push(' ');
+                    out.push_str(comment);
+                }
+                out.push('\n');
+                for comment in trailing {
+                    out.push_str(comment);
+                    out.push('\n');
+                }
+            }
+        }
+        previous_end = Some(span.end);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    fn format(source: &str) -> String {
+        format_source(source, &FormatOptions::default()).unwrap()
+    }
+
+    #[test]
+    fn spacing_and_parens() {
+        assert_eq!(format("1+2"), "1 + 2;\n");
+        assert_eq!(format("  ((1 +2))*   - 3 ;"), "(1 + 2) * -3;\n");
+        assert_eq!(format("1-(2-3);(1-2)-3"), "1 - (2 - 3);\n1 - 2 - 3;\n");
+        assert_eq!(format(""), "");
+        assert_eq!(format("rate( \"#EUR\" )*2 # rate"), "rate(\"#EUR\") * 2; # rate\n");
+    }
+
+    #[test]
+    fn comments_and_blank_lines() {
+        let source = "# header\n\n\n1+2; # three\n# before\n3*4\n\n# footer\n";
+        assert_eq!(format(source), "# header\n\n1 + 2; # three\n# before\n3 * 4;\n\n# footer\n");
+
+        // comments inside a statement are moved above it
+        let source = "1 + # one\n  2 # two\n  ; 3";
+        assert_eq!(format(source), "# one\n1 + 2; # two\n3;\n");
+    }
+
+    #[test]
+    fn long_statements() {
+        let source = "100000 * 2 + 300000 * 4 - 500000 / 6 + 700000 * 8 - 900000 / 10 + 1100000 * (12 - 13)";
+        let expected = "100000 * 2\n    + 300000 * 4\n    - 500000 / 6\n    + 700000 * 8\n    - 900000 / 10\n    + 1100000 * (12 - 13);\n";
+        assert_eq!(format(source), expected);
+        assert_eq!(parse(source).unwrap(), parse(expected).unwrap());
+
+        let narrow = FormatOptions { max_width: 10, indent: 2 };
+        assert_eq!(format_source("1 + 2 + 3 + 4", &narrow).unwrap(), "1\n  + 2\n  + 3\n  + 4;\n");
+    }
+
+    #[test]
+    fn idempotent() {
+        let source = "#a\n1+(2+3) #b\n;\n\n\n-(4*5)/6 ; 7; #c\n# d\n\n\n100000 * 2 + 300000 * 4 - 500000 / 6 + 700000 * 8 - 900000 / 10";
+        let formatted = format(source);
+        assert_eq!(format(&formatted), formatted);
+        assert_eq!(parse(source).unwrap(), parse(&formatted).unwrap());
+    }
+
+    #[test]
+    fn number_literals() {
+        let source = "0xFF;\n0b101;\n1_000;\n1e-9;\n1e300;\n";
+        assert_eq!(format(source), source);
+        assert_eq!(format("f(0XFF,1E3)+-.5"), "f(0XFF, 1E3) + -.5;\n");
+    }
+
+    #[test]
+    fn syntax_error() {
+        assert!(format_source("1 +", &FormatOptions::default()).is_err());
+    }
+}
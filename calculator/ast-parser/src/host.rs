@@ -0,0 +1,249 @@
+use std::{collections::HashMap, fmt, sync::Arc};
+
+use crate::{ast::Node, visit::Visitor};
+
+// type of a parameter of a host function, every host function returns a number
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Type {
+    Number,
+    Str,
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Number => write!(f, "number"),
+            Type::Str => write!(f, "string"),
+        }
+    }
+}
+
+// an argument as the host function receives it,
+// arguments always match the declared parameter types
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Arg<'a> {
+    Number(f64),
+    Str(&'a str),
+}
+
+impl<'a> Arg<'a> {
+    pub fn as_number(self) -> Option<f64> {
+        match self {
+            Arg::Number(val) => Some(val),
+            Arg::Str(_) => None,
+        }
+    }
+
+    pub fn as_str(self) -> Option<&'a str> {
+        match self {
+            Arg::Str(val) => Some(val),
+            Arg::Number(_) => None,
+        }
+    }
+}
+
+// shared between threads and between the backends, cloning a Host is cheap
+pub type HostFn = Arc<dyn Fn(&[Arg]) -> f64 + Send + Sync>;
+
+#[derive(Clone)]
+pub struct HostFunction {
+    pub params: Vec<Type>,
+    function: HostFn,
+}
+
+impl HostFunction {
+    pub fn call(&self, args: &[Arg]) -> f64 {
+        (self.function)(args)
+    }
+
+    // a call of this function by name passing arguments of these types,
+    // shared by the ast checker and the bytecode of the vm
+    pub fn check_signature(&self, name: &str, args: &[Type]) -> Result<(), HostError> {
+        if self.params.len() != args.len() {
+            return Err(HostError::WrongArity {
+                name: name.to_string(),
+                expected: self.params.len(),
+                found: args.len(),
+            });
+        }
+        for (position, (param, arg)) in self.params.iter().zip(args).enumerate() {
+            if param != arg {
+                return Err(HostError::WrongType {
+                    name: name.to_string(),
+                    position,
+                    expected: *param,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for HostFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HostFunction").field("params", &self.params).finish()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostError {
+    UnknownFunction(String),
+    WrongArity { name: String, expected: usize, found: usize },
+    WrongType { name: String, position: usize, expected: Type },
+    // a string literal anywhere but in the arguments of a call
+    StrayString(String),
+}
+
+impl fmt::Display for HostError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HostError::UnknownFunction(name) => write!(f, "unknown function `{}`", name),
+            HostError::WrongArity { name, expected, found } => {
+                write!(f, "`{}` takes {} arguments, {} given", name, expected, found)
+            }
+            HostError::WrongType { name, position, expected } => {
+                write!(f, "argument {} of `{}` must be a {}", position + 1, name, expected)
+            }
+            HostError::StrayString(value) => write!(f, "string \"{}\" is only allowed as an argument", value),
+        }
+    }
+}
+
+impl std::error::Error for HostError {}
+
+// Host holds the functions an embedder exposes to formulas, by name.
+// Every backend checks the calls of an ast against it before running it,
+// so a host function only ever sees arguments of the declared types.
+#[derive(Debug, Clone, Default)]
+pub struct Host {
+    functions: HashMap<String, HostFunction>,
+}
+
+impl Host {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // registering a name again replaces the previous function
+    pub fn register<F>(&mut self, name: &str, params: &[Type], function: F) -> &mut Self
+    where
+        F: Fn(&[Arg]) -> f64 + Send + Sync + 'static,
+    {
+        let function = HostFunction {
+            params: params.to_vec(),
+            function: Arc::new(function),
+        };
+        self.functions.insert(name.to_string(), function);
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&HostFunction> {
+        self.functions.get(name)
+    }
+
+    // calls must name a registered function with arguments of the right types:
+    // a string literal for a string parameter, any other expression for a number.
+    // Walks the ast recursively, check the depth of the ast first
+    pub fn check(&self, ast: &[Node]) -> Result<(), HostError> {
+        let mut checker = Checker { host: self, error: None };
+        for node in ast {
+            checker.visit_node(node);
+        }
+        match checker.error {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+}
+
+struct Checker<'a> {
+    host: &'a Host,
+    // first error found
+    error: Option<HostError>,
+}
+
+impl Checker<'_> {
+    fn check_call(&self, name: &str, args: &[Node]) -> Result<(), HostError> {
+        let function = self
+            .host
+            .get(name)
+            .ok_or_else(|| HostError::UnknownFunction(name.to_string()))?;
+        let types: Vec<Type> = args
+            .iter()
+            .map(|arg| match arg {
+                Node::Str(_) => Type::Str,
+                _ => Type::Number,
+            })
+            .collect();
+        function.check_signature(name, &types)
+    }
+}
+
+impl Visitor for Checker<'_> {
+    fn visit_call(&mut self, name: &str, args: &[Node]) {
+        if self.error.is_some() {
+            return;
+        }
+        if let Err(error) = self.check_call(name, args) {
+            self.error = Some(error);
+            return;
+        }
+        // string arguments are fine, anything below them is visited as usual
+        for arg in args.iter().filter(|arg| !matches!(arg, Node::Str(_))) {
+            self.visit_node(arg);
+        }
+    }
+
+    fn visit_str(&mut self, value: &str) {
+        self.error.get_or_insert_with(|| HostError::StrayString(value.to_string()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    fn host() -> Host {
+        let mut host = Host::new();
+        host.register("rate", &[Type::Str], |args| match args[0].as_str() {
+            Some("EUR") => 1.1,
+            _ => f64::NAN,
+        })
+        .register("max", &[Type::Number, Type::Number], |args| {
+            args[0].as_number().unwrap().max(args[1].as_number().unwrap())
+        });
+        host
+    }
+
+    #[test]
+    fn check() {
+        let host = host();
+        assert_eq!(host.check(&parse("max(rate(\"EUR\"), 2) * 3; 4").unwrap()), Ok(()));
+        assert_eq!(host.get("rate").unwrap().call(&[Arg::Str("EUR")]), 1.1);
+
+        let errors = [
+            ("1 + nope()", HostError::UnknownFunction("nope".to_string())),
+            ("max(1)", HostError::WrongArity { name: "max".to_string(), expected: 2, found: 1 }),
+            ("rate(1)", HostError::WrongType { name: "rate".to_string(), position: 0, expected: Type::Str }),
+            (
+                "max(1, -max(2, \"3\"))",
+                HostError::WrongType { name: "max".to_string(), position: 1, expected: Type::Number },
+            ),
+        ];
+        for (source, error) in errors {
+            assert_eq!(host.check(&parse(source).unwrap()), Err(error), "{}", source);
+        }
+
+        // only the parser keeps strings inside calls, a rewritten ast may not
+        let stray = Node::UnaryExpr {
+            op: crate::Sign::Negative,
+            child: Box::new(Node::Str("EUR".to_string())),
+        };
+        assert_eq!(host.check(&[stray]), Err(HostError::StrayString("EUR".to_string())));
+        assert_eq!(
+            HostError::WrongArity { name: "max".to_string(), expected: 2, found: 1 }.to_string(),
+            "`max` takes 2 arguments, 1 given"
+        );
+    }
+}
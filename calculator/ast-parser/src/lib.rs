@@ -1,7 +1,23 @@
 pub mod ast;
+pub mod expr;
+pub mod formatter;
+pub mod host;
+pub mod limits;
+pub mod optimizer;
 pub mod parser;
+pub mod printer;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod visit;
 
 pub use crate::ast::{Node, Operator, Sign};
+pub use crate::expr::{CompiledExpr, Inputs, UnknownVariable};
+pub use crate::formatter::{format_source, FormatOptions};
+pub use crate::host::{Arg, Host, HostError, HostFunction, Type};
+pub use crate::limits::{Fuel, LimitError, Limits};
+pub use crate::optimizer::Optimizer;
+pub use crate::printer::{Parens, PrintOptions, Printer, Spacing};
+pub use crate::visit::{Fold, Visitor, VisitorMut};
 
 pub type Result<T> = anyhow::Result<T>;
 
@@ -16,4 +32,9 @@ pub trait Compile {
         println!("{:?}", ast);
         Self::from_ast(ast)
     }
+
+    // same as from_ast, the ast goes through the optimizer before compiling
+    fn from_ast_optimized(ast: Vec<Node>, optimizer: &Optimizer) -> Self::Output {
+        Self::from_ast(optimizer.optimize(ast))
+    }
 }
@@ -0,0 +1,148 @@
+use std::fmt;
+
+use crate::ast::Node;
+
+// Limits bound the resources a script may use, so a formula coming from
+// an untrusted source can neither run forever nor exhaust memory.
+// - fuel: number of steps the evaluation may take, one step per node
+//   for a tree walker and one per instruction for a vm
+// - max_stack_depth: number of values a vm stack may hold at once
+// - max_nesting_depth: how deep parentheses may be nested in the source
+// - max_depth: how deep the ast may get, every sign and operator adds a level.
+//   The parser and the backends walk the ast recursively, this keeps them
+//   from overflowing the native stack
+// - max_source_len: length of the source in bytes
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Limits {
+    pub fuel: u64,
+    pub max_stack_depth: usize,
+    pub max_nesting_depth: usize,
+    pub max_depth: usize,
+    pub max_source_len: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            fuel: 10_000_000,
+            max_stack_depth: 512,
+            max_nesting_depth: 256,
+            max_depth: 1024,
+            max_source_len: 1 << 20,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LimitError {
+    OutOfFuel { fuel: u64 },
+    StackOverflow { max: usize },
+    NestingTooDeep { max: usize },
+    TooDeep { max: usize },
+    SourceTooLong { len: usize, max: usize },
+}
+
+impl fmt::Display for LimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitError::OutOfFuel { fuel } => write!(f, "out of fuel after {} steps", fuel),
+            LimitError::StackOverflow { max } => write!(f, "stack holds more than {} values", max),
+            LimitError::NestingTooDeep { max } => {
+                write!(f, "expression nested deeper than {} levels", max)
+            }
+            LimitError::TooDeep { max } => write!(f, "ast deeper than {} levels", max),
+            LimitError::SourceTooLong { len, max } => {
+                write!(f, "source is {} bytes long, at most {} are allowed", len, max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LimitError {}
+
+impl Limits {
+    // for walkers that cannot track the depth as they go
+    pub fn check_depth(&self, ast: &[Node]) -> std::result::Result<(), LimitError> {
+        if ast.iter().any(|node| node.depth() > self.max_depth) {
+            return Err(LimitError::TooDeep { max: self.max_depth });
+        }
+        Ok(())
+    }
+
+    // for walkers spending one unit of fuel per node: every node of ast
+    // is evaluated at most once, so the walk cannot run out if this holds
+    pub fn check_fuel(&self, ast: &[Node]) -> std::result::Result<(), LimitError> {
+        let size: usize = ast.iter().map(Node::size).sum();
+        if size as u64 > self.fuel {
+            return Err(LimitError::OutOfFuel { fuel: self.fuel });
+        }
+        Ok(())
+    }
+}
+
+// counts down the steps left to an evaluation
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Fuel {
+    initial: u64,
+    left: u64,
+}
+
+impl Fuel {
+    pub fn new(fuel: u64) -> Self {
+        Self { initial: fuel, left: fuel }
+    }
+
+    // takes one step, fails once the fuel is used up
+    pub fn consume(&mut self) -> std::result::Result<(), LimitError> {
+        if self.left == 0 {
+            return Err(LimitError::OutOfFuel { fuel: self.initial });
+        }
+        self.left -= 1;
+        Ok(())
+    }
+
+    pub fn used(&self) -> u64 {
+        self.initial - self.left
+    }
+
+    pub fn left(&self) -> u64 {
+        self.left
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fuel() {
+        let mut fuel = Fuel::new(2);
+        assert_eq!(fuel.consume(), Ok(()));
+        assert_eq!(fuel.consume(), Ok(()));
+        assert_eq!(fuel.used(), 2);
+        assert_eq!(fuel.consume(), Err(LimitError::OutOfFuel { fuel: 2 }));
+        assert_eq!(fuel.used(), 2);
+    }
+
+    #[test]
+    fn depth() {
+        let ast = crate::parser::parse("1; -(2 + 3) * 4").unwrap();
+        assert_eq!(ast[1].depth(), 4);
+
+        let limits = Limits { max_depth: 4, ..Limits::default() };
+        assert_eq!(limits.check_depth(&ast), Ok(()));
+        let limits = Limits { max_depth: 3, ..Limits::default() };
+        assert_eq!(limits.check_depth(&ast), Err(LimitError::TooDeep { max: 3 }));
+    }
+
+    #[test]
+    fn size() {
+        let ast = crate::parser::parse("1; -(2 + 3) * f(x, \"a\")").unwrap();
+        assert_eq!(ast[1].size(), 8);
+
+        let limits = Limits { fuel: 9, ..Limits::default() };
+        assert_eq!(limits.check_fuel(&ast), Ok(()));
+        let limits = Limits { fuel: 8, ..Limits::default() };
+        assert_eq!(limits.check_fuel(&ast), Err(LimitError::OutOfFuel { fuel: 8 }));
+    }
+}
@@ -0,0 +1,167 @@
+use crate::{
+    ast::{Node, Operator, Sign},
+    visit::Fold,
+};
+
+// Optimizer rewrites the ast into a cheaper one that evaluates to exactly
+// the same f64, following IEEE 754 semantics (signed zeros, NaN, infinities):
+// - constant folding: 2 * 3 + x becomes 6 + x
+// - identities: x * 1, 1 * x, x / 1, x - 0, x + -0, -0 + x and --x become x
+// - unary plus is dropped: +x becomes x
+//
+// x + 0 is kept on purpose as -0 + 0 is +0, not -0
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Optimizer {
+    pub fold_constants: bool,
+    pub simplify_identities: bool,
+}
+
+impl Default for Optimizer {
+    fn default() -> Self {
+        Self {
+            fold_constants: true,
+            simplify_identities: true,
+        }
+    }
+}
+
+impl Optimizer {
+    // leaves the ast untouched, handy to debug a backend against the raw ast
+    pub fn disabled() -> Self {
+        Self {
+            fold_constants: false,
+            simplify_identities: false,
+        }
+    }
+
+    pub fn optimize(&self, ast: Vec<Node>) -> Vec<Node> {
+        let mut optimizer = *self;
+        ast.into_iter().map(|node| optimizer.fold_node(node)).collect()
+    }
+}
+
+fn is_zero(value: f64, negative: bool) -> bool {
+    value == 0.0 && value.is_sign_negative() == negative
+}
+
+impl Fold for Optimizer {
+    fn fold_unary(&mut self, op: Sign, child: Node) -> Node {
+        let mut child = self.fold_node(child);
+
+        match (op, &mut child) {
+            (Sign::Positive, _) if self.simplify_identities => child,
+            (Sign::Negative, Node::Number(n)) if self.fold_constants => Node::Number(-*n),
+            (
+                Sign::Negative,
+                Node::UnaryExpr {
+                    op: Sign::Negative,
+                    child,
+                },
+            ) if self.simplify_identities => child.take(),
+            (op, _) => Node::UnaryExpr {
+                op,
+                child: Box::new(child),
+            },
+        }
+    }
+
+    fn fold_binary(&mut self, op: Operator, lhs: Node, rhs: Node) -> Node {
+        let lhs = self.fold_node(lhs);
+        let rhs = self.fold_node(rhs);
+
+        if let (true, Node::Number(lhs), Node::Number(rhs)) = (self.fold_constants, &lhs, &rhs) {
+            return Node::Number(match op {
+                Operator::Add => lhs + rhs,
+                Operator::Sub => lhs - rhs,
+                Operator::Mul => lhs * rhs,
+                Operator::Div => lhs / rhs,
+            });
+        }
+
+        if self.simplify_identities {
+            match (op, &lhs, &rhs) {
+                (Operator::Mul, _, Node::Number(one)) | (Operator::Div, _, Node::Number(one))
+                    if *one == 1.0 =>
+                {
+                    return lhs
+                }
+                (Operator::Mul, Node::Number(one), _) if *one == 1.0 => return rhs,
+                (Operator::Sub, _, Node::Number(zero)) if is_zero(*zero, false) => return lhs,
+                (Operator::Add, _, Node::Number(zero)) if is_zero(*zero, true) => return lhs,
+                (Operator::Add, Node::Number(zero), _) if is_zero(*zero, true) => return rhs,
+                _ => {}
+            }
+        }
+
+        Node::BinaryExpr {
+            op,
+            lhs: Box::new(lhs),
+            rhs: Box::new(rhs),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    fn optimize_with(optimizer: Optimizer, source: &str) -> String {
+        optimizer.optimize(parse(source).unwrap())[0].to_string()
+    }
+
+    #[test]
+    fn fold_constants() {
+        let optimizer = Optimizer::default();
+        assert_eq!(optimize_with(optimizer, "2 * 3 + 1"), "7");
+        assert_eq!(optimize_with(optimizer, "-(1 + 2) * 3"), "-9");
+        assert_eq!(optimize_with(optimizer, "1 / 0"), "(1 / 0)");
+        assert_eq!(
+            optimizer.optimize(parse("1 - 1 * 0.1 / 3").unwrap()),
+            vec![Node::Number(1.0 - 1.0 * 0.1 / 3.0)]
+        );
+
+        let optimized = optimizer.optimize(parse("0 / 0").unwrap());
+        assert!(matches!(optimized[0], Node::Number(n) if n.is_nan()));
+        let optimized = optimizer.optimize(parse("-0").unwrap());
+        assert!(matches!(optimized[0], Node::Number(n) if is_zero(n, true)));
+    }
+
+    #[test]
+    fn simplify_identities() {
+        let optimizer = Optimizer {
+            fold_constants: false,
+            simplify_identities: true,
+        };
+        assert_eq!(optimize_with(optimizer, "(2 + 3) * 1"), "2 + 3");
+        assert_eq!(optimize_with(optimizer, "1 * (2 + 3)"), "2 + 3");
+        assert_eq!(optimize_with(optimizer, "(2 + 3) / 1"), "2 + 3");
+        assert_eq!(optimize_with(optimizer, "(2 + 3) - 0"), "2 + 3");
+        assert_eq!(optimize_with(optimizer, "+(2 + 3)"), "2 + 3");
+        assert_eq!(optimize_with(optimizer, "--(2 + 3)"), "2 + 3");
+        assert_eq!(optimize_with(optimizer, "-+-(2 + 3)"), "2 + 3");
+
+        // not an identity for -0
+        assert_eq!(optimize_with(optimizer, "(2 + 3) + 0"), "2 + 3 + 0");
+        assert_eq!(optimize_with(optimizer, "0 + (2 + 3)"), "0 + (2 + 3)");
+        assert_eq!(optimize_with(optimizer, "(2 + 3) - -0"), "2 + 3 - -0");
+        assert_eq!(optimize_with(optimizer, "1 / (2 + 3)"), "1 / (2 + 3)");
+    }
+
+    #[test]
+    fn signed_zeros() {
+        let optimizer = Optimizer::default();
+        // -0 + 0 = 0, -0 - 0 = -0, -0 * 1 = -0
+        for (source, negative) in [("-0 + 0", false), ("-0 - 0", true), ("-0 * 1", true), ("0 * -1", true)] {
+            let optimized = optimizer.optimize(parse(source).unwrap());
+            assert!(matches!(optimized[0], Node::Number(n) if is_zero(n, negative)), "{}", source);
+        }
+    }
+
+    #[test]
+    fn disabled() {
+        for source in ["+1", "2 * 3 + 1", "(2 + 3) * 1", "--1"] {
+            assert_eq!(Optimizer::disabled().optimize(parse(source).unwrap()), parse(source).unwrap());
+        }
+    }
+}
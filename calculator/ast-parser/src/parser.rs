@@ -1,8 +1,10 @@
 #![allow(clippy::upper_case_acronyms, clippy::result_large_err)]
 
-use pest::{self, Parser, pratt_parser::PrattParser, iterators::Pairs};
+use std::ops::Range;
 
-use crate::{ast::{Node, Operator}, Sign};
+use pest::{self, Parser, Position, Span, pratt_parser::PrattParser, iterators::{Pair, Pairs}, error::ErrorVariant};
+
+use crate::{ast::{Node, Operator}, limits::{LimitError, Limits}, Sign};
 
 // https://pest.rs/book/precedence.html?highlight=prefix#operator-precedence
 lazy_static::lazy_static! {
@@ -13,9 +15,10 @@ lazy_static::lazy_static! {
         // Precedence is defined lowest to highest
         PrattParser::new()
             // Addition and subtract have equal precedence
-            .op(Op::prefix(positive) | Op::prefix(negative))
             .op(Op::infix(plus, Left) | Op::infix(minus, Left))
             .op(Op::infix(mul, Left) | Op::infix(div, Left))
+            // signs bind tighter than any infix operator, so `-2 * 3` is `(-2) * 3`
+            .op(Op::prefix(positive) | Op::prefix(negative))
     };
 }
 
@@ -27,32 +30,147 @@ struct CalcParser;
 
 // ANCHOR: parse_source
 pub fn parse(source: &str) -> std::result::Result<Vec<Node>, pest::error::Error<Rule>> {
+    parse_with_limits(source, &Limits::default())
+}
+
+// same as parse, but the source length and the depth of the ast are checked against limits
+pub fn parse_with_limits(source: &str, limits: &Limits) -> std::result::Result<Vec<Node>, pest::error::Error<Rule>> {
+    check_source_len(source, limits)?;
+    check_nesting(source, limits)?;
     let mut ast = vec![];
-    let pairs = CalcParser::parse(Rule::Program, source).unwrap_or_else(
-        |e| {
-            panic!("error = {:?}", e);
+    for pair in CalcParser::parse(Rule::Program, source)? {
+        if let Rule::Expr = pair.as_rule() {
+            ast.push(parse_expr(pair.into_inner(), 0, limits)?);
         }
-    );
+    }
+    Ok(ast)
+}
 
-    for pair in pairs {
+// a top level expression of a script and the byte range it spans in the source
+#[derive(Debug, Clone, PartialEq)]
+pub struct Statement {
+    pub node: Node,
+    pub span: Range<usize>,
+    // source text of every number literal, in the order they are written
+    pub literals: Vec<String>,
+}
+
+// same as parse, but every expression keeps track of where it sits in the source
+pub fn parse_statements(source: &str) -> std::result::Result<Vec<Statement>, pest::error::Error<Rule>> {
+    let limits = Limits::default();
+    check_source_len(source, &limits)?;
+    check_nesting(source, &limits)?;
+    let mut statements = vec![];
+    for pair in CalcParser::parse(Rule::Program, source)? {
         if let Rule::Expr = pair.as_rule() {
-            ast.push(parse_binary_expr(pair.into_inner()));
+            let span = pair.as_span().start()..pair.as_span().end();
+            let literals = pair
+                .clone()
+                .into_inner()
+                .flatten()
+                .filter(|pair| pair.as_rule() == Rule::Number)
+                .map(|pair| pair.as_str().to_string())
+                .collect();
+            statements.push(Statement {
+                node: parse_expr(pair.into_inner(), 0, &limits)?,
+                span,
+                literals,
+            });
         }
     }
-    Ok(ast)
+    Ok(statements)
+}
+
+fn check_source_len(source: &str, limits: &Limits) -> std::result::Result<(), pest::error::Error<Rule>> {
+    if source.len() > limits.max_source_len {
+        let error = LimitError::SourceTooLong { len: source.len(), max: limits.max_source_len };
+        return Err(pest::error::Error::new_from_pos(
+            ErrorVariant::CustomError { message: error.to_string() },
+            Position::from_start(source),
+        ));
+    }
+    Ok(())
 }
 
-pub fn parse_binary_expr(pairs: Pairs<Rule>) -> Node {
+pub fn parse_binary_expr(pairs: Pairs<Rule>) -> std::result::Result<Node, pest::error::Error<Rule>> {
+    parse_expr(pairs, 0, &Limits::default())
+}
+
+// pest and the pratt parser recurse once per parenthesis and once per prefix sign,
+// so both are bounded on the raw source before it reaches them
+fn check_nesting(source: &str, limits: &Limits) -> std::result::Result<(), pest::error::Error<Rule>> {
+    let error = |error: LimitError, pos: usize| {
+        pest::error::Error::new_from_pos(
+            ErrorVariant::CustomError { message: error.to_string() },
+            Position::new(source, pos).unwrap(),
+        )
+    };
+
+    // levels added by every open parenthesis, itself and the signs in front of it
+    let mut parens = vec![];
+    let mut depth = 0;
+    let mut signs = 0;
+    let mut chars = source.char_indices();
+    while let Some((pos, c)) = chars.next() {
+        match c {
+            '#' => {
+                chars.by_ref().find(|(_, c)| *c == '\n');
+            }
+            // parentheses in a string are not nesting
+            '"' => {
+                chars.by_ref().find(|(_, c)| *c == '"');
+            }
+            '+' | '-' => signs += 1,
+            '(' => {
+                parens.push(signs + 1);
+                depth += signs + 1;
+                signs = 0;
+                if parens.len() > limits.max_nesting_depth {
+                    return Err(error(LimitError::NestingTooDeep { max: limits.max_nesting_depth }, pos));
+                }
+            }
+            ')' => {
+                depth -= parens.pop().unwrap_or(0);
+                signs = 0;
+            }
+            c if c.is_whitespace() => {}
+            _ => signs = 0,
+        }
+        if depth + signs > limits.max_depth {
+            return Err(error(LimitError::TooDeep { max: limits.max_depth }, pos));
+        }
+    }
+    Ok(())
+}
+
+fn parse_expr(pairs: Pairs<Rule>, parens: usize, limits: &Limits) -> std::result::Result<Node, pest::error::Error<Rule>> {
+    parse_expr_with_depth(pairs, parens, limits).map(|(node, _)| node)
+}
+
+// parens counts the parentheses around the expression,
+// the depth of the ast is returned along with it and checked as the tree
+// is built, so a tree too deep to be walked is never even allocated
+fn parse_expr_with_depth(
+    pairs: Pairs<Rule>,
+    parens: usize,
+    limits: &Limits,
+) -> std::result::Result<(Node, usize), pest::error::Error<Rule>> {
     PRATT_PARSER
         .map_primary(|primary| {            
             match primary.as_rule() {
-                Rule::Expr => parse_binary_expr(primary.into_inner()),
-                Rule::UnaryExpr => parse_binary_expr(primary.into_inner()),
-                Rule::BinaryExpr => parse_binary_expr(primary.into_inner()),
-                Rule::Number => Node::Number(primary.as_str().parse::<f64>().unwrap()),
+                Rule::Expr if parens >= limits.max_nesting_depth => {
+                    Err(limit_error(LimitError::NestingTooDeep { max: limits.max_nesting_depth }, primary.as_span()))
+                }
+                Rule::Expr => parse_expr_with_depth(primary.into_inner(), parens + 1, limits),
+                Rule::Number => parse_number(primary).map(|n| (Node::Number(n), 1)),
+                Rule::Call => parse_call(primary, parens, limits),
+                Rule::Ident => Ok((Node::Var(primary.as_str().to_string()), 1)),
                 rule => unreachable!("Expr::parse expected atom, found {:?}", rule)
 }})
         .map_infix(|lhs, op, rhs| {
+            let (lhs, lhs_depth) = lhs?;
+            let (rhs, rhs_depth) = rhs?;
+            let depth = check_depth(lhs_depth.max(rhs_depth) + 1, limits, op.as_span())?;
             let op = match op.as_rule() {
                 Rule::plus => Operator::Add,
                 Rule::minus => Operator::Sub,
@@ -60,34 +178,136 @@ pub fn parse_binary_expr(pairs: Pairs<Rule>) -> Node {
                 Rule::div => Operator::Div,
                 rule => unreachable!("Expr::parse expected infix operation, found {:?}", rule),
             };
-            Node::BinaryExpr { 
+            Ok((Node::BinaryExpr { 
                 op, 
                 lhs: Box::new(lhs), 
                 rhs: Box::new(rhs) 
-            }
+            }, depth))
         })
         .map_prefix(|op, n| {
+            let (n, depth) = n?;
+            let depth = check_depth(depth + 1, limits, op.as_span())?;
             let op = match op.as_rule() {
                 Rule::positive => Sign::Positive,
                 Rule::negative => Sign::Negative,
                 rule => unreachable!("Expr::parse expected prefix operation, found {:?}", rule),
             };
 
-            Node::UnaryExpr { 
+            Ok((Node::UnaryExpr { 
                 op, 
                 child: Box::new(n)
-            }
+            }, depth))
         })
         .parse(pairs)
 }
 
+// name(arg, ...), the arguments are checked like parenthesized expressions
+fn parse_call(
+    pair: Pair<Rule>,
+    parens: usize,
+    limits: &Limits,
+) -> std::result::Result<(Node, usize), pest::error::Error<Rule>> {
+    let span = pair.as_span();
+    let mut inner = pair.into_inner();
+    let name = inner.next().unwrap().as_str().to_string();
+
+    let mut args = vec![];
+    let mut depth = 0;
+    for arg in inner {
+        let (arg, arg_depth) = match arg.as_rule() {
+            Rule::Str => (Node::Str(arg.into_inner().as_str().to_string()), 1),
+            Rule::Expr if parens >= limits.max_nesting_depth => {
+                return Err(limit_error(LimitError::NestingTooDeep { max: limits.max_nesting_depth }, arg.as_span()))
+            }
+            Rule::Expr => parse_expr_with_depth(arg.into_inner(), parens + 1, limits)?,
+            rule => unreachable!("Expr::parse expected call argument, found {:?}", rule),
+        };
+        depth = depth.max(arg_depth);
+        args.push(arg);
+    }
+    let depth = check_depth(depth + 1, limits, span)?;
+    Ok((Node::Call { name, args }, depth))
+}
+
+fn limit_error(error: LimitError, span: Span) -> pest::error::Error<Rule> {
+    pest::error::Error::new_from_span(ErrorVariant::CustomError { message: error.to_string() }, span)
+}
+
+fn check_depth(depth: usize, limits: &Limits, span: Span) -> std::result::Result<usize, pest::error::Error<Rule>> {
+    if depth > limits.max_depth {
+        return Err(limit_error(LimitError::TooDeep { max: limits.max_depth }, span));
+    }
+    Ok(depth)
+}
+
+// converts a Number literal into f64, the literal can be
+// - decimal: 12, 1.5, .5, 1e-9, 2.5E+3
+// - hexadecimal: 0xFF
+// - binary: 0b1010
+// digits of any form can be grouped by a single "_" separator, e.g. 1_000_000
+fn parse_number(pair: Pair<Rule>) -> std::result::Result<f64, pest::error::Error<Rule>> {
+    let error = |message: String| {
+        pest::error::Error::new_from_span(ErrorVariant::CustomError { message }, pair.as_span())
+    };
+
+    let literal = pair.as_str();
+    let lower = literal.to_ascii_lowercase();
+    let (radix, digits) = if let Some(digits) = lower.strip_prefix("0x") {
+        (16, digits)
+    } else if let Some(digits) = lower.strip_prefix("0b") {
+        (2, digits)
+    } else {
+        (10, lower.as_str())
+    };
+
+    // a separator must sit between two digits
+    let chars: Vec<char> = digits.chars().collect();
+    for (i, c) in chars.iter().enumerate() {
+        if *c != '_' {
+            continue;
+        }
+        let between_digits = i > 0
+            && i + 1 < chars.len()
+            && chars[i - 1].is_digit(radix)
+            && chars[i + 1].is_digit(radix);
+        if !between_digits {
+            return Err(error(format!("misplaced digit separator in number literal `{}`", literal)));
+        }
+    }
+    let digits = digits.replace('_', "");
+
+    if radix == 10 {
+        let well_formed = digits.chars().all(|c| c.is_ascii_digit() || matches!(c, '.' | 'e' | '+' | '-'));
+        let value = match digits.parse::<f64>() {
+            Ok(value) if well_formed => value,
+            _ => return Err(error(format!("malformed number literal `{}`", literal))),
+        };
+        if value.is_infinite() {
+            return Err(error(format!("number literal `{}` overflows f64", literal)));
+        }
+        Ok(value)
+    } else {
+        if digits.is_empty() {
+            return Err(error(format!("missing digits in number literal `{}`", literal)));
+        }
+        match u64::from_str_radix(&digits, radix) {
+            Ok(value) => Ok(value as f64),
+            Err(e) if *e.kind() == std::num::IntErrorKind::PosOverflow => {
+                Err(error(format!("number literal `{}` overflows u64", literal)))
+            }
+            Err(_) => Err(error(format!("malformed number literal `{}`", literal))),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     #[test]
-    #[should_panic]
     fn basics() {
-        let _ = parse("b");
+        // syntax errors are returned, untrusted sources cannot crash the process
+        assert!(parse("$").is_err());
+        assert!(parse_with_limits("1 +", &Limits::default()).is_err());
     }
 
     #[test]
@@ -163,9 +383,16 @@ mod tests {
             }]
         );
         assert_eq!(format!("{}", minus.unwrap()[0]), "1.7 - 2");
-        // fails as there's no rhs:
-        // let paran_sum = parse("(1 + 2)");
-        // assert!(paran_sum.is_ok());
+        let paran_sum = parse("(1 + 2)");
+        assert!(paran_sum.is_ok());
+        assert_eq!(
+            paran_sum.unwrap(),
+            vec![Node::BinaryExpr {
+                op: Operator::Add,
+                lhs: Box::new(Node::Number(1.0)),
+                rhs: Box::new(Node::Number(2.0))
+            }]
+        );
 
         let mul = parse("3.56 * 4");
         assert!(mul.is_ok());
@@ -206,11 +433,243 @@ mod tests {
         }
 
         test_expr("1 + 2 + 3", "(1 + 2) + 3");
-        test_expr("1 + 2 + 3", "1 + (2 + 3)");
-        test_expr("1 + 2 + 3 + 4", "1 + (2 + (3 + 4))");
-        test_expr("1 + 2 + 3 - 4", "(1 + 2) + (3 - 4)");
+        test_expr("1 + (2 + 3)", "1 + (2 + 3)");
+        test_expr("1 + (2 + (3 + 4))", "1 + (2 + (3 + 4))");
+        test_expr("1 + 2 + (3 - 4)", "(1 + 2) + (3 - 4)");
+        test_expr("1 - (2 - 3)", "1 - (2 - 3)");
+        test_expr("(1 + 2) * 3", "(1 + 2) * 3");
         test_expr("1 + 0.5 * 0.3", "1 + (0.5 * 0.3)")
     }
+    #[test]
+    fn prefix_operators() {
+        fn neg(child: Node) -> Node {
+            Node::UnaryExpr { op: Sign::Negative, child: Box::new(child) }
+        }
+        fn pos(child: Node) -> Node {
+            Node::UnaryExpr { op: Sign::Positive, child: Box::new(child) }
+        }
+        fn bin(op: Operator, lhs: Node, rhs: Node) -> Node {
+            Node::BinaryExpr { op, lhs: Box::new(lhs), rhs: Box::new(rhs) }
+        }
+        let n = Node::Number;
+
+        assert_eq!(parse("2 * -3").unwrap(), vec![bin(Operator::Mul, n(2.0), neg(n(3.0)))]);
+        assert_eq!(parse("--1").unwrap(), vec![neg(neg(n(1.0)))]);
+        assert_eq!(parse("-+1").unwrap(), vec![neg(pos(n(1.0)))]);
+        assert_eq!(parse("1 - -1").unwrap(), vec![bin(Operator::Sub, n(1.0), neg(n(1.0)))]);
+        assert_eq!(parse("1 -1").unwrap(), vec![bin(Operator::Sub, n(1.0), n(1.0))]);
+        // signs bind tighter than infix operators
+        assert_eq!(parse("-1 + 2").unwrap(), vec![bin(Operator::Add, neg(n(1.0)), n(2.0))]);
+        assert_eq!(
+            parse("-(1+2)*3").unwrap(),
+            vec![bin(
+                Operator::Mul,
+                neg(bin(Operator::Add, n(1.0), n(2.0))),
+                n(3.0)
+            )]
+        );
+        assert_eq!(
+            parse("2 + 3 - 1").unwrap(),
+            vec![bin(Operator::Sub, bin(Operator::Add, n(2.0), n(3.0)), n(1.0))]
+        );
+        assert_eq!(
+            parse("-2 * -(3 - -4)").unwrap(),
+            vec![bin(
+                Operator::Mul,
+                neg(n(2.0)),
+                neg(bin(Operator::Sub, n(3.0), neg(n(4.0))))
+            )]
+        );
+    }
+
+    #[test]
+    fn number_literals() {
+        fn test_number(expected: f64, src: &str) {
+            assert_eq!(parse(src).unwrap(), vec![Node::Number(expected)], "{}", src);
+        }
+
+        test_number(0.000000001, "1e-9");
+        test_number(2500.0, "2.5E+3");
+        test_number(1e10, "1e10");
+        test_number(0.5, ".5");
+        test_number(255.0, "0xFF");
+        test_number(255.0, "0Xff");
+        test_number(10.0, "0b1010");
+        test_number(1_000_000.0, "1_000_000");
+        test_number(1000.5, "1_000.5");
+        test_number(65535.0, "0xFF_FF");
+        test_number(5.0, "0b0000_0101");
+        test_number(f64::MAX, "1.7976931348623157e308");
+        test_number(u64::MAX as f64, "0xFFFF_FFFF_FFFF_FFFF");
+
+        assert_eq!(
+            parse("1e-9 + 0x10").unwrap(),
+            vec![Node::BinaryExpr {
+                op: Operator::Add,
+                lhs: Box::new(Node::Number(1e-9)),
+                rhs: Box::new(Node::Number(16.0))
+            }]
+        );
+    }
+
+    #[test]
+    fn malformed_number_literals() {
+        fn test_error(message: &str, src: &str) {
+            let error = parse(src).unwrap_err();
+            assert!(error.to_string().contains(message), "{}: {}", src, error);
+        }
+
+        test_error("overflows f64", "1e400");
+        test_error("overflows f64", "2e308 + 1");
+        test_error("overflows u64", "0x1_0000_0000_0000_0000");
+        test_error("overflows u64", "0b11111111111111111111111111111111111111111111111111111111111111111");
+        test_error("missing digits", "0x");
+        test_error("missing digits", "0b");
+        test_error("malformed number literal", "0b102");
+        test_error("malformed number literal", "0xFG");
+        test_error("malformed number literal", "1e");
+        test_error("malformed number literal", "1.2.3");
+        test_error("malformed number literal", "12abc");
+        test_error("misplaced digit separator", "1__000");
+        test_error("misplaced digit separator", "1000_");
+        test_error("misplaced digit separator", "1_.5");
+        test_error("misplaced digit separator", "0x_FF");
+    }
+
+    #[test]
+    fn scripts() {
+        assert_eq!(parse("").unwrap(), vec![]);
+        assert_eq!(parse("1;").unwrap(), vec![Node::Number(1.0)]);
+        assert_eq!(
+            parse("1; 2;\n# a comment\n3 # trailing\n").unwrap(),
+            vec![Node::Number(1.0), Node::Number(2.0), Node::Number(3.0)]
+        );
+        assert_eq!(
+            parse("1 +\n    2").unwrap(),
+            vec![Node::BinaryExpr {
+                op: Operator::Add,
+                lhs: Box::new(Node::Number(1.0)),
+                rhs: Box::new(Node::Number(2.0))
+            }]
+        );
+
+        let statements = parse_statements("1 + 2;\n  3").unwrap();
+        assert_eq!(statements.iter().map(|s| s.span.clone()).collect::<Vec<_>>(), vec![0..5, 9..10]);
+        assert!(parse_statements("1 2").is_err());
+        assert!(parse_statements("1;;").is_err());
+    }
+
+    #[test]
+    fn calls() {
+        let call = |name: &str, args: Vec<Node>| Node::Call { name: name.to_string(), args };
+        assert_eq!(parse("now()").unwrap(), vec![call("now", vec![])]);
+        assert_eq!(
+            parse("2 * lookup_rate( \"EUR\" ) - max(1, -x_2(\"(#\"), 3 + 4)").unwrap(),
+            vec![Node::BinaryExpr {
+                op: Operator::Sub,
+                lhs: Box::new(Node::BinaryExpr {
+                    op: Operator::Mul,
+                    lhs: Box::new(Node::Number(2.0)),
+                    rhs: Box::new(call("lookup_rate", vec![Node::Str("EUR".to_string())])),
+                }),
+                rhs: Box::new(call(
+                    "max",
+                    vec![
+                        Node::Number(1.0),
+                        Node::UnaryExpr {
+                            op: Sign::Negative,
+                            child: Box::new(call("x_2", vec![Node::Str("(#".to_string())])),
+                        },
+                        Node::BinaryExpr {
+                            op: Operator::Add,
+                            lhs: Box::new(Node::Number(3.0)),
+                            rhs: Box::new(Node::Number(4.0)),
+                        },
+                    ]
+                )),
+            }]
+        );
+
+        for source in ["f(", "f(1,)", "f(,)", "\"EUR\"", "1 + \"EUR\"", "f(\"a\nb\")", "2f(1)", "f(1) (2)"] {
+            assert!(parse_statements(source).is_err(), "{}", source);
+        }
+
+        // arguments are nested like parentheses
+        let limits = Limits { max_nesting_depth: 2, ..Limits::default() };
+        assert!(parse_with_limits("f(g(1))", &limits).is_ok());
+        assert!(parse_with_limits("f(g(h(1)))", &limits).is_err());
+        let limits = Limits { max_depth: 3, ..Limits::default() };
+        assert!(parse_with_limits("f(\"a\", -1, g())", &limits).is_ok());
+        assert!(parse_with_limits("f(-g(1))", &limits).is_err());
+    }
+
+    #[test]
+    fn variables() {
+        let var = |name: &str| Box::new(Node::Var(name.to_string()));
+        assert_eq!(
+            parse("price * qty * (1 - _discount2)").unwrap(),
+            vec![Node::BinaryExpr {
+                op: Operator::Mul,
+                lhs: Box::new(Node::BinaryExpr { op: Operator::Mul, lhs: var("price"), rhs: var("qty") }),
+                rhs: Box::new(Node::BinaryExpr {
+                    op: Operator::Sub,
+                    lhs: Box::new(Node::Number(1.0)),
+                    rhs: var("_discount2"),
+                }),
+            }]
+        );
+        assert_eq!(
+            parse("-f(x)").unwrap(),
+            vec![Node::UnaryExpr {
+                op: Sign::Negative,
+                child: Box::new(Node::Call { name: "f".to_string(), args: vec![Node::Var("x".to_string())] }),
+            }]
+        );
+        for source in ["x y", "2x", "x.y", "x(1"] {
+            assert!(parse_statements(source).is_err(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn limits() {
+        let limits = Limits { max_nesting_depth: 2, max_source_len: 16, ..Limits::default() };
+        assert!(parse_with_limits("((1 + 2)) * 3", &limits).is_ok());
+
+        let error = parse_with_limits("(((1 + 2))) * 3", &limits).unwrap_err();
+        assert!(error.to_string().contains("nested deeper than 2 levels"), "{}", error);
+
+        let error = parse_with_limits("1 + 2 + 3 + 4 + 5", &limits).unwrap_err();
+        assert!(error.to_string().contains("17 bytes long"), "{}", error);
+
+        let limits = Limits { max_depth: 3, ..Limits::default() };
+        assert!(parse_with_limits("-(1 + 2) # ((((((", &limits).is_ok());
+        for source in ["1 + 2 + 3 + 4", "--(1 + 2)", "---1", "-(-(1))", "1 * (2 - -3)"] {
+            let error = parse_with_limits(source, &limits).unwrap_err();
+            assert!(error.to_string().contains("ast deeper than 3 levels"), "{}", error);
+        }
+    }
+
+    // would overflow the native stack without the depth limits
+    #[test]
+    fn pathological() {
+        let max_depth = Limits::default().max_depth;
+        for source in &crate::testing::pathological_sources() {
+            assert!(parse_statements(source).is_err());
+            assert!(parse(source).is_err());
+        }
+
+        // right at the limit
+        assert_eq!(parse(&("-".repeat(max_depth - 1) + "1")).unwrap()[0].depth(), max_depth);
+        let (chain, _) = crate::testing::deepest_chain();
+        assert_eq!(parse(&chain).unwrap()[0].depth(), max_depth);
+        // a parenthesis counts as a level, even though it is not a node
+        let nested = "-(".repeat(255) + &"-".repeat(max_depth - 2 * 255) + "1" + &")".repeat(255);
+        assert!(parse(&nested).is_ok());
+        let max_nesting_depth = Limits::default().max_nesting_depth;
+        let nested = "--(".repeat(max_nesting_depth) + "1" + &")".repeat(max_nesting_depth);
+        assert!(parse(&nested).is_ok());
+    }
+
     #[test]
     fn mixed_mul_add() {
         assert_eq!(
@@ -0,0 +1,378 @@
+use std::{
+    fmt::{self, Write},
+    slice,
+};
+
+use crate::ast::{Node, Operator};
+
+// how parentheses are inserted into the output
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum Parens {
+    // only where precedence or associativity requires them: 1 - (2 - 3)
+    #[default]
+    Minimal,
+    // around every compound operand: (1 * 2) + (-3)
+    Full,
+}
+
+// how operators are spaced in the output
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum Spacing {
+    // single space around infix operators: 1 + -2
+    #[default]
+    Canonical,
+    // no space at all: 1+-2
+    Compact,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct PrintOptions {
+    pub parens: Parens,
+    pub spacing: Spacing,
+}
+
+// binding power of a node, the higher the tighter it binds
+// it mirrors the precedence levels declared in PRATT_PARSER
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Precedence {
+    Sum,
+    Product,
+    Prefix,
+    Atom,
+}
+
+impl From<Operator> for Precedence {
+    fn from(op: Operator) -> Self {
+        match op {
+            Operator::Add | Operator::Sub => Precedence::Sum,
+            Operator::Mul | Operator::Div => Precedence::Product,
+        }
+    }
+}
+
+fn precedence(node: &Node) -> Precedence {
+    match node {
+        // NaN is printed in parentheses
+        Node::Number(n) if n.is_nan() => Precedence::Atom,
+        // a negative literal can only come from a rewrite of the ast,
+        // it is printed like its unary counterpart
+        Node::Number(n) if n.is_sign_negative() => Precedence::Prefix,
+        Node::Number(_) | Node::Call { .. } | Node::Str(_) | Node::Var(_) => Precedence::Atom,
+        Node::UnaryExpr { .. } => Precedence::Prefix,
+        Node::BinaryExpr { op, .. } => Precedence::from(*op),
+    }
+}
+
+// Printer turns an ast back into source code.
+//
+// For every ast produced by the parser, parsing the printed source yields
+// the same ast again: parse(print(ast)) == ast. NaN and infinities, which
+// only the optimizer produces, come back as the divisions it folds them from
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Printer {
+    options: PrintOptions,
+}
+
+impl Printer {
+    pub fn new(options: PrintOptions) -> Self {
+        Self { options }
+    }
+
+    pub fn print(&self, node: &Node) -> String {
+        let mut out = String::new();
+        // writing into a String never fails
+        self.write(&mut out, node).unwrap();
+        out
+    }
+
+    pub fn write(&self, out: &mut dyn Write, node: &Node) -> fmt::Result {
+        self.write_literals(out, node, &mut [].iter())
+    }
+
+    // literals holds the source text of the numbers of node in the order they
+    // are written, which is printed instead of their value; numbers past the
+    // end of literals are printed from their value
+    fn write_literals(&self, out: &mut dyn Write, node: &Node, literals: &mut slice::Iter<String>) -> fmt::Result {
+        match node {
+            Node::Number(_) if literals.len() > 0 => out.write_str(literals.next().unwrap()),
+            // NaN and infinities have no literal, the optimizer folds
+            // the divisions they are printed as back into them
+            Node::Number(n) if !n.is_finite() => {
+                let (sign, dividend) = match n {
+                    n if n.is_nan() => ("", 0),
+                    n if n.is_sign_negative() => ("-", 1),
+                    _ => ("", 1),
+                };
+                match self.options.spacing {
+                    Spacing::Canonical => write!(out, "{}({} / 0)", sign, dividend),
+                    Spacing::Compact => write!(out, "{}({}/0)", sign, dividend),
+                }
+            }
+            Node::Number(n) => write!(out, "{}", n),
+            Node::UnaryExpr { op, child } => {
+                write!(out, "{}", op)?;
+                // stacked signs need no parentheses: --1
+                let wrap = match self.options.parens {
+                    Parens::Minimal => precedence(child) < Precedence::Prefix,
+                    Parens::Full => precedence(child) < Precedence::Atom,
+                };
+                self.write_operand(out, child, wrap, literals)
+            }
+            Node::BinaryExpr { op, lhs, rhs } => {
+                let op_precedence = Precedence::from(*op);
+                // operators are left associative, an operand of equal precedence
+                // only needs parentheses on the right hand side: 1 - (2 - 3)
+                let (wrap_lhs, wrap_rhs) = match self.options.parens {
+                    Parens::Minimal => (
+                        precedence(lhs) < op_precedence,
+                        precedence(rhs) <= op_precedence,
+                    ),
+                    Parens::Full => (
+                        precedence(lhs) < Precedence::Atom,
+                        precedence(rhs) < Precedence::Atom,
+                    ),
+                };
+
+                self.write_operand(out, lhs, wrap_lhs, literals)?;
+                match self.options.spacing {
+                    Spacing::Canonical => write!(out, " {} ", op)?,
+                    Spacing::Compact => write!(out, "{}", op)?,
+                }
+                self.write_operand(out, rhs, wrap_rhs, literals)
+            }
+            Node::Call { name, args } => {
+                write!(out, "{}(", name)?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        match self.options.spacing {
+                            Spacing::Canonical => out.write_str(", ")?,
+                            Spacing::Compact => out.write_char(',')?,
+                        }
+                    }
+                    self.write_literals(out, arg, literals)?;
+                }
+                out.write_char(')')
+            }
+            Node::Str(value) => write!(out, "\"{}\"", value),
+            Node::Var(name) => out.write_str(name),
+        }
+    }
+
+    // prints the node on one line if it fits in `width` columns, otherwise
+    // the outermost chain of operators of equal precedence is broken
+    // with every operator starting an indented line:
+    //
+    // first_operand
+    //     + second_operand
+    //     - third_operand
+    pub fn print_wrapped(&self, node: &Node, width: usize, indent: usize) -> String {
+        self.print_wrapped_literals(node, &[], width, indent)
+    }
+
+    // same as print_wrapped, with the numbers printed as in write_literals
+    pub fn print_wrapped_literals(&self, node: &Node, literals: &[String], width: usize, indent: usize) -> String {
+        let mut flat = String::new();
+        // writing into a String never fails
+        self.write_literals(&mut flat, node, &mut literals.iter()).unwrap();
+        let chain_precedence = match node {
+            Node::BinaryExpr { op, .. } if flat.chars().count() > width => Precedence::from(*op),
+            _ => return flat,
+        };
+
+        // operands of the chain from right to left
+        let mut operands = vec![];
+        let mut current = node;
+        while let Node::BinaryExpr { op, lhs, rhs } = current {
+            if Precedence::from(*op) != chain_precedence {
+                break;
+            }
+            operands.push((Some(*op), rhs.as_ref()));
+            current = lhs;
+        }
+        operands.push((None, current));
+
+        let mut out = String::new();
+        let literals = &mut literals.iter();
+        for (op, operand) in operands.into_iter().rev() {
+            // writing into a String never fails
+            match op {
+                None => self.write_operand(&mut out, operand, precedence(operand) < chain_precedence, literals),
+                Some(op) => {
+                    out.push('\n');
+                    out.push_str(&" ".repeat(indent));
+                    write!(out, "{} ", op).unwrap();
+                    self.write_operand(&mut out, operand, precedence(operand) <= chain_precedence, literals)
+                }
+            }
+            .unwrap();
+        }
+        out
+    }
+
+    fn write_operand(&self, out: &mut dyn Write, node: &Node, wrap: bool, literals: &mut slice::Iter<String>) -> fmt::Result {
+        if wrap {
+            out.write_char('(')?;
+            self.write_literals(out, node, literals)?;
+            out.write_char(')')
+        } else {
+            self.write_literals(out, node, literals)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parser::parse, Sign};
+
+    fn print_with(parens: Parens, spacing: Spacing, source: &str) -> String {
+        Printer::new(PrintOptions { parens, spacing }).print(&parse(source).unwrap()[0])
+    }
+
+    #[test]
+    fn minimal_parens() {
+        fn test_print(expected: &str, src: &str) {
+            assert_eq!(expected, print_with(Parens::Minimal, Spacing::Canonical, src));
+        }
+
+        test_print("1 - (2 - 3)", "1 - (2 - 3)");
+        test_print("1 - 2 - 3", "(1 - 2) - 3");
+        test_print("1 / (2 * 3)", "1/(2*3)");
+        test_print("1 * 2 / 3", "((1*2)/3)");
+        test_print("(1 + 2) * 3", "(1 + 2) * 3");
+        test_print("1 + 2 * 3", "1 + (2 * 3)");
+        test_print("-(1 + 2) * 3", "-(1+2)*3");
+        test_print("-2 * -3", "(-2) * (-3)");
+        test_print("--1", "-(-1)");
+        test_print("-+1", "-(+(1))");
+        test_print("1 - -1", "1 - (-1)");
+        test_print("2", "((2))");
+        test_print("f() * -g(1 + 2, \"a b\")", "(f()) * (-g((1 + 2), \"a b\"))");
+    }
+
+    #[test]
+    fn full_parens() {
+        fn test_print(expected: &str, src: &str) {
+            assert_eq!(expected, print_with(Parens::Full, Spacing::Canonical, src));
+        }
+
+        test_print("1 + 2", "1 + 2");
+        test_print("(1 + 2) + 3", "1 + 2 + 3");
+        test_print("1 + (2 * 3)", "1 + 2 * 3");
+        test_print("(-2) * (-3)", "-2 * -3");
+        test_print("-(-1)", "--1");
+    }
+
+    #[test]
+    fn compact_spacing() {
+        assert_eq!("1+2*-3", print_with(Parens::Minimal, Spacing::Compact, "1 + 2 * -3"));
+        assert_eq!("(1+2)*3", print_with(Parens::Minimal, Spacing::Compact, "(1 + 2) * 3"));
+        assert_eq!("1-(2-3)", print_with(Parens::Full, Spacing::Compact, "1 - (2 - 3)"));
+        assert_eq!("f(1+2,\"x\")", print_with(Parens::Minimal, Spacing::Compact, "f(1 + 2, \"x\")"));
+    }
+
+    #[test]
+    fn negative_literal() {
+        // only rewrites of the ast produce negative literals
+        let node = Node::BinaryExpr {
+            op: Operator::Mul,
+            lhs: Box::new(Node::Number(2.0)),
+            rhs: Box::new(Node::Number(-3.0)),
+        };
+        assert_eq!("2 * -3", Printer::default().print(&node));
+    }
+
+    #[test]
+    fn not_finite() {
+        let optimizer = crate::Optimizer::default();
+        for (value, expected) in [(f64::NAN, "(0 / 0)"), (f64::INFINITY, "(1 / 0)"), (f64::NEG_INFINITY, "-(1 / 0)")] {
+            let printed = Printer::default().print(&Node::Number(value));
+            assert_eq!(printed, expected);
+            match &optimizer.optimize(parse(&printed).unwrap())[..] {
+                [Node::Number(n)] => assert!(n.to_bits() == value.to_bits() || n.is_nan() && value.is_nan()),
+                ast => panic!("{:?}", ast),
+            }
+        }
+
+        let node = Node::BinaryExpr {
+            op: Operator::Mul,
+            lhs: Box::new(Node::Number(f64::NEG_INFINITY)),
+            rhs: Box::new(Node::Number(f64::NAN)),
+        };
+        assert_eq!(Printer::default().print(&node), "-(1 / 0) * (0 / 0)");
+        let compact = Printer::new(PrintOptions { parens: Parens::Minimal, spacing: Spacing::Compact });
+        assert_eq!(compact.print(&node), "-(1/0)*(0/0)");
+    }
+
+    #[test]
+    fn wrapped() {
+        let printer = Printer::default();
+        let ast = parse("1 + 2 * 3 - (4 - 5) + 6").unwrap().remove(0);
+        assert_eq!(printer.print_wrapped(&ast, 80, 4), "1 + 2 * 3 - (4 - 5) + 6");
+        assert_eq!(printer.print_wrapped(&ast, 10, 4), "1\n    + 2 * 3\n    - (4 - 5)\n    + 6");
+
+        let ast = parse("(1 + 2) * 3 / (4 * 5)").unwrap().remove(0);
+        assert_eq!(printer.print_wrapped(&ast, 10, 2), "(1 + 2)\n  * 3\n  / (4 * 5)");
+
+        let ast = parse("-(1 + 2 + 3)").unwrap().remove(0);
+        assert_eq!(printer.print_wrapped(&ast, 5, 4), "-(1 + 2 + 3)");
+
+        // numbers keep the text they were written with
+        let ast = parse("(0xFF + 1_000) * -1e-9").unwrap().remove(0);
+        let literals = ["0xFF", "1_000", "1e-9"].map(String::from);
+        assert_eq!(printer.print_wrapped_literals(&ast, &literals, 80, 4), "(0xFF + 1_000) * -1e-9");
+        assert_eq!(printer.print_wrapped_literals(&ast, &literals, 10, 4), "(0xFF + 1_000)\n    * -1e-9");
+    }
+
+    // deterministic xorshift generator, good enough to build random trees
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+    }
+
+    fn random_node(rng: &mut Rng, depth: usize) -> Node {
+        let choice = if depth == 0 { rng.next() % 2 * 5 } else { rng.next() % 6 };
+        match choice {
+            0 => Node::Number((rng.next() % 10_000) as f64 / 8.0),
+            5 => Node::Var(["x", "price_2"][(rng.next() % 2) as usize].to_string()),
+            1 => Node::UnaryExpr {
+                op: [Sign::Positive, Sign::Negative][(rng.next() % 2) as usize],
+                child: Box::new(random_node(rng, depth - 1)),
+            },
+            2 => Node::Call {
+                name: ["f", "rate_2"][(rng.next() % 2) as usize].to_string(),
+                args: (0..rng.next() % 3)
+                    .map(|i| match i {
+                        0 => Node::Str("EUR".to_string()),
+                        _ => random_node(rng, depth - 1),
+                    })
+                    .collect(),
+            },
+            _ => Node::BinaryExpr {
+                op: [Operator::Add, Operator::Sub, Operator::Mul, Operator::Div][(rng.next() % 4) as usize],
+                lhs: Box::new(random_node(rng, depth - 1)),
+                rhs: Box::new(random_node(rng, depth - 1)),
+            },
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut rng = Rng(0x2545_F491_4F6C_DD1D);
+        for _ in 0..2000 {
+            let ast = random_node(&mut rng, 5);
+            for parens in [Parens::Minimal, Parens::Full] {
+                for spacing in [Spacing::Canonical, Spacing::Compact] {
+                    let source = Printer::new(PrintOptions { parens, spacing }).print(&ast);
+                    assert_eq!(parse(&source).unwrap(), vec![ast.clone()], "{}", source);
+                }
+            }
+        }
+    }
+}
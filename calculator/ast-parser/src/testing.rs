@@ -0,0 +1,79 @@
+// fixtures shared by the tests of the backends, which all have to agree
+// on what a formula evaluates to
+
+use crate::{Limits, Node, Sign};
+
+// every combination of signs, parentheses and operators for `<sign>lhs <op> <sign>rhs`
+pub fn sign_matrix() -> Vec<(String, f64)> {
+    type Apply = fn(f64, f64) -> f64;
+    let signs = [("", 1.0), ("+", 1.0), ("-", -1.0), ("--", 1.0), ("-+", -1.0), ("+-", -1.0)];
+    let lhs_terms = [("4", 4.0), ("(1 + 3)", 4.0), ("(8 / 2)", 4.0)];
+    let rhs_terms = [("2", 2.0), ("(5 - 3)", 2.0), ("(-1 * -2)", 2.0)];
+    let operators: [(&str, Apply); 4] = [
+        ("+", |lhs, rhs| lhs + rhs),
+        ("-", |lhs, rhs| lhs - rhs),
+        ("*", |lhs, rhs| lhs * rhs),
+        ("/", |lhs, rhs| lhs / rhs),
+    ];
+
+    let mut cases = vec![
+        ("2 * -3".to_string(), -6.0),
+        ("--1".to_string(), 1.0),
+        ("-(1+2)*3".to_string(), -9.0),
+        ("(1 + 2)".to_string(), 3.0),
+        ("2 + 3 - 1".to_string(), 4.0),
+        ("-1 + 2".to_string(), 1.0),
+        ("1 - -1".to_string(), 2.0),
+        ("6 / -2 * -3".to_string(), 9.0),
+        ("-(-(-1))".to_string(), -1.0),
+        ("-2 * -3 - -4".to_string(), 10.0),
+        ("1 - 2 * -(3 + -4) / 2".to_string(), 0.0),
+    ];
+    for (lhs_sign, lhs_factor) in signs {
+        for (lhs, lhs_value) in lhs_terms {
+            for (op, apply) in operators {
+                for (rhs_sign, rhs_factor) in signs {
+                    for (rhs, rhs_value) in rhs_terms {
+                        cases.push((
+                            format!("{}{} {} {}{}", lhs_sign, lhs, op, rhs_sign, rhs),
+                            apply(lhs_factor * lhs_value, rhs_factor * rhs_value),
+                        ));
+                    }
+                }
+            }
+        }
+    }
+    cases
+}
+
+// sources the parser refuses before any backend sees them: nested, chained
+// or signed far past the default limits
+pub fn pathological_sources() -> Vec<String> {
+    let max_depth = Limits::default().max_depth;
+    vec![
+        "(".repeat(100_000) + "1" + &")".repeat(100_000),
+        "-".repeat(100_000) + "1",
+        "-(".repeat(50_000) + "1" + &")".repeat(50_000),
+        "1".to_string() + &" + 1".repeat(100_000),
+        "1".to_string() + &" * (1".repeat(10_000) + &")".repeat(10_000),
+        "-".repeat(max_depth) + "1",
+        "1".to_string() + &" / 2".repeat(max_depth),
+        "f(".repeat(100_000) + &")".repeat(100_000),
+        "f(1, ".repeat(max_depth) + "1" + &")".repeat(max_depth),
+    ]
+}
+
+// `+ + ... + 1` built by hand, depth levels deep, which no source past the
+// limits would parse into
+pub fn deep(depth: usize) -> Node {
+    (1..depth).fold(Node::Number(1.0), |child, _| Node::UnaryExpr {
+        op: Sign::Positive,
+        child: Box::new(child),
+    })
+}
+
+// `1 - 1 - ... - 1` exactly as deep as the default limits allow, and its value
+pub fn deepest_chain() -> (String, f64) {
+    let max_depth = Limits::default().max_depth;
+    ("1".to_string() + &" - 1".repeat(max_depth - 1), 2.0 - max_depth as f64)
+}
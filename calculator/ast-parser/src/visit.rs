@@ -0,0 +1,268 @@
+// Traversal of the ast.
+//
+// Every trait has one method per kind of node, the default implementation
+// of a method walks into the children of the node through the matching
+// walk_* function. An implementation overrides the methods of the nodes
+// it cares about and calls walk_* itself where it still wants to recurse.
+//
+// - Visitor reads the ast
+// - VisitorMut edits the ast in place
+// - Fold consumes the ast and rebuilds a new one
+
+use crate::ast::{Node, Operator, Sign};
+
+pub trait Visitor {
+    fn visit_node(&mut self, node: &Node) {
+        walk_node(self, node)
+    }
+
+    fn visit_number(&mut self, _value: f64) {}
+
+    fn visit_unary(&mut self, op: Sign, child: &Node) {
+        walk_unary(self, op, child)
+    }
+
+    fn visit_binary(&mut self, op: Operator, lhs: &Node, rhs: &Node) {
+        walk_binary(self, op, lhs, rhs)
+    }
+
+    fn visit_call(&mut self, name: &str, args: &[Node]) {
+        walk_call(self, name, args)
+    }
+
+    fn visit_str(&mut self, _value: &str) {}
+
+    fn visit_var(&mut self, _name: &str) {}
+}
+
+pub fn walk_node<V: Visitor + ?Sized>(visitor: &mut V, node: &Node) {
+    match node {
+        Node::Number(value) => visitor.visit_number(*value),
+        Node::UnaryExpr { op, child } => visitor.visit_unary(*op, child),
+        Node::BinaryExpr { op, lhs, rhs } => visitor.visit_binary(*op, lhs, rhs),
+        Node::Call { name, args } => visitor.visit_call(name, args),
+        Node::Str(value) => visitor.visit_str(value),
+        Node::Var(name) => visitor.visit_var(name),
+    }
+}
+
+pub fn walk_unary<V: Visitor + ?Sized>(visitor: &mut V, _op: Sign, child: &Node) {
+    visitor.visit_node(child)
+}
+
+pub fn walk_binary<V: Visitor + ?Sized>(visitor: &mut V, _op: Operator, lhs: &Node, rhs: &Node) {
+    visitor.visit_node(lhs);
+    visitor.visit_node(rhs);
+}
+
+pub fn walk_call<V: Visitor + ?Sized>(visitor: &mut V, _name: &str, args: &[Node]) {
+    for arg in args {
+        visitor.visit_node(arg);
+    }
+}
+
+pub trait VisitorMut {
+    fn visit_node_mut(&mut self, node: &mut Node) {
+        walk_node_mut(self, node)
+    }
+
+    fn visit_number_mut(&mut self, _value: &mut f64) {}
+
+    fn visit_unary_mut(&mut self, op: &mut Sign, child: &mut Node) {
+        walk_unary_mut(self, op, child)
+    }
+
+    fn visit_binary_mut(&mut self, op: &mut Operator, lhs: &mut Node, rhs: &mut Node) {
+        walk_binary_mut(self, op, lhs, rhs)
+    }
+
+    fn visit_call_mut(&mut self, name: &mut String, args: &mut [Node]) {
+        walk_call_mut(self, name, args)
+    }
+
+    fn visit_str_mut(&mut self, _value: &mut String) {}
+
+    fn visit_var_mut(&mut self, _name: &mut String) {}
+}
+
+pub fn walk_node_mut<V: VisitorMut + ?Sized>(visitor: &mut V, node: &mut Node) {
+    match node {
+        Node::Number(value) => visitor.visit_number_mut(value),
+        Node::UnaryExpr { op, child } => visitor.visit_unary_mut(op, child),
+        Node::BinaryExpr { op, lhs, rhs } => visitor.visit_binary_mut(op, lhs, rhs),
+        Node::Call { name, args } => visitor.visit_call_mut(name, args),
+        Node::Str(value) => visitor.visit_str_mut(value),
+        Node::Var(name) => visitor.visit_var_mut(name),
+    }
+}
+
+pub fn walk_unary_mut<V: VisitorMut + ?Sized>(visitor: &mut V, _op: &mut Sign, child: &mut Node) {
+    visitor.visit_node_mut(child)
+}
+
+pub fn walk_binary_mut<V: VisitorMut + ?Sized>(
+    visitor: &mut V,
+    _op: &mut Operator,
+    lhs: &mut Node,
+    rhs: &mut Node,
+) {
+    visitor.visit_node_mut(lhs);
+    visitor.visit_node_mut(rhs);
+}
+
+pub fn walk_call_mut<V: VisitorMut + ?Sized>(visitor: &mut V, _name: &mut String, args: &mut [Node]) {
+    for arg in args {
+        visitor.visit_node_mut(arg);
+    }
+}
+
+pub trait Fold {
+    fn fold_node(&mut self, node: Node) -> Node {
+        fold_node(self, node)
+    }
+
+    fn fold_number(&mut self, value: f64) -> Node {
+        Node::Number(value)
+    }
+
+    fn fold_unary(&mut self, op: Sign, child: Node) -> Node {
+        Node::UnaryExpr {
+            op,
+            child: Box::new(self.fold_node(child)),
+        }
+    }
+
+    fn fold_binary(&mut self, op: Operator, lhs: Node, rhs: Node) -> Node {
+        Node::BinaryExpr {
+            op,
+            lhs: Box::new(self.fold_node(lhs)),
+            rhs: Box::new(self.fold_node(rhs)),
+        }
+    }
+
+    fn fold_call(&mut self, name: String, args: Vec<Node>) -> Node {
+        Node::Call {
+            name,
+            args: args.into_iter().map(|arg| self.fold_node(arg)).collect(),
+        }
+    }
+
+    fn fold_str(&mut self, value: String) -> Node {
+        Node::Str(value)
+    }
+
+    fn fold_var(&mut self, name: String) -> Node {
+        Node::Var(name)
+    }
+}
+
+// dispatches a node to the fold_* method of its kind
+pub fn fold_node<F: Fold + ?Sized>(folder: &mut F, mut node: Node) -> Node {
+    match &mut node {
+        Node::Number(value) => folder.fold_number(*value),
+        Node::UnaryExpr { op, child } => folder.fold_unary(*op, child.take()),
+        Node::BinaryExpr { op, lhs, rhs } => folder.fold_binary(*op, lhs.take(), rhs.take()),
+        Node::Call { name, args } => folder.fold_call(std::mem::take(name), std::mem::take(args)),
+        Node::Str(value) => folder.fold_str(std::mem::take(value)),
+        Node::Var(name) => folder.fold_var(std::mem::take(name)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    fn ast(source: &str) -> Node {
+        parse(source).unwrap().remove(0)
+    }
+
+    #[test]
+    fn visitor() {
+        struct Literals(Vec<f64>);
+
+        impl Visitor for Literals {
+            fn visit_number(&mut self, value: f64) {
+                self.0.push(value)
+            }
+        }
+
+        let mut literals = Literals(vec![]);
+        literals.visit_node(&ast("1 + -(2 * 3) / 4"));
+        assert_eq!(literals.0, vec![1.0, 2.0, 3.0, 4.0]);
+
+        // arguments of calls are walked as well
+        let mut literals = Literals(vec![]);
+        literals.visit_node(&ast("f(1, \"a\", g(2 + 3)) * 4"));
+        assert_eq!(literals.0, vec![1.0, 2.0, 3.0, 4.0]);
+
+        // stops the walk below divisions
+        struct Operators(Vec<Operator>);
+
+        impl Visitor for Operators {
+            fn visit_binary(&mut self, op: Operator, lhs: &Node, rhs: &Node) {
+                self.0.push(op);
+                if op != Operator::Div {
+                    walk_binary(self, op, lhs, rhs);
+                }
+            }
+        }
+
+        let mut operators = Operators(vec![]);
+        operators.visit_node(&ast("(1 - 2) * (3 / (4 + 5))"));
+        assert_eq!(operators.0, vec![Operator::Mul, Operator::Sub, Operator::Div]);
+    }
+
+    #[test]
+    fn visitor_mut() {
+        struct Scale(f64);
+
+        impl VisitorMut for Scale {
+            fn visit_number_mut(&mut self, value: &mut f64) {
+                *value *= self.0
+            }
+
+            fn visit_unary_mut(&mut self, op: &mut Sign, child: &mut Node) {
+                *op = Sign::Positive;
+                walk_unary_mut(self, op, child);
+            }
+        }
+
+        let mut node = ast("1 + -2 * 3");
+        Scale(10.0).visit_node_mut(&mut node);
+        assert_eq!(node, ast("10 + +20 * 30"));
+    }
+
+    #[test]
+    fn fold() {
+        // removes unary plus and swaps the operands of additions
+        struct Rewrite;
+
+        impl Fold for Rewrite {
+            fn fold_unary(&mut self, op: Sign, child: Node) -> Node {
+                match op {
+                    Sign::Positive => self.fold_node(child),
+                    Sign::Negative => Node::UnaryExpr {
+                        op,
+                        child: Box::new(self.fold_node(child)),
+                    },
+                }
+            }
+
+            fn fold_binary(&mut self, op: Operator, lhs: Node, rhs: Node) -> Node {
+                let (lhs, rhs) = match op {
+                    Operator::Add => (rhs, lhs),
+                    _ => (lhs, rhs),
+                };
+                Node::BinaryExpr {
+                    op,
+                    lhs: Box::new(self.fold_node(lhs)),
+                    rhs: Box::new(self.fold_node(rhs)),
+                }
+            }
+        }
+
+        assert_eq!(Rewrite.fold_node(ast("+1 + -(+2 * 3)")), ast("-(2 * 3) + 1"));
+        assert_eq!(Rewrite.fold_node(ast("++4")), ast("4"));
+    }
+}
@@ -0,0 +1,357 @@
+use std::{
+    fs,
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+};
+
+use calculator_ast_parser::{
+    parser,
+    visit::{walk_binary, Visitor},
+    CompiledExpr, Host, Inputs, Node, Operator, Result,
+};
+use calculator_compiler::compiler::JitExpr;
+use calculator_interpreter::interpreter::AstExpr;
+use calculator_vm::expr::VmExpr;
+use clap::{Args, ValueEnum};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum Backend {
+    /// Tree-walking interpreter
+    Interpreter,
+    /// Bytecode vm
+    Vm,
+    /// Machine code jitted by llvm
+    Llvm,
+}
+
+#[derive(Debug, Args)]
+pub struct CsvArgs {
+    /// CSV file, its first line names the columns
+    file: PathBuf,
+    /// Column to append, computed for every row by a formula over the columns of the file
+    #[arg(short, long = "formula", value_name = "NAME=FORMULA", required = true)]
+    formulas: Vec<String>,
+    /// Backend evaluating the formulas
+    #[arg(long, value_enum, default_value_t = Backend::Vm)]
+    backend: Backend,
+    /// Report a division by zero as an error instead of writing inf or NaN
+    #[arg(long)]
+    strict: bool,
+    /// Write the result to this file instead of stdout
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+// writes the file with one column appended per formula. A row that cannot be
+// computed is reported with its number, the header being row 1, and written
+// with empty cells; returns false if any row or the file itself failed
+pub fn run(args: CsvArgs) -> bool {
+    let text = match fs::read_to_string(&args.file) {
+        Ok(text) => text,
+        Err(e) => {
+            eprintln!("error: {}: {}", args.file.display(), e);
+            return false;
+        }
+    };
+    let records = match parse_records(&text) {
+        Ok(records) if !records.is_empty() => records,
+        Ok(_) => {
+            eprintln!("error: {}: no header", args.file.display());
+            return false;
+        }
+        Err(e) => {
+            eprintln!("error: {}: {}", args.file.display(), e);
+            return false;
+        }
+    };
+
+    let mut columns = vec![];
+    for spec in &args.formulas {
+        match Column::compile(spec, &records[0], args.backend, args.strict) {
+            Ok(column) => columns.push(column),
+            Err(e) => {
+                eprintln!("error: {}: {}", spec, e);
+                return false;
+            }
+        }
+    }
+
+    let out: Box<dyn Write> = match &args.output {
+        Some(path) => match fs::File::create(path) {
+            Ok(file) => Box::new(file),
+            Err(e) => {
+                eprintln!("error: {}: {}", path.display(), e);
+                return false;
+            }
+        },
+        None => Box::new(io::stdout()),
+    };
+    let mut out = BufWriter::new(out);
+    let errors = match evaluate(&records, &mut columns, &mut out).and_then(|errors| out.flush().map(|_| errors)) {
+        Ok(errors) => errors,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return false;
+        }
+    };
+    for error in &errors {
+        eprintln!("error: {}: {}", args.file.display(), error);
+    }
+    errors.is_empty()
+}
+
+// a computed column
+struct Column {
+    name: String,
+    expr: Box<dyn CompiledExpr>,
+    // position in a record of every input of expr
+    fields: Vec<usize>,
+    // right hand sides of every division, only compiled in strict mode
+    divisors: Vec<Box<dyn CompiledExpr>>,
+    values: Vec<f64>,
+}
+
+impl Column {
+    fn compile(spec: &str, header: &[String], backend: Backend, strict: bool) -> Result<Self> {
+        let (name, source) = match spec.split_once('=') {
+            Some((name, source)) if !name.trim().is_empty() => (name.trim(), source),
+            _ => anyhow::bail!("expected NAME=FORMULA"),
+        };
+
+        let mut usage = Usage::default();
+        for statement in parser::parse_statements(source)? {
+            usage.visit_node(&statement.node);
+        }
+        let mut fields = vec![];
+        for var in &usage.vars {
+            match header.iter().position(|column| column == var) {
+                Some(field) => fields.push(field),
+                None => anyhow::bail!("no column named `{}`", var),
+            }
+        }
+
+        let inputs = Inputs::new(&usage.vars);
+        let compile = |source: &str| -> Result<Box<dyn CompiledExpr>> {
+            let host = Host::default();
+            Ok(match backend {
+                Backend::Interpreter => Box::new(AstExpr::compile(source, inputs.clone(), &host)?),
+                Backend::Vm => Box::new(VmExpr::compile(source, inputs.clone(), &host)?),
+                Backend::Llvm => Box::new(JitExpr::compile(source, inputs.clone(), &host)?),
+            })
+        };
+        let expr = compile(source)?;
+        let divisors = match strict {
+            true => usage.divisors.iter().map(|divisor| compile(&divisor.to_string())).collect::<Result<_>>()?,
+            false => vec![],
+        };
+        Ok(Self {
+            name: name.to_string(),
+            expr,
+            fields,
+            divisors,
+            values: vec![0.0; usage.vars.len()],
+        })
+    }
+
+    // the value of the formula for a record, or why there is none
+    fn eval(&mut self, header: &[String], record: &[String]) -> std::result::Result<f64, String> {
+        for (value, field) in self.values.iter_mut().zip(&self.fields) {
+            let text = record[*field].trim();
+            *value = text
+                .parse()
+                .map_err(|_| format!("`{}` in column `{}` is not a number", text, header[*field]))?;
+        }
+        // any division by zero is found by evaluating its divisor on its own,
+        // every operand of a formula is always evaluated
+        for divisor in &mut self.divisors {
+            if divisor.eval(&self.values) == 0.0 {
+                return Err(format!("division by zero in `{}`", self.name));
+            }
+        }
+        Ok(self.expr.eval(&self.values))
+    }
+}
+
+// variables in order of first use and divisors of a formula
+#[derive(Default)]
+struct Usage {
+    vars: Vec<String>,
+    divisors: Vec<Node>,
+}
+
+impl Visitor for Usage {
+    fn visit_binary(&mut self, op: Operator, lhs: &Node, rhs: &Node) {
+        if op == Operator::Div {
+            self.divisors.push(rhs.clone());
+        }
+        walk_binary(self, op, lhs, rhs)
+    }
+
+    fn visit_var(&mut self, name: &str) {
+        if !self.vars.iter().any(|var| var == name) {
+            self.vars.push(name.to_string());
+        }
+    }
+}
+
+// writes the records with the computed columns, returns the errors of every row
+fn evaluate(records: &[Vec<String>], columns: &mut [Column], out: &mut dyn Write) -> io::Result<Vec<String>> {
+    let header = &records[0];
+    let names = columns.iter().map(|column| column.name.clone());
+    write_record(out, header.iter().cloned().chain(names))?;
+
+    let mut errors = vec![];
+    for (index, record) in records.iter().enumerate().skip(1) {
+        let row = index + 1;
+        let mut cells = vec![String::new(); columns.len()];
+        if record.len() != header.len() {
+            errors.push(format!("row {}: expected {} fields, found {}", row, header.len(), record.len()));
+        } else {
+            for (cell, column) in cells.iter_mut().zip(columns.iter_mut()) {
+                match column.eval(header, record) {
+                    Ok(value) => *cell = value.to_string(),
+                    // a bad field fails every column using it, report it once
+                    Err(e) => {
+                        let error = format!("row {}: {}", row, e);
+                        if errors.last() != Some(&error) {
+                            errors.push(error);
+                        }
+                    }
+                }
+            }
+        }
+        write_record(out, record.iter().cloned().chain(cells))?;
+    }
+    Ok(errors)
+}
+
+// RFC 4180: fields are separated by commas and records by LF or CRLF,
+// a field in double quotes may hold commas, line breaks and "" for a quote.
+// Blank lines are skipped
+fn parse_records(text: &str) -> std::result::Result<Vec<Vec<String>>, String> {
+    let mut records = vec![];
+    let mut record = vec![];
+    let mut field = String::new();
+    let mut line = 1;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if field.is_empty() => {
+                let start = line;
+                loop {
+                    match chars.next() {
+                        Some('"') if chars.peek() == Some(&'"') => {
+                            chars.next();
+                            field.push('"');
+                        }
+                        Some('"') => break,
+                        Some(c) => {
+                            line += (c == '\n') as usize;
+                            field.push(c);
+                        }
+                        None => return Err(format!("quoted field on line {} is never closed", start)),
+                    }
+                }
+            }
+            ',' => record.push(std::mem::take(&mut field)),
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' => {
+                line += 1;
+                record.push(std::mem::take(&mut field));
+                if record != [""] {
+                    records.push(std::mem::take(&mut record));
+                }
+                record.clear();
+            }
+            c => field.push(c),
+        }
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+    Ok(records)
+}
+
+fn write_record(out: &mut dyn Write, fields: impl Iterator<Item = String>) -> io::Result<()> {
+    for (i, field) in fields.enumerate() {
+        if i > 0 {
+            out.write_all(b",")?;
+        }
+        if field.contains([',', '"', '\n', '\r']) {
+            write!(out, "\"{}\"", field.replace('"', "\"\""))?;
+        } else {
+            out.write_all(field.as_bytes())?;
+        }
+    }
+    out.write_all(b"\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records() {
+        let text = "a,b\r\n1,\"x, \"\"y\"\"\"\n\n\"multi\nline\",\n";
+        let records = parse_records(text).unwrap();
+        assert_eq!(records, vec![vec!["a", "b"], vec!["1", "x, \"y\""], vec!["multi\nline", ""]]);
+        assert_eq!(parse_records("a\n1").unwrap(), vec![vec!["a"], vec!["1"]]);
+        assert_eq!(parse_records("a\n\"1\n").unwrap_err(), "quoted field on line 2 is never closed");
+
+        let mut out = vec![];
+        for record in &records {
+            write_record(&mut out, record.iter().cloned()).unwrap();
+        }
+        assert_eq!(parse_records(std::str::from_utf8(&out).unwrap()).unwrap(), records);
+    }
+
+    fn table(text: &str, formulas: &[&str], backend: Backend, strict: bool) -> (String, Vec<String>) {
+        let records = parse_records(text).unwrap();
+        let mut columns: Vec<Column> = formulas
+            .iter()
+            .map(|spec| Column::compile(spec, &records[0], backend, strict).unwrap())
+            .collect();
+        let mut out = vec![];
+        let errors = evaluate(&records, &mut columns, &mut out).unwrap();
+        (String::from_utf8(out).unwrap(), errors)
+    }
+
+    #[test]
+    fn formulas() {
+        let text = "item,price,qty,discount\npen,2.5,4,0.5\nbook,10,0,0\n\"bad, row\",x,1,0\nshort,1\n";
+        let formulas = ["total=price * qty * (1 - discount)", "unit=price / qty"];
+        for backend in [Backend::Interpreter, Backend::Vm, Backend::Llvm] {
+            let (out, errors) = table(text, &formulas, backend, false);
+            assert_eq!(
+                out,
+                "item,price,qty,discount,total,unit\n\
+                 pen,2.5,4,0.5,5,0.625\n\
+                 book,10,0,0,0,inf\n\
+                 \"bad, row\",x,1,0,,\n\
+                 short,1,,\n"
+            );
+            assert_eq!(
+                errors,
+                vec![
+                    "row 4: `x` in column `price` is not a number",
+                    "row 5: expected 4 fields, found 2",
+                ]
+            );
+
+            let (out, errors) = table(text, &formulas, backend, true);
+            assert!(out.contains("\nbook,10,0,0,0,\n"), "{}", out);
+            assert_eq!(errors[0], "row 3: division by zero in `unit`");
+        }
+
+        let records = parse_records(text).unwrap();
+        for (spec, error) in [
+            ("total", "expected NAME=FORMULA"),
+            ("total=price * tax", "no column named `tax`"),
+            ("total=price *", ""),
+        ] {
+            let found = Column::compile(spec, &records[0], Backend::Vm, false).err().unwrap().to_string();
+            assert!(found.contains(error), "{}", found);
+        }
+    }
+}
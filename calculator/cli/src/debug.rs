@@ -0,0 +1,212 @@
+use std::{
+    fs,
+    io::{self, BufRead, Write},
+    path::PathBuf,
+};
+
+use calculator_ast_parser::{parser, Compile};
+use calculator_vm::{
+    bytecode::Interpreter,
+    value::Primitive,
+    vm::{Stop, VM},
+};
+use clap::Args;
+
+#[derive(Debug, Args)]
+pub struct DebugArgs {
+    /// Script to debug
+    file: PathBuf,
+}
+
+const HELP: &str = "\
+step [n]       execute the next n instructions, 1 by default (s)
+continue       run until the next breakpoint or the end (c)
+stack          print the live stack, top first
+break [offset] set a breakpoint on an instruction offset, or list them (b)
+delete offset  remove a breakpoint (d)
+list           print the instructions with their offsets (l)
+quit           leave the debugger (q)";
+
+// compiles the script without optimizations, so the bytecode follows the source,
+// then reads commands from stdin until quit or the end of the input
+pub fn run(args: DebugArgs) -> bool {
+    let source = match fs::read_to_string(&args.file) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("error: {}: {}", args.file.display(), e);
+            return false;
+        }
+    };
+    let ast = match parser::parse_statements(&source) {
+        Ok(statements) => statements.into_iter().map(|statement| statement.node).collect(),
+        Err(e) => {
+            eprintln!("error: {}:\n{}", args.file.display(), e);
+            return false;
+        }
+    };
+
+    let mut session = Session::new(VM::new(Interpreter::from_ast(ast)));
+    let mut stdout = io::stdout();
+    let result = session.execute("list", &mut stdout).and_then(|_| {
+        writeln!(stdout, "type help for the list of commands")?;
+        let mut lines = io::stdin().lock().lines();
+        loop {
+            write!(stdout, "(debug) ")?;
+            stdout.flush()?;
+            let line = match lines.next() {
+                Some(line) => line?,
+                None => return Ok(()),
+            };
+            if !session.execute(&line, &mut stdout)? {
+                return Ok(());
+            }
+        }
+    });
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        return false;
+    }
+    true
+}
+
+struct Session {
+    vm: VM,
+}
+
+impl Session {
+    fn new(vm: VM) -> Self {
+        Self { vm }
+    }
+
+    // returns false once the user quits
+    fn execute(&mut self, command: &str, out: &mut dyn Write) -> io::Result<bool> {
+        let mut words = command.split_whitespace();
+        match (words.next(), words.next()) {
+            (None, _) => {}
+            (Some("step" | "s"), count) => {
+                let count = match count.map(str::parse) {
+                    None => 1,
+                    Some(Ok(count)) => count,
+                    Some(Err(_)) => {
+                        writeln!(out, "step expects a number of instructions")?;
+                        return Ok(true);
+                    }
+                };
+                for _ in 0..count {
+                    let (ip, opcode) = match self.vm.current_opcode() {
+                        Some(opcode) => (self.vm.ip(), opcode),
+                        None => break,
+                    };
+                    if let Err(e) = self.vm.step() {
+                        writeln!(out, "error: {}", e)?;
+                        return Ok(true);
+                    }
+                    writeln!(out, "{:>6}  {:?}", ip, opcode)?;
+                }
+                self.show_position(out)?;
+            }
+            (Some("continue" | "c"), None) => match self.vm.resume() {
+                Ok(Stop::Breakpoint(ip)) => {
+                    writeln!(out, "breakpoint at {}", ip)?;
+                    self.show_position(out)?;
+                }
+                Ok(Stop::Halted) => self.show_position(out)?,
+                Err(e) => writeln!(out, "error: {}", e)?,
+            },
+            (Some("stack"), None) => {
+                if self.vm.stack().is_empty() {
+                    writeln!(out, "stack is empty")?;
+                }
+                for (i, value) in self.vm.stack().iter().enumerate().rev() {
+                    writeln!(out, "{:>6}  {}", i, Primitive::from(*value))?;
+                }
+            }
+            (Some("break" | "b"), None) => {
+                for offset in self.vm.breakpoints() {
+                    writeln!(out, "breakpoint at {}", offset)?;
+                }
+            }
+            (Some("break" | "b"), Some(offset)) => match offset.parse() {
+                Ok(offset) if self.vm.set_breakpoint(offset) => writeln!(out, "breakpoint set at {}", offset)?,
+                _ => writeln!(out, "no instruction starts at {}, see list", offset)?,
+            },
+            (Some("delete" | "d"), Some(offset)) => match offset.parse() {
+                Ok(offset) if self.vm.remove_breakpoint(offset) => {
+                    writeln!(out, "breakpoint at {} removed", offset)?
+                }
+                _ => writeln!(out, "no breakpoint at {}", offset)?,
+            },
+            (Some("list" | "l"), None) => {
+                let breakpoints: Vec<_> = self.vm.breakpoints().collect();
+                for (offset, opcode) in self.vm.bytecode().disassemble() {
+                    let marker = if offset == self.vm.ip() { "=>" } else { "" };
+                    let breakpoint = if breakpoints.contains(&offset) { "*" } else { "" };
+                    writeln!(out, "{:>2}{:>1}{:>4}  {:?}", marker, breakpoint, offset, opcode)?;
+                }
+            }
+            (Some("help" | "h"), None) => writeln!(out, "{}", HELP)?,
+            (Some("quit" | "q"), None) => return Ok(false),
+            _ => writeln!(out, "unknown command {:?}, type help for the list of commands", command)?,
+        }
+        Ok(true)
+    }
+
+    // prints the next instruction, or the result once the program is over
+    fn show_position(&self, out: &mut dyn Write) -> io::Result<()> {
+        match self.vm.current_opcode() {
+            Some(opcode) => writeln!(out, "next {:>4}  {:?}", self.vm.ip(), opcode),
+            None => writeln!(out, "program halted, result is {}", self.vm.pop_last()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(source: &str, commands: &[&str]) -> String {
+        let ast = parser::parse(source).unwrap();
+        let mut session = Session::new(VM::new(Interpreter::from_ast(ast)));
+        let mut out = vec![];
+        for command in commands {
+            if !session.execute(command, &mut out).unwrap() {
+                break;
+            }
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn commands() {
+        let out = session("1 + 2 * 3", &["step 2", "stack"]);
+        assert_eq!(
+            out,
+            "     0  OpConstant(1.0)\n     9  OpConstant(2.0)\nnext   18  OpConstant(3.0)\n     1  2\n     0  1\n"
+        );
+
+        let out = session("1 + 2 * 3", &["break 27", "break 3", "c", "stack", "c", "q", "c"]);
+        assert_eq!(
+            out,
+            "breakpoint set at 27\n\
+             no instruction starts at 3, see list\n\
+             breakpoint at 27\n\
+             next   27  OpMul\n     \
+             2  3\n     1  2\n     0  1\n\
+             program halted, result is 7\n"
+        );
+
+        let out = session("-1", &["b 9", "l", "bogus"]);
+        assert_eq!(
+            out,
+            [
+                "breakpoint set at 9",
+                "=>    0  OpConstant(1.0)",
+                "  *   9  OpMinus",
+                "     10  OpPop",
+                "unknown command \"bogus\", type help for the list of commands",
+                "",
+            ]
+            .join("\n")
+        );
+    }
+}
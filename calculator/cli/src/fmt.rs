@@ -0,0 +1,59 @@
+use std::{fs, path::PathBuf};
+
+use calculator_ast_parser::{format_source, FormatOptions};
+use clap::Args;
+
+#[derive(Debug, Args)]
+pub struct FmtArgs {
+    /// Scripts to format
+    #[arg(required = true)]
+    files: Vec<PathBuf>,
+    /// Only report files that are not formatted, without rewriting them
+    #[arg(long)]
+    check: bool,
+    /// Statements longer than this are wrapped
+    #[arg(long, default_value_t = FormatOptions::default().max_width)]
+    max_width: usize,
+}
+
+// formats every file in place, or with --check lists the ones that would change.
+// returns false if a file could not be formatted or, with --check, is not formatted
+pub fn run(args: FmtArgs) -> bool {
+    let options = FormatOptions {
+        max_width: args.max_width,
+        ..FormatOptions::default()
+    };
+
+    let mut success = true;
+    for path in &args.files {
+        let source = match fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(e) => {
+                eprintln!("error: {}: {}", path.display(), e);
+                success = false;
+                continue;
+            }
+        };
+
+        let formatted = match format_source(&source, &options) {
+            Ok(formatted) => formatted,
+            Err(e) => {
+                eprintln!("error: {}:\n{}", path.display(), e);
+                success = false;
+                continue;
+            }
+        };
+
+        if formatted == source {
+            continue;
+        }
+        if args.check {
+            println!("{} is not formatted", path.display());
+            success = false;
+        } else if let Err(e) = fs::write(path, formatted) {
+            eprintln!("error: {}: {}", path.display(), e);
+            success = false;
+        }
+    }
+    success
+}
@@ -0,0 +1,41 @@
+mod csv;
+mod debug;
+mod fmt;
+mod profile;
+
+use clap::{Parser, Subcommand};
+
+#[derive(Debug, Parser)]
+#[command(author, version)]
+#[command(about = "calc - tools to work with calculator scripts")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Rewrite .calc scripts in canonical style
+    Fmt(fmt::FmtArgs),
+    /// Step through the bytecode of a script in the vm
+    Debug(debug::DebugArgs),
+    /// Count and time the instructions a script executes in the vm
+    Profile(profile::ProfileArgs),
+    /// Append columns computed by formulas over the columns of a CSV file
+    Csv(csv::CsvArgs),
+}
+
+// cargo run --package calculator-cli --bin calc -- fmt --check script.calc
+fn main() {
+    let cli = Cli::parse();
+
+    let success = match cli.command {
+        Command::Fmt(args) => fmt::run(args),
+        Command::Debug(args) => debug::run(args),
+        Command::Profile(args) => profile::run(args),
+        Command::Csv(args) => csv::run(args),
+    };
+    if !success {
+        std::process::exit(1);
+    }
+}
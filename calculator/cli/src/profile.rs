@@ -0,0 +1,244 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use calculator_ast_parser::{parser, Compile, Optimizer};
+use calculator_vm::{
+    bytecode::{Bytecode, Interpreter},
+    opcode::OpCode,
+    peephole,
+    profile::{Counter, Profiler},
+    vm::VM,
+};
+use clap::Args;
+
+#[derive(Debug, Args)]
+pub struct ProfileArgs {
+    /// Script to profile
+    file: PathBuf,
+    /// Evaluate the script this many times
+    #[arg(long, default_value_t = 1)]
+    runs: u32,
+    /// Also write the profile as folded stacks, the input of flamegraph tools
+    #[arg(long)]
+    folded: Option<PathBuf>,
+    /// Profile the bytecode as the parser produces it, without the optimizers
+    #[arg(long)]
+    no_optimize: bool,
+}
+
+// instructions listed in the per instruction table
+const TOP_INSTRUCTIONS: usize = 20;
+
+// runs the script in the vm with the profiler on and prints where the time goes,
+// per statement of the script, per op code and per instruction
+pub fn run(args: ProfileArgs) -> bool {
+    let source = match fs::read_to_string(&args.file) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("error: {}: {}", args.file.display(), e);
+            return false;
+        }
+    };
+    let statements = match parser::parse_statements(&source) {
+        Ok(statements) => statements,
+        Err(e) => {
+            eprintln!("error: {}:\n{}", args.file.display(), e);
+            return false;
+        }
+    };
+    let labels: Vec<String> = statements
+        .iter()
+        .map(|statement| format!("line {}", source[..statement.span.start].matches('\n').count() + 1))
+        .collect();
+
+    let ast = statements.into_iter().map(|statement| statement.node).collect();
+    let bytecode = if args.no_optimize {
+        Interpreter::from_ast(ast)
+    } else {
+        let bytecode = Interpreter::from_ast_with_superinstructions(Optimizer::default().optimize(ast));
+        peephole::optimize(&bytecode).0
+    };
+
+    let mut vm = VM::new(bytecode.clone());
+    vm.enable_profiler();
+    for _ in 0..args.runs {
+        if let Err(e) = vm.run() {
+            eprintln!("error: {}: {}", args.file.display(), e);
+            return false;
+        }
+    }
+    let profile = Profile::new(vm.take_profiler().unwrap(), &bytecode, labels);
+
+    let mut stdout = io::stdout();
+    if let Err(e) = profile.report(&mut stdout) {
+        eprintln!("error: {}", e);
+        return false;
+    }
+    if let Some(path) = &args.folded {
+        let result = fs::File::create(path).and_then(|mut file| profile.write_folded(&args.file, &mut file));
+        if let Err(e) = result {
+            eprintln!("error: {}: {}", path.display(), e);
+            return false;
+        }
+    }
+    true
+}
+
+struct Profile {
+    profiler: Profiler,
+    // statement every instruction offset belongs to
+    statements: BTreeMap<usize, usize>,
+    labels: Vec<String>,
+}
+
+impl Profile {
+    // every statement of a script compiles to instructions ending with its own OpPop
+    fn new(profiler: Profiler, bytecode: &Bytecode, labels: Vec<String>) -> Self {
+        let mut statements = BTreeMap::new();
+        let mut statement = 0;
+        for (offset, opcode) in bytecode.disassemble() {
+            statements.insert(offset, statement);
+            if opcode == OpCode::OpPop {
+                statement += 1;
+            }
+        }
+        Self {
+            profiler,
+            statements,
+            labels,
+        }
+    }
+
+    fn by_statement(&self) -> Vec<(&str, Counter)> {
+        let mut counters = vec![Counter::default(); self.labels.len()];
+        for (offset, _, counter) in self.profiler.by_offset() {
+            let counter_of_statement = &mut counters[self.statements[&offset]];
+            counter_of_statement.count += counter.count;
+            counter_of_statement.time += counter.time;
+        }
+        let mut by_statement: Vec<_> = self.labels.iter().map(String::as_str).zip(counters).collect();
+        by_statement.sort_by_key(|(_, counter)| std::cmp::Reverse(counter.time));
+        by_statement
+    }
+
+    fn report(&self, out: &mut dyn Write) -> io::Result<()> {
+        let total = self.profiler.total();
+        let share = |time: Duration| {
+            if total.time.is_zero() {
+                return 0.0;
+            }
+            time.as_secs_f64() / total.time.as_secs_f64() * 100.0
+        };
+        let duration = |time: Duration| format!("{:?}", time);
+
+        writeln!(out, "{} instructions executed in {:?}", total.count, total.time)?;
+
+        writeln!(out, "\n{:>6}  {:>12}  {:>10}  statement", "share", "time", "count")?;
+        for (label, counter) in self.by_statement() {
+            writeln!(
+                out,
+                "{:>5.1}%  {:>12}  {:>10}  {}",
+                share(counter.time),
+                duration(counter.time),
+                counter.count,
+                label
+            )?;
+        }
+
+        writeln!(out, "\n{:>6}  {:>12}  {:>10}  {:>10}  op code", "share", "time", "count", "mean")?;
+        for (name, counter) in self.profiler.by_opcode() {
+            writeln!(
+                out,
+                "{:>5.1}%  {:>12}  {:>10}  {:>10}  {}",
+                share(counter.time),
+                duration(counter.time),
+                counter.count,
+                duration(counter.mean()),
+                name
+            )?;
+        }
+
+        writeln!(out, "\n{:>6}  {:>12}  {:>10}  {:>6}  instruction", "share", "time", "count", "offset")?;
+        for (offset, opcode, counter) in self.profiler.by_offset().into_iter().take(TOP_INSTRUCTIONS) {
+            writeln!(
+                out,
+                "{:>5.1}%  {:>12}  {:>10}  {:>6}  {:?}  ({})",
+                share(counter.time),
+                duration(counter.time),
+                counter.count,
+                offset,
+                opcode,
+                self.labels[self.statements[&offset]]
+            )?;
+        }
+        Ok(())
+    }
+
+    // one line per stack of frames with the nanoseconds spent in it:
+    //     script.calc;line 3;OpMul 1200
+    // statements are the only frames below the script until functions exist
+    fn write_folded(&self, script: &Path, out: &mut dyn Write) -> io::Result<()> {
+        let mut stacks: BTreeMap<(usize, &str), Duration> = BTreeMap::new();
+        for (offset, opcode, counter) in self.profiler.by_offset() {
+            *stacks.entry((self.statements[&offset], opcode.name())).or_default() += counter.time;
+        }
+
+        let script = script.display().to_string().replace(';', "_");
+        for ((statement, name), time) in stacks {
+            writeln!(out, "{};{};{} {}", script, self.labels[statement], name, time.as_nanos())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn statements() {
+        let source = "1 + 2;\n\n3 * 4 * 5";
+        let statements = parser::parse_statements(source).unwrap();
+        let bytecode = Interpreter::from_ast(statements.into_iter().map(|statement| statement.node).collect());
+
+        let mut vm = VM::new(bytecode.clone());
+        vm.enable_profiler();
+        vm.run().unwrap();
+        vm.run().unwrap();
+        let labels = vec!["line 1".to_string(), "line 3".to_string()];
+        let profile = Profile::new(vm.take_profiler().unwrap(), &bytecode, labels);
+
+        let mut counts: Vec<_> = profile.by_statement().into_iter().map(|(label, counter)| (label, counter.count)).collect();
+        counts.sort();
+        assert_eq!(counts, vec![("line 1", 2 * 4), ("line 3", 2 * 6)]);
+
+        let mut folded = vec![];
+        profile.write_folded(Path::new("rules.calc"), &mut folded).unwrap();
+        let stacks: Vec<_> = String::from_utf8(folded)
+            .unwrap()
+            .lines()
+            .map(|line| line.rsplit_once(' ').unwrap().0.to_string())
+            .collect();
+        assert_eq!(
+            stacks,
+            vec![
+                "rules.calc;line 1;OpAdd",
+                "rules.calc;line 1;OpConstant",
+                "rules.calc;line 1;OpPop",
+                "rules.calc;line 3;OpConstant",
+                "rules.calc;line 3;OpMul",
+                "rules.calc;line 3;OpPop",
+            ]
+        );
+
+        let mut report = vec![];
+        profile.report(&mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.starts_with("20 instructions executed in"), "{}", report);
+    }
+}
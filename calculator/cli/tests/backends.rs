@@ -0,0 +1,57 @@
+// what every CompiledExpr does the same, whatever the backend
+
+use calculator_ast_parser::{expr, CompiledExpr, Host, Inputs, Result, Type};
+use calculator_compiler::compiler::JitExpr;
+use calculator_interpreter::interpreter::AstExpr;
+use calculator_vm::expr::VmExpr;
+
+type Compile = fn(&str, Inputs, &Host) -> Result<Box<dyn CompiledExpr>>;
+
+fn backends() -> Vec<(&'static str, Compile)> {
+    vec![
+        ("interpreter", |source, inputs, host| Ok(Box::new(AstExpr::compile(source, inputs, host)?))),
+        ("vm", |source, inputs, host| Ok(Box::new(VmExpr::compile(source, inputs, host)?))),
+        ("llvm", |source, inputs, host| Ok(Box::new(JitExpr::compile(source, inputs, host)?))),
+    ]
+}
+
+fn host() -> Host {
+    let mut host = Host::new();
+    host.register("double", &[Type::Number], |args| 2.0 * args[0].as_number().unwrap());
+    host
+}
+
+#[test]
+fn compiled_expr() {
+    let host = host();
+    let inputs = Inputs::new(&["price", "qty", "discount"]);
+    for (backend, compile) in backends() {
+        let mut formula = compile("price * qty * (1 - discount)", inputs.clone(), &host).unwrap();
+        assert_eq!(formula.inputs(), &inputs);
+        assert_eq!(formula.eval(&[2.5, 4.0, 0.5]), 5.0, "{}", backend);
+        assert_eq!(formula.eval(&[10.0, 3.0, 0.0]), 30.0, "{}", backend);
+        assert!(formula.eval(&[f64::NAN, 1.0, 0.0]).is_nan(), "{}", backend);
+        assert_eq!(formula.fork().eval(&[2.5, 4.0, 0.5]), 5.0, "{}", backend);
+
+        let mut formula = compile("x; double(x) - -y", Inputs::new(&["x", "y"]), &host).unwrap();
+        assert_eq!(formula.eval(&[3.0, 1.0]), 7.0, "{}", backend);
+
+        let error = compile("price * tax", inputs.clone(), &host).err().unwrap();
+        assert_eq!(error.to_string(), "unknown variable `tax`", "{}", backend);
+    }
+}
+
+// the rows split across threads give the same results as a single fork
+#[test]
+fn eval_batch_parallel() {
+    let host = host();
+    let rows = expr::MIN_ROWS_PER_THREAD * 2 + 1;
+    let xs: Vec<f64> = (0..rows).map(|row| row as f64).collect();
+    let ys: Vec<f64> = (0..rows).map(|row| (row % 5) as f64).collect();
+    for (backend, compile) in backends() {
+        let formula = compile("x * y - double(x) / 3", Inputs::new(&["x", "y"]), &host).unwrap();
+        let expected = formula.fork().eval_batch(&[&xs, &ys]);
+        assert_eq!(expected.len(), rows);
+        assert_eq!(expr::eval_batch_parallel(formula.as_ref(), &[&xs, &ys], 3), expected, "{}", backend);
+    }
+}
@@ -1,72 +1,61 @@
-use calculator_ast_parser::{Result, Compile, Node, Operator, Sign};
-use inkwell::{context::Context, types::FloatType, values::{FloatValue, AnyValue}, execution_engine::JitFunction};
+use std::{
+    any::Any,
+    cell::Cell,
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+    process,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use calculator_ast_parser::{
+    expr, Arg, Compile, CompiledExpr, Host, HostFunction, Inputs, Limits, Node, Operator, Result, Sign, Type,
+};
+use inkwell::{
+    builder::Builder,
+    context::Context,
+    execution_engine::{ExecutionEngine, JitFunction},
+    module::Module,
+    passes::{PassManager, PassManagerBuilder},
+    targets::{CodeModel, FileType, InitializationConfig, RelocMode, Target, TargetMachine},
+    types::FloatType,
+    values::{AnyValue, BasicMetadataValueEnum, FloatValue, FunctionValue, PointerValue},
+    AddressSpace, IntPredicate, OptimizationLevel,
+};
 
 pub struct Compiler;
 
-type CompileFunc = unsafe extern "C" fn() -> f64;
+// the jitted function takes the values of the variables, in the order of the inputs
+type CompileFunc = unsafe extern "C" fn(*const f64) -> f64;
+// the columns of the variables, the results and the number of rows
+type BatchFunc = unsafe extern "C" fn(*const *const f64, *mut f64, u64);
 
 impl Compile for Compiler {
     type Output = Result<f64>;
 
     // implement fn from_ast()
     fn from_ast(ast: Vec<calculator_ast_parser::Node>) -> Self::Output {
+        Compiler::from_ast_with_host(ast, &Host::default())
+    }
+}
+
+impl Compiler {
+    // same as Compile::from_ast, formulas may call the functions of host
+    pub fn from_ast_with_host(ast: Vec<Node>, host: &Host) -> Result<f64> {
+        // RecursiveBuilder recurses once per level of the ast
+        Limits::default().check_depth(&ast)?;
+        host.check(&ast)?;
+        // without inputs, any variable is unknown
+        let inputs = Inputs::default();
+        inputs.check(&ast)?;
+
         // llvm context? he LLVMContext is a central component in the LLVM 
         // infrastructure and serves as a container for various global data and settings 
         // that are used during the compilation process.
         let context = Context::create();
-        // what is llvm module? The Module class in LLVM represents a single translation 
-        // unit or a compilation module in LLVM IR (Intermediate Representation). 
-        // This class is part of the LLVM IR library and is used to represent 
-        // the entire program being compiled.
-        //
-        // A translation unit is the LLVM IR representation of a source file. 
-        // A program is composed of many translation unit linked together.
-        // 
-        // In the following code, I am declaring a group of translation units.
-        let module = context.create_module("compiler");
-
-
-        let builder = context.create_builder();
-
-        // declare execution engine
-        let execution_engine = module
-            .create_jit_execution_engine(inkwell::OptimizationLevel::None)
-            .unwrap();
-        
-        // declare function signature
-        let decimal_type = context.f64_type();
-        let fn_type = decimal_type.fn_type(&[], false);
-
-        let function = module.add_function("compile", fn_type, None);
-        // what is basic block in LLVM?
-        // a function is divided into basic blocks, the flow of a function 
-        // will go from one block to another until it reaches the end block.
-        // it is crucial to understand LLVM block correctly.
-        // for example, 
-        // define i32 @example_function(i32 %a, i32 %b) {
-        //     entry:
-        //       %sum = add i32 %a, %b
-        //       br label %exit
-          
-        //     exit:
-        //       %result = phi i32 [ %sum, %entry ]
-        //       ret i32 %result
-        // }
-        // instructions? instructions define what needs to be done in a block
-        // however, instructions and building blocks relation can be flexible.
-        // And, instructions can be inserted later into building blocks or arranged flexibly in different building blocks.
-        // Just need to make sure that the function will be executed in correct order.
-
-        let basic_block = context.append_basic_block(function, "entry");
-        // setting the builder position to insert instructions into basic blocks
-        builder.position_at_end(basic_block);
-        
-        // recursively add instructions into basic block by traversing ast tree
-        for node in ast {
-            let recursive_builder = RecursiveBuilder::new(decimal_type);
-            let return_value = recursive_builder.build(&node);
-            let _ = builder.build_return(Some(&return_value));
-        }
+        let (_module, execution_engine, function) = jit(&context, &ast, host, &inputs, OptimizationLevel::None);
         println!(
             "Generated LLVM IR: {}",
             function.print_to_string().to_string()
@@ -76,25 +65,568 @@ impl Compile for Compiler {
         unsafe {
             let compile_func: JitFunction<CompileFunc> = execution_engine.get_function("compile").unwrap();
 
-            Ok(compile_func.call())
+            let result = compile_func.call(std::ptr::null());
+            resume_host_panic();
+            Ok(result)
+        }
+    }
+}
+
+// ahead of time compilation: host functions are closures of this process, so
+// a formula compiled to a file cannot call any
+impl Compiler {
+    // writes the formula to an object file for any cpu of the architecture
+    // of this machine, the object defines
+    //     double compile(const double *inputs)
+    //     void compile_batch(const double **columns, double *results, uint64_t rows)
+    pub fn write_object(source: &str, inputs: &Inputs, path: &Path) -> Result<()> {
+        write_object_file(source, inputs, path, false)
+    }
+
+    // writes an executable taking the value of every input as an argument
+    // and printing the result, the object is linked by the system cc
+    pub fn write_executable(source: &str, inputs: &Inputs, path: &Path) -> Result<()> {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let object = std::env::temp_dir().join(format!(
+            "calc-{}-{}.o",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        write_object_file(source, inputs, &object, true)?;
+        let output = process::Command::new("cc").arg(&object).arg("-o").arg(path).output();
+        let _ = std::fs::remove_file(&object);
+        let output = output.map_err(|e| anyhow::anyhow!("cannot run cc: {}", e))?;
+        if !output.status.success() {
+            anyhow::bail!("cc failed: {}", String::from_utf8_lossy(&output.stderr).trim());
         }
-        
+        Ok(())
+    }
+}
 
+fn write_object_file(source: &str, inputs: &Inputs, path: &Path, with_main: bool) -> Result<()> {
+    let host = Host::default();
+    let ast = expr::prepare(source, inputs, &host)?;
+    let context = Context::create();
+    let module = build_module(&context, &ast, &host, inputs);
+    if with_main {
+        build_main(&context, &module, inputs);
     }
+    // position independent, as cc links executables by default
+    let level = OptimizationLevel::Default;
+    let machine = target_machine(level, "", "", RelocMode::PIC, CodeModel::Default);
+    optimize(&module, &machine, level);
+    machine
+        .write_to_file(&module, FileType::Object, path)
+        .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e.to_string()))
+}
+
+// a formula jitted once, every eval calls the same machine code.
+// Forks share the machine code, which never changes once jitted
+pub struct JitExpr {
+    // point into the code owned by jit
+    function: CompileFunc,
+    batch: BatchFunc,
+    jit: Arc<Jit>,
+    inputs: Inputs,
 }
 
-struct RecursiveBuilder<'a> {
-    f64_type: FloatType<'a>,
+// everything the machine code depends on
+struct Jit {
+    // fields are dropped in order, the context must go last
+    // owns the machine code
+    _execution_engine: ExecutionEngine<'static>,
+    module: Mutex<Module<'static>>,
+    // the jitted code points right into the functions of host
+    _host: Host,
+    _context: Box<Context>,
 }
 
-impl <'a> RecursiveBuilder<'a> {
-    fn new(f64_type: FloatType<'a>) -> Self {
-        Self{f64_type}
+// the llvm objects are only used by one thread at a time: the engine is left
+// alone once the functions are looked up and the module is behind a lock.
+// The machine code itself is never written to and the host functions it
+// calls are Send + Sync
+unsafe impl Send for Jit {}
+unsafe impl Sync for Jit {}
+
+impl JitExpr {
+    pub fn compile(source: &str, inputs: Inputs, host: &Host) -> Result<Self> {
+        let ast = expr::prepare(source, &inputs, host)?;
+        let context = Box::new(Context::create());
+        // the context is boxed so it never moves, and outlives everything built from it
+        let context_ref: &'static Context = unsafe { &*(context.as_ref() as *const Context) };
+        // moving a Host keeps its functions where they are, so the addresses
+        // baked into the code stay valid once it is stored in the JitExpr
+        let host = host.clone();
+        let (module, execution_engine, _) = jit(context_ref, &ast, &host, &inputs, OptimizationLevel::Default);
+        // the pointers stay valid as long as the engine, which jit keeps
+        let (function, batch) = unsafe {
+            let function: JitFunction<CompileFunc> = execution_engine.get_function("compile")?;
+            let batch: JitFunction<BatchFunc> = execution_engine.get_function("compile_batch")?;
+            (function.into_raw(), batch.into_raw())
+        };
+        Ok(Self {
+            function,
+            batch,
+            jit: Arc::new(Jit {
+                _execution_engine: execution_engine,
+                module: Mutex::new(module),
+                _host: host,
+                _context: context,
+            }),
+            inputs,
+        })
+    }
+
+    // the optimized llvm ir of the module
+    pub fn ir(&self) -> String {
+        self.jit.module.lock().unwrap().print_to_string().to_string()
+    }
+}
+
+impl CompiledExpr for JitExpr {
+    fn inputs(&self) -> &Inputs {
+        &self.inputs
+    }
+
+    fn fork(&self) -> Box<dyn CompiledExpr> {
+        Box::new(Self {
+            function: self.function,
+            batch: self.batch,
+            jit: self.jit.clone(),
+            inputs: self.inputs.clone(),
+        })
+    }
+
+    fn eval(&mut self, values: &[f64]) -> f64 {
+        assert_eq!(values.len(), self.inputs.len(), "wrong number of inputs");
+        // the function reads exactly inputs.len() values
+        let result = unsafe { (self.function)(values.as_ptr()) };
+        resume_host_panic();
+        result
+    }
+
+    fn eval_batch(&mut self, columns: &[&[f64]]) -> Vec<f64> {
+        let rows = expr::rows(&self.inputs, columns);
+        let columns: Vec<*const f64> = columns.iter().map(|column| column.as_ptr()).collect();
+        let mut results = Vec::with_capacity(rows);
+        // the function writes exactly rows results, reading as many values from every column
+        unsafe {
+            (self.batch)(columns.as_ptr(), results.as_mut_ptr(), rows as u64);
+            results.set_len(rows);
+        }
+        resume_host_panic();
+        results
+    }
+}
+
+// builds the ast into the functions of a module and jits them, see build_module
+fn jit<'ctx>(
+    context: &'ctx Context,
+    ast: &[Node],
+    host: &Host,
+    inputs: &Inputs,
+    level: OptimizationLevel,
+) -> (Module<'ctx>, ExecutionEngine<'ctx>, FunctionValue<'ctx>) {
+    let module = build_module(context, ast, host, inputs);
+
+    // declare execution engine
+    let execution_engine = module
+        .create_jit_execution_engine(level)
+        .unwrap();
+    execution_engine.add_global_mapping(&module.get_function("calc_host_call").unwrap(), host_call as *const () as usize);
+
+    if level != OptimizationLevel::None {
+        let machine = target_machine(
+            level,
+            &TargetMachine::get_host_cpu_name().to_string(),
+            &TargetMachine::get_host_cpu_features().to_string(),
+            RelocMode::Default,
+            CodeModel::JITDefault,
+        );
+        optimize(&module, &machine, level);
+    }
+    let function = module.get_function("compile").unwrap();
+    (module, execution_engine, function)
+}
+
+// builds the ast into the functions of a new module:
+//     define double @compile(double* %inputs)
+//     define void @compile_batch(double** %columns, double* %results, i64 %rows)
+// the ast must have been checked against the limits, host and inputs
+fn build_module<'ctx>(context: &'ctx Context, ast: &[Node], host: &Host, inputs: &Inputs) -> Module<'ctx> {
+    // what is llvm module? The Module class in LLVM represents a single translation 
+    // unit or a compilation module in LLVM IR (Intermediate Representation). 
+    // This class is part of the LLVM IR library and is used to represent 
+    // the entire program being compiled.
+    //
+    // A translation unit is the LLVM IR representation of a source file. 
+    // A program is composed of many translation unit linked together.
+    // 
+    // In the following code, I am declaring a group of translation units.
+    let module = context.create_module("compiler");
+
+
+    let builder = context.create_builder();
+
+    // declare function signature
+    let decimal_type = context.f64_type();
+    let fn_type = decimal_type.fn_type(&[decimal_type.ptr_type(AddressSpace::default()).into()], false);
+
+    let function = module.add_function("compile", fn_type, None);
+    let values = function.get_first_param().unwrap().into_pointer_value();
+    values.set_name("inputs");
+
+    // host functions are Rust closures, the jitted code reaches them through
+    // an external function the execution engine maps to host_call:
+    //     declare double @calc_host_call(i8* %function, double* %numbers, i8** %strings, i64* %lengths)
+    let i8_ptr_type = context.i8_type().ptr_type(AddressSpace::default());
+    let host_call_type = decimal_type.fn_type(
+        &[
+            i8_ptr_type.into(),
+            decimal_type.ptr_type(AddressSpace::default()).into(),
+            i8_ptr_type.ptr_type(AddressSpace::default()).into(),
+            context.i64_type().ptr_type(AddressSpace::default()).into(),
+        ],
+        false,
+    );
+    let host_call_function = module.add_function("calc_host_call", host_call_type, None);
+
+    // what is basic block in LLVM?
+    // a function is divided into basic blocks, the flow of a function 
+    // will go from one block to another until it reaches the end block.
+    // it is crucial to understand LLVM block correctly.
+    // for example, 
+    // define i32 @example_function(i32 %a, i32 %b) {
+    //     entry:
+    //       %sum = add i32 %a, %b
+    //       br label %exit
+      
+    //     exit:
+    //       %result = phi i32 [ %sum, %entry ]
+    //       ret i32 %result
+    // }
+    // instructions? instructions define what needs to be done in a block
+    // however, instructions and building blocks relation can be flexible.
+    // And, instructions can be inserted later into building blocks or arranged flexibly in different building blocks.
+    // Just need to make sure that the function will be executed in correct order.
+
+    let basic_block = context.append_basic_block(function, "entry");
+    // setting the builder position to insert instructions into basic blocks
+    builder.position_at_end(basic_block);
+    let values: Vec<FloatValue> = (0..inputs.len())
+        .map(|index| {
+            let ptr = unsafe { builder.build_gep(values, &[context.i32_type().const_int(index as u64, false)], "ptr") };
+            builder.build_load(ptr, &inputs.names()[index]).into_float_value()
+        })
+        .collect();
+    
+    // recursively add instructions into basic block by traversing ast tree,
+    // a script returns the value of its last expression
+    let recursive_builder = RecursiveBuilder::new(context, &builder, &builder, host, host_call_function, inputs, &values);
+    let return_value = recursive_builder.build_script(ast);
+    let _ = builder.build_return(Some(&return_value));
+
+    build_batch(context, &module, ast, host, host_call_function, inputs);
+    module
+}
+
+// the same script in a loop over every row of the columns of the inputs:
+//     define void @compile_batch(double** %columns, double* %results, i64 %rows)
+// the loop body has no branch and every row is independent of the others,
+// which is the shape the loop vectorizer of llvm looks for
+fn build_batch<'ctx>(
+    context: &'ctx Context,
+    module: &Module<'ctx>,
+    ast: &[Node],
+    host: &Host,
+    host_call_function: FunctionValue<'ctx>,
+    inputs: &Inputs,
+) {
+    let f64_ptr_type = context.f64_type().ptr_type(AddressSpace::default());
+    let i64_type = context.i64_type();
+    let fn_type = context.void_type().fn_type(
+        &[f64_ptr_type.ptr_type(AddressSpace::default()).into(), f64_ptr_type.into(), i64_type.into()],
+        false,
+    );
+    let function = module.add_function("compile_batch", fn_type, None);
+    let columns = function.get_nth_param(0).unwrap().into_pointer_value();
+    let results = function.get_nth_param(1).unwrap().into_pointer_value();
+    let rows = function.get_nth_param(2).unwrap().into_int_value();
+    columns.set_name("columns");
+    results.set_name("results");
+    rows.set_name("rows");
+
+    // entry:
+    //   %price.column = load double*, double** %ptr
+    //   br i1 %empty, label %exit, label %loop
+    let entry = context.append_basic_block(function, "entry");
+    let body = context.append_basic_block(function, "loop");
+    let exit = context.append_basic_block(function, "exit");
+    let builder = context.create_builder();
+    builder.position_at_end(entry);
+    let column_ptrs: Vec<PointerValue> = (0..inputs.len())
+        .map(|index| {
+            let ptr = unsafe { builder.build_gep(columns, &[i64_type.const_int(index as u64, false)], "ptr") };
+            let name = format!("{}.column", inputs.names()[index]);
+            builder.build_load(ptr, &name).into_pointer_value()
+        })
+        .collect();
+    // the arguments of host calls are allocated once in entry, not once per row
+    let allocas = context.create_builder();
+    allocas.position_at_end(entry);
+
+    // loop:
+    //   %row = phi i64 [ 0, %entry ], [ %next, %loop ]
+    //   %price = load double, double* %price.ptr
+    //   ...
+    //   store double %result, double* %result.ptr
+    //   %next = add i64 %row, 1
+    //   br i1 %done, label %exit, label %loop
+    builder.position_at_end(body);
+    let row = builder.build_phi(i64_type, "row");
+    let row_value = row.as_basic_value().into_int_value();
+    let values: Vec<FloatValue> = column_ptrs
+        .iter()
+        .zip(inputs.names())
+        .map(|(column, name)| {
+            let ptr = unsafe { builder.build_gep(*column, &[row_value], "ptr") };
+            builder.build_load(ptr, name).into_float_value()
+        })
+        .collect();
+    let recursive_builder = RecursiveBuilder::new(context, &builder, &allocas, host, host_call_function, inputs, &values);
+    let result = recursive_builder.build_script(ast);
+    let ptr = unsafe { builder.build_gep(results, &[row_value], "result.ptr") };
+    builder.build_store(ptr, result);
+    let next = builder.build_int_add(row_value, i64_type.const_int(1, false), "next");
+    let done = builder.build_int_compare(IntPredicate::EQ, next, rows, "done");
+    builder.build_conditional_branch(done, exit, body);
+    row.add_incoming(&[(&i64_type.const_zero(), entry), (&next, body)]);
+
+    // the entry block is finished last, after the allocas of the loop body
+    builder.position_at_end(entry);
+    let empty = builder.build_int_compare(IntPredicate::EQ, rows, i64_type.const_zero(), "empty");
+    builder.build_conditional_branch(empty, exit, body);
+
+    builder.position_at_end(exit);
+    builder.build_return(None);
+}
+
+// the entry point of an executable, every argument is the value of an input:
+//     define i32 @main(i32 %argc, i8** %argv)
+// prints the result of @compile, or which arguments are expected and
+// returns 2 unless there is a number per input
+fn build_main<'ctx>(context: &'ctx Context, module: &Module<'ctx>, inputs: &Inputs) {
+    let i8_type = context.i8_type();
+    let i32_type = context.i32_type();
+    let i64_type = context.i64_type();
+    let f64_type = context.f64_type();
+    let i8_ptr_type = i8_type.ptr_type(AddressSpace::default());
+    let i8_ptr_ptr_type = i8_ptr_type.ptr_type(AddressSpace::default());
+
+    // from the C library cc links with
+    let strtod = module.add_function("strtod", f64_type.fn_type(&[i8_ptr_type.into(), i8_ptr_ptr_type.into()], false), None);
+    let printf = module.add_function("printf", i32_type.fn_type(&[i8_ptr_type.into()], true), None);
+    let write_type = i64_type.fn_type(&[i32_type.into(), i8_ptr_type.into(), i64_type.into()], false);
+    let write = module.add_function("write", write_type, None);
+
+    let main_type = i32_type.fn_type(&[i32_type.into(), i8_ptr_ptr_type.into()], false);
+    let main = module.add_function("main", main_type, None);
+    let argc = main.get_nth_param(0).unwrap().into_int_value();
+    argc.set_name("argc");
+    let argv = main.get_nth_param(1).unwrap().into_pointer_value();
+    argv.set_name("argv");
+
+    let builder = context.create_builder();
+    let entry = context.append_basic_block(main, "entry");
+    let parse = context.append_basic_block(main, "parse");
+    let run = context.append_basic_block(main, "run");
+    let usage = context.append_basic_block(main, "usage");
+
+    builder.position_at_end(entry);
+    let values = builder.build_array_alloca(f64_type, i32_type.const_int(inputs.len() as u64, false), "values");
+    let end = builder.build_alloca(i8_ptr_type, "end");
+    let expected = i32_type.const_int(inputs.len() as u64 + 1, false);
+    let count = builder.build_int_compare(IntPredicate::EQ, argc, expected, "count");
+    builder.build_conditional_branch(count, parse, usage);
+
+    // strtod must read a number and the whole argument
+    builder.position_at_end(parse);
+    let mut valid = context.bool_type().const_all_ones();
+    for (index, name) in inputs.names().iter().enumerate() {
+        let arg = unsafe { builder.build_gep(argv, &[i32_type.const_int(index as u64 + 1, false)], "arg") };
+        let arg = builder.build_load(arg, "arg").into_pointer_value();
+        let value = builder
+            .build_call(strtod, &[arg.into(), end.into()], name)
+            .try_as_basic_value()
+            .left()
+            .unwrap();
+        let ptr = unsafe { builder.build_gep(values, &[i32_type.const_int(index as u64, false)], "ptr") };
+        builder.build_store(ptr, value);
+
+        let rest = builder.build_load(end, "rest").into_pointer_value();
+        let last = builder.build_load(rest, "last").into_int_value();
+        let whole = builder.build_int_compare(IntPredicate::EQ, last, i8_type.const_zero(), "whole");
+        let start = builder.build_ptr_to_int(arg, i64_type, "start");
+        let stop = builder.build_ptr_to_int(rest, i64_type, "stop");
+        let read = builder.build_int_compare(IntPredicate::NE, start, stop, "read");
+        let number = builder.build_and(whole, read, "number");
+        valid = builder.build_and(valid, number, "valid");
+    }
+    builder.build_conditional_branch(valid, run, usage);
+
+    builder.position_at_end(run);
+    let compile = module.get_function("compile").unwrap();
+    let result = builder
+        .build_call(compile, &[values.into()], "result")
+        .try_as_basic_value()
+        .left()
+        .unwrap();
+    // enough digits to read the same number back
+    let format = builder.build_global_string_ptr("%.17g\n", "format");
+    builder.build_call(printf, &[format.as_pointer_value().into(), result.into()], "printed");
+    builder.build_return(Some(&i32_type.const_zero()));
+
+    builder.position_at_end(usage);
+    let message = match inputs.is_empty() {
+        true => "expected no argument\n".to_string(),
+        false => format!("expected a number per input: {}\n", inputs.names().join(" ")),
+    };
+    let text = builder.build_global_string_ptr(&message, "usage");
+    let len = i64_type.const_int(message.len() as u64, false);
+    let stderr = i32_type.const_int(2, false);
+    builder.build_call(write, &[stderr.into(), text.as_pointer_value().into(), len.into()], "written");
+    builder.build_return(Some(&i32_type.const_int(2, false)));
+}
+
+// a machine of the architecture of this one, tuned for cpu: the vectorizers
+// learn how wide its vector registers are from the cpu name and features.
+// An empty cpu name and features target any cpu of the architecture
+fn target_machine(
+    level: OptimizationLevel,
+    cpu: &str,
+    features: &str,
+    reloc: RelocMode,
+    code_model: CodeModel,
+) -> TargetMachine {
+    Target::initialize_native(&InitializationConfig::default()).unwrap();
+    let triple = TargetMachine::get_default_triple();
+    Target::from_triple(&triple)
+        .unwrap()
+        .create_target_machine(&triple, cpu, features, level, reloc, code_model)
+        .unwrap()
+}
+
+// the usual llvm pipeline at level, for the target of machine
+fn optimize(module: &Module, machine: &TargetMachine, level: OptimizationLevel) {
+    module.set_triple(&machine.get_triple());
+    module.set_data_layout(&machine.get_target_data().get_data_layout());
+
+    let passes = PassManager::create(());
+    machine.add_analysis_passes(&passes);
+    let builder = PassManagerBuilder::create();
+    builder.set_optimization_level(level);
+    builder.populate_module_pass_manager(&passes);
+    passes.run_on(module);
+}
+
+thread_local! {
+    // payload of a host function that panicked under the jitted code, a panic
+    // cannot unwind through it. Set by host_call, resumed once the jitted
+    // code returns to Rust on the same thread
+    static HOST_PANIC: Cell<Option<Box<dyn Any + Send>>> = Cell::new(None);
+}
+
+fn resume_host_panic() {
+    if let Some(payload) = HOST_PANIC.with(Cell::take) {
+        panic::resume_unwind(payload);
+    }
+}
+
+// the jitted code calls every host function through here, with its number
+// and string arguments each in their own array, in the order of the parameters.
+// Strings come with their length in bytes, they may hold a NUL
+extern "C" fn host_call(function: *const HostFunction, numbers: *const f64, strings: *const *const u8, lengths: *const u64) -> f64 {
+    // the pointers come from RecursiveBuilder::build_call, the host outlives the call
+    let function = unsafe { &*function };
+    let (mut number, mut string) = (0, 0);
+    let args: Vec<Arg> = function
+        .params
+        .iter()
+        .map(|param| match param {
+            Type::Number => {
+                number += 1;
+                Arg::Number(unsafe { *numbers.add(number - 1) })
+            }
+            Type::Str => {
+                string += 1;
+                let bytes = unsafe { std::slice::from_raw_parts(*strings.add(string - 1), *lengths.add(string - 1) as usize) };
+                // the bytes of a string literal of the source, copied as is from a &str
+                Arg::Str(unsafe { std::str::from_utf8_unchecked(bytes) })
+            }
+        })
+        .collect();
+    HOST_PANIC.with(|pending| {
+        // the rest of the evaluation is thrown away, no need to call anything else
+        if let Some(payload) = pending.take() {
+            pending.set(Some(payload));
+            return f64::NAN;
+        }
+        panic::catch_unwind(AssertUnwindSafe(|| function.call(&args))).unwrap_or_else(|payload| {
+            pending.set(Some(payload));
+            f64::NAN
+        })
+    })
+}
+
+struct RecursiveBuilder<'a, 'ctx> {
+    context: &'ctx Context,
+    builder: &'a Builder<'ctx>,
+    // positioned in the entry block of the function, where allocas belong
+    allocas: &'a Builder<'ctx>,
+    f64_type: FloatType<'ctx>,
+    host: &'a Host,
+    host_call: FunctionValue<'ctx>,
+    // value of every input, already loaded, in the order of inputs
+    inputs: &'a Inputs,
+    values: &'a [FloatValue<'ctx>],
+}
+
+impl <'a, 'ctx> RecursiveBuilder<'a, 'ctx> {
+    fn new(
+        context: &'ctx Context,
+        builder: &'a Builder<'ctx>,
+        allocas: &'a Builder<'ctx>,
+        host: &'a Host,
+        host_call: FunctionValue<'ctx>,
+        inputs: &'a Inputs,
+        values: &'a [FloatValue<'ctx>],
+    ) -> Self {
+        Self {
+            context,
+            builder,
+            allocas,
+            f64_type: context.f64_type(),
+            host,
+            host_call,
+            inputs,
+            values,
+        }
+    }
+
+    // every expression is built, the value of the last one is returned
+    fn build_script(&self, ast: &[Node]) -> FloatValue<'ctx> {
+        let mut value = self.f64_type.const_float(0.0);
+        for node in ast {
+            value = self.build(node);
+        }
+        value
     }
 
     // ast is currently in the form of
 
-    fn build(&self, ast: &Node) -> FloatValue {
+    // every operator is an instruction of the function body; only the
+    // Builder still folds an operator whose operands are all constants
+    fn build(&self, ast: &Node) -> FloatValue<'ctx> {
         match ast {
             Node::Number(dec) => self.f64_type.const_float(*dec),
             Node::BinaryExpr { op, lhs, rhs } => {
@@ -103,10 +635,10 @@ impl <'a> RecursiveBuilder<'a> {
 
                 // perform computation
                 match op {
-                    Operator::Sub => lhs_num.const_sub(rhs_num),
-                    Operator::Add => lhs_num.const_add(rhs_num),
-                    Operator::Mul => lhs_num.const_mul(rhs_num),
-                    Operator::Div => lhs_num.const_div(rhs_num)
+                    Operator::Sub => self.builder.build_float_sub(lhs_num, rhs_num, "sub"),
+                    Operator::Add => self.builder.build_float_add(lhs_num, rhs_num, "add"),
+                    Operator::Mul => self.builder.build_float_mul(lhs_num, rhs_num, "mul"),
+                    Operator::Div => self.builder.build_float_div(lhs_num, rhs_num, "div")
                 }
             }
             Node::UnaryExpr { op, child } => {
@@ -114,15 +646,92 @@ impl <'a> RecursiveBuilder<'a> {
 
                 match op {
                     Sign::Positive => child,
-                    Sign::Negative => child.const_neg()
+                    Sign::Negative => self.builder.build_float_neg(child, "neg")
                 }
             }
+            Node::Call { name, args } => self.build_call(name, args),
+            Node::Str(value) => unreachable!("string \"{}\" outside of a call", value),
+            // variables were checked against the inputs before building
+            Node::Var(name) => self.values[self.inputs.index(name).unwrap()],
+        }
+    }
+
+    // stores the arguments into arrays on the stack and calls
+    // @calc_host_call with the address of the host function:
+    //     %numbers = alloca double, i32 1
+    //     store double 2.0, double* %number
+    //     %call = call double @calc_host_call(i8* inttoptr (...), double* %numbers, i8** %strings, i64* %lengths)
+    fn build_call(&self, name: &str, args: &[Node]) -> FloatValue<'ctx> {
+        // calls were checked against the host before building
+        let function: &HostFunction = self.host.get(name).unwrap();
+        let i32_type = self.context.i32_type();
+        let i8_ptr_type = self.context.i8_type().ptr_type(AddressSpace::default());
+
+        let numbers: Vec<FloatValue> = args
+            .iter()
+            .filter(|arg| !matches!(arg, Node::Str(_)))
+            .map(|arg| self.build(arg))
+            .collect();
+        let strings: Vec<&str> = args
+            .iter()
+            .filter_map(|arg| match arg {
+                Node::Str(value) => Some(value.as_str()),
+                _ => None,
+            })
+            .collect();
+
+        let numbers_ptr = self.allocas.build_array_alloca(
+            self.f64_type,
+            i32_type.const_int(numbers.len() as u64, false),
+            "numbers",
+        );
+        for (i, number) in numbers.into_iter().enumerate() {
+            let ptr = unsafe { self.builder.build_gep(numbers_ptr, &[i32_type.const_int(i as u64, false)], "number") };
+            self.builder.build_store(ptr, number);
         }
+        let strings_ptr = self.allocas.build_array_alloca(
+            i8_ptr_type,
+            i32_type.const_int(strings.len() as u64, false),
+            "strings",
+        );
+        let lengths_ptr = self.allocas.build_array_alloca(
+            self.context.i64_type(),
+            i32_type.const_int(strings.len() as u64, false),
+            "lengths",
+        );
+        for (i, string) in strings.into_iter().enumerate() {
+            // the bytes without a terminating NUL, a global string would end at the first NUL
+            let bytes = self.context.const_string(string.as_bytes(), false);
+            let value = self.allocas.build_alloca(bytes.get_type(), "str");
+            self.builder.build_store(value, bytes);
+            let value = self.builder.build_pointer_cast(value, i8_ptr_type, "str");
+            let index = i32_type.const_int(i as u64, false);
+            let ptr = unsafe { self.builder.build_gep(strings_ptr, &[index], "string") };
+            self.builder.build_store(ptr, value);
+            let ptr = unsafe { self.builder.build_gep(lengths_ptr, &[index], "length") };
+            self.builder.build_store(ptr, self.context.i64_type().const_int(string.len() as u64, false));
+        }
+
+        let function_ptr = self
+            .context
+            .i64_type()
+            .const_int(function as *const HostFunction as u64, false)
+            .const_to_pointer(i8_ptr_type);
+        let args: [BasicMetadataValueEnum; 4] =
+            [function_ptr.into(), numbers_ptr.into(), strings_ptr.into(), lengths_ptr.into()];
+        self.builder
+            .build_call(self.host_call, &args, name)
+            .try_as_basic_value()
+            .left()
+            .unwrap()
+            .into_float_value()
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use calculator_ast_parser::testing;
+
     use super::*;
 
     #[test]
@@ -133,7 +742,201 @@ mod tests {
         assert_eq!(Compiler::from_source("1.7 + ((2.3 + 3.1) - (2.9 + 3.5))").unwrap(), 0.7);
         assert_eq!(Compiler::from_source("1.2 * (1.9 + 2.9)").unwrap(), 5.76);
         assert_eq!(Compiler::from_source("(7.8+2.4)/(1.3+2.5)").unwrap(), 2.6842105263157894);
-        // parser fails
-        // assert_eq!(Jit::from_source("2 + 3 - 1").unwrap(), 4);
+        assert_eq!(Compiler::from_source("2 + 3 - 1").unwrap(), 4.0);
+        assert_eq!(Compiler::from_source("1 + 2;\n# comment\n3 * 4;").unwrap(), 12.0);
+    }
+
+    #[test]
+    fn signs() {
+        for (source, expected) in testing::sign_matrix() {
+            assert_eq!(Compiler::from_source(&source).unwrap(), expected, "{}", source);
+        }
+    }
+
+    #[test]
+    fn host_functions() {
+        let mut host = Host::new();
+        host.register("lookup_rate", &[Type::Str], |args| match args[0].as_str() {
+            Some("EUR") => 1.25,
+            Some("GBP") => 1.5,
+            _ => f64::NAN,
+        })
+        .register("scale", &[Type::Number, Type::Str, Type::Number], |args| {
+            let unit = match args[1].as_str().unwrap() {
+                "k" => 1000.0,
+                _ => 1.0,
+            };
+            args[0].as_number().unwrap() * unit + args[2].as_number().unwrap()
+        })
+        .register("zero", &[], |_| 0.0);
+
+        let run = |source: &str| {
+            Compiler::from_ast_with_host(calculator_ast_parser::parser::parse(source).unwrap(), &host)
+        };
+        assert_eq!(run("100 * lookup_rate(\"EUR\")").unwrap(), 125.0);
+        assert_eq!(run("-lookup_rate(\"GBP\") * 2 + zero() + 1").unwrap(), -2.0);
+        assert_eq!(run("scale(2 - 0.5, \"k\", -scale(1, \"\", zero())); 3 - 1").unwrap(), 2.0);
+        assert_eq!(run("scale(2 - 0.5, \"k\", -scale(1, \"\", zero()))").unwrap(), 1499.0);
+
+        let error = run("lookup_rate(1)").unwrap_err();
+        assert_eq!(error.to_string(), "argument 1 of `lookup_rate` must be a string");
+        assert!(Compiler::from_source("zero()").is_err());
+    }
+
+    #[test]
+    fn strings() {
+        let mut host = Host::new();
+        host.register("len", &[Type::Str, Type::Number, Type::Str], |args| {
+            (args[0].as_str().unwrap().len() * 10 + args[2].as_str().unwrap().len()) as f64 + args[1].as_number().unwrap()
+        });
+        // NUL is a character like any other in a literal
+        let ast = calculator_ast_parser::parser::parse("len(\"a\0b\", 0.5, \"\")").unwrap();
+        assert_eq!(Compiler::from_ast_with_host(ast, &host).unwrap(), 30.5);
+        let mut formula = JitExpr::compile("len(\"\0\", x, \"é\0\")", Inputs::new(&["x"]), &host).unwrap();
+        assert_eq!(formula.eval(&[1.0]), 14.0);
+        assert_eq!(formula.eval_batch(&[&[1.0, 2.0]]), vec![14.0, 15.0]);
+    }
+
+    // a panic cannot unwind through the jitted code, it is carried over it
+    #[test]
+    fn host_panic() {
+        let mut host = Host::new();
+        host.register("half", &[Type::Number], |args| {
+            let val = args[0].as_number().unwrap();
+            assert!(val >= 0.0, "negative value");
+            val / 2.0
+        });
+        let message = |payload: Box<dyn Any + Send>| payload.downcast_ref::<&str>().map(|message| message.to_string());
+
+        let ast = calculator_ast_parser::parser::parse("half(-1) + half(2)").unwrap();
+        let payload = panic::catch_unwind(AssertUnwindSafe(|| Compiler::from_ast_with_host(ast, &host))).unwrap_err();
+        assert_eq!(message(payload).as_deref(), Some("negative value"));
+
+        let mut formula = JitExpr::compile("half(x)", Inputs::new(&["x"]), &host).unwrap();
+        let payload = panic::catch_unwind(AssertUnwindSafe(|| formula.eval(&[-1.0]))).unwrap_err();
+        assert_eq!(message(payload).as_deref(), Some("negative value"));
+        assert_eq!(formula.eval(&[3.0]), 1.5);
+        let payload = panic::catch_unwind(AssertUnwindSafe(|| formula.eval_batch(&[&[1.0, -1.0, 2.0]]))).unwrap_err();
+        assert_eq!(message(payload).as_deref(), Some("negative value"));
+        assert_eq!(formula.eval_batch(&[&[1.0, 2.0]]), vec![0.5, 1.0]);
+    }
+
+    #[test]
+    fn host_outlives_compile() {
+        // the host is kept alive by the formula
+        let mut formula = {
+            let mut host = Host::new();
+            host.register("double", &[Type::Number], |args| 2.0 * args[0].as_number().unwrap());
+            JitExpr::compile("x; double(x) - -y", Inputs::new(&["x", "y"]), &host).unwrap()
+        };
+        assert_eq!(formula.eval(&[3.0, 1.0]), 7.0);
+        assert_eq!(Compiler::from_source("1 + x").unwrap_err().to_string(), "unknown variable `x`");
+    }
+
+    #[test]
+    fn eval_batch() {
+        let mut host = Host::new();
+        host.register("clamp", &[Type::Number, Type::Str, Type::Number], |args| {
+            args[0].as_number().unwrap().min(args[2].as_number().unwrap())
+        });
+        let inputs = Inputs::new(&["x", "y"]);
+        let xs: Vec<f64> = (0..1003).map(|row| row as f64 / 7.0 - 100.0).collect();
+        let ys: Vec<f64> = (0..1003).map(|row| (row % 13) as f64 - 6.0).collect();
+        for source in ["x * y - -(x / y)", "y; 2", "1; clamp(x * 2, \"max\", y + 1) - 1"] {
+            let mut formula = JitExpr::compile(source, inputs.clone(), &host).unwrap();
+            let results = formula.eval_batch(&[&xs, &ys]);
+            assert_eq!(results.len(), xs.len());
+            for (row, result) in results.into_iter().enumerate() {
+                let expected = formula.eval(&[xs[row], ys[row]]);
+                assert!(expected.to_bits() == result.to_bits() || expected.is_nan() && result.is_nan(), "{}", source);
+            }
+            assert_eq!(formula.eval_batch(&[&[], &[]]), vec![]);
+        }
+
+        // the loop over the rows is turned into vector instructions
+        let formula = JitExpr::compile("price * qty * (1 - discount)", Inputs::new(&["price", "qty", "discount"]), &host);
+        assert!(formula.unwrap().ir().contains(" x double>"));
+    }
+
+    #[test]
+    fn instructions() {
+        let inputs = Inputs::new(&["x", "y"]);
+        let ast = calculator_ast_parser::parser::parse("-(x + y) * (x - 2) / y; +x").unwrap();
+        let context = Context::create();
+        let (_module, _, function) = jit(&context, &ast, &Host::default(), &inputs, OptimizationLevel::None);
+        let ir = function.print_to_string().to_string();
+        for instruction in [
+            "%add = fadd double %x, %y",
+            "%neg = fneg double %add",
+            "%sub = fsub double %x, 2.0",
+            "%mul = fmul double %neg, %sub",
+            "%div = fdiv double %mul, %y",
+            "ret double %x",
+        ] {
+            assert!(ir.contains(instruction), "{} in {}", instruction, ir);
+        }
+    }
+
+    // a path in the temporary directory, unique to this test process
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("calc-test-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn write_object() {
+        let path = temp_path("formula.o");
+        Compiler::write_object("price * qty", &Inputs::new(&["price", "qty"]), &path).unwrap();
+        let object = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(!object.is_empty());
+        #[cfg(target_os = "linux")]
+        assert_eq!(&object[..4], b"\x7fELF");
+
+        // host functions cannot be compiled ahead of time
+        let error = Compiler::write_object("double(1)", &Inputs::default(), &path).unwrap_err();
+        assert_eq!(error.to_string(), "unknown function `double`");
+        let error = Compiler::write_object("x", &Inputs::default(), &path).unwrap_err();
+        assert_eq!(error.to_string(), "unknown variable `x`");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn write_executable() {
+        let path = temp_path("formula");
+        let inputs = Inputs::new(&["price", "qty", "discount"]);
+        Compiler::write_executable("price * qty * (1 - discount)", &inputs, &path).unwrap();
+        let run = |args: &[&str]| std::process::Command::new(&path).args(args).output().unwrap();
+
+        let output = run(&["2.5", "4", "0.5"]);
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "5\n");
+        assert_eq!(String::from_utf8(run(&["0.1", "3", "0"]).stdout).unwrap(), format!("{}\n", 0.1 * 3.0));
+        for args in [&["1", "2"][..], &["1", "2", "x"], &["1", "2", "3x"], &["1", "2", ""]] {
+            let output = run(args);
+            assert_eq!(output.status.code(), Some(2), "{:?}", args);
+            assert_eq!(String::from_utf8(output.stderr).unwrap(), "expected a number per input: price qty discount\n");
+        }
+
+        Compiler::write_executable("1; 2 / 4", &Inputs::default(), &path).unwrap();
+        assert_eq!(String::from_utf8(run(&[]).stdout).unwrap(), "0.5\n");
+        assert_eq!(String::from_utf8(run(&["1"]).stderr).unwrap(), "expected no argument\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn pathological() {
+        let max_depth = Limits::default().max_depth;
+        for source in testing::pathological_sources() {
+            assert!(JitExpr::compile(&source, Inputs::new(&["x"]), &Host::default()).is_err());
+        }
+
+        let error = Compiler::from_ast(vec![testing::deep(max_depth + 1)]).unwrap_err();
+        assert_eq!(
+            error.downcast::<calculator_ast_parser::LimitError>().unwrap(),
+            calculator_ast_parser::LimitError::TooDeep { max: max_depth }
+        );
+
+        // right at the limit
+        let (chain, expected) = testing::deepest_chain();
+        assert_eq!(Compiler::from_source(&chain).unwrap(), expected);
+    }
+}
@@ -1,6 +1,7 @@
-mod compiler;
-use calculator_ast_parser::Compile;
-use compiler::Compiler;
+use std::path::PathBuf;
+
+use calculator_ast_parser::{Compile, Inputs};
+use calculator_compiler::compiler::Compiler;
 
 use clap::Parser;
 
@@ -8,17 +9,38 @@ use clap::Parser;
 #[command(author, version)]
 #[command(about = "calculator - a simple CLI to calculate based on llvm")]
 struct Cli {
-    operation: String
+    operation: String,
+    /// Write the compiled formula to an object file instead of running it
+    #[arg(long, value_name = "PATH")]
+    object: Option<PathBuf>,
+    /// Write an executable printing the result of the formula instead of running it
+    #[arg(long, value_name = "PATH")]
+    executable: Option<PathBuf>,
+    /// Variable of the formula, the executable takes the value of every input as an argument
+    #[arg(long = "input", value_name = "NAME")]
+    inputs: Vec<String>,
 }
 
 // cargo run --package calculator-compiler --bin main
+// cargo run --package calculator-compiler --bin main -- --input x --executable double "x * 2"
 fn main() {
     let cli = Cli::parse();
 
+    if cli.object.is_some() || cli.executable.is_some() {
+        let inputs = Inputs::new(&cli.inputs);
+        if let Some(path) = &cli.object {
+            Compiler::write_object(&cli.operation, &inputs, path).unwrap_or_else(|e| panic!("compile error: {}", e));
+        }
+        if let Some(path) = &cli.executable {
+            Compiler::write_executable(&cli.operation, &inputs, path).unwrap_or_else(|e| panic!("compile error: {}", e));
+        }
+        return;
+    }
+
     let out = Compiler::from_source(&cli.operation).unwrap_or_else(
         |e| {
             panic!("parse error: {}", e)
         }
     );
     println!("result is {}", out)
-}
\ No newline at end of file
+}
@@ -1,6 +1,8 @@
-#![allow(clippy::only_used_in_recursion)]
+use std::sync::Arc;
 
-use calculator_ast_parser::{Compile, Node, Operator, Result, Sign};
+use calculator_ast_parser::{
+    expr, Arg, Compile, CompiledExpr, Fuel, Host, Inputs, LimitError, Limits, Node, Operator, Result, Sign,
+};
 
 // ANCHOR: interpreter
 pub struct Interpreter;
@@ -8,39 +10,131 @@ pub struct Interpreter;
 impl Compile for Interpreter {
     type Output = Result<f64>;
 
-    // f64 computation
+    // f64 computation, a script evaluates to its last expression
     fn from_ast(ast: Vec<Node>) -> Self::Output {
+        Interpreter::eval_with_limits(ast, &Limits::default())
+    }
+}
+
+impl Interpreter {
+    // same as Compile::from_ast, the evaluation aborts once it runs out of fuel
+    pub fn eval_with_limits(ast: Vec<Node>, limits: &Limits) -> Result<f64> {
+        Interpreter::eval_with_host(ast, limits, &Host::default())
+    }
+
+    // same as eval_with_limits, formulas may call the functions of host
+    pub fn eval_with_host(ast: Vec<Node>, limits: &Limits, host: &Host) -> Result<f64> {
+        limits.check_depth(&ast)?;
+        host.check(&ast)?;
+        // without inputs, any variable is unknown
+        let inputs = Inputs::default();
+        inputs.check(&ast)?;
         let mut ret = 0 as f64;
-        let evaluator = Eval::new();
+        let mut evaluator = Eval::new(limits, host, &inputs, &[]);
         for node in ast {
-            ret += evaluator.eval(&node);
+            ret = evaluator.eval(&node)?;
         }
         Ok(ret)
     }
 }
 // ANCHOR_END: interpreter
 
+// a formula walked by the interpreter, parsed once and evaluated with many inputs
+#[derive(Debug, Clone)]
+pub struct AstExpr {
+    // shared by every fork
+    ast: Arc<Vec<Node>>,
+    inputs: Inputs,
+    host: Host,
+    limits: Limits,
+}
+
+impl AstExpr {
+    pub fn compile(source: &str, inputs: Inputs, host: &Host) -> Result<Self> {
+        let ast = expr::prepare(source, &inputs, host)?;
+        let limits = Limits::default();
+        limits.check_fuel(&ast)?;
+        Ok(Self {
+            ast: Arc::new(ast),
+            inputs,
+            host: host.clone(),
+            limits,
+        })
+    }
+}
+
+impl CompiledExpr for AstExpr {
+    fn inputs(&self) -> &Inputs {
+        &self.inputs
+    }
+
+    // the ast is never changed, evaluating it needs no other state
+    fn fork(&self) -> Box<dyn CompiledExpr> {
+        Box::new(Self {
+            ast: self.ast.clone(),
+            inputs: self.inputs.clone(),
+            host: self.host.clone(),
+            limits: self.limits,
+        })
+    }
+
+    fn eval(&mut self, values: &[f64]) -> f64 {
+        assert_eq!(values.len(), self.inputs.len(), "wrong number of inputs");
+        let mut evaluator = Eval::new(&self.limits, &self.host, &self.inputs, values);
+        let mut ret = 0.0;
+        for node in self.ast.iter() {
+            // compile checked the depth and that the fuel covers every node
+            ret = evaluator.eval(node).expect("formula within limits");
+        }
+        ret
+    }
+}
+
 // ANCHOR: interpreter_recursive
-struct Eval;
+struct Eval<'a> {
+    host: &'a Host,
+    // values of the variables, in the order of inputs
+    inputs: &'a Inputs,
+    values: &'a [f64],
+    // one unit of fuel is spent per node
+    fuel: Fuel,
+    // eval recurses once per level of the ast
+    depth: usize,
+    max_depth: usize,
+}
 
-impl Eval {
-    pub fn new() -> Self {
-        Self
+impl<'a> Eval<'a> {
+    pub fn new(limits: &Limits, host: &'a Host, inputs: &'a Inputs, values: &'a [f64]) -> Self {
+        Self {
+            host,
+            inputs,
+            values,
+            fuel: Fuel::new(limits.fuel),
+            depth: 0,
+            max_depth: limits.max_depth,
+        }
     }
     // ANCHOR: interpreter_eval
-    pub fn eval(&self, node: &Node) -> f64 {
-        match node {
+    pub fn eval(&mut self, node: &Node) -> std::result::Result<f64, LimitError> {
+        self.fuel.consume()?;
+        if self.depth >= self.max_depth {
+            return Err(LimitError::TooDeep { max: self.max_depth });
+        }
+        self.depth += 1;
+        let ret = match node {
             Node::Number(n) => *n,
+            // variables were checked against the inputs before evaluating
+            Node::Var(name) => self.values[self.inputs.index(name).unwrap()],
             Node::UnaryExpr { op, child } => {
-                let child = self.eval(child);
+                let child = self.eval(child)?;
                 match op {
                     Sign::Positive => child,
                     Sign::Negative => -child,
                 }
             }
             Node::BinaryExpr { op, lhs, rhs } => {
-                let lhs_ret = self.eval(lhs);
-                let rhs_ret = self.eval(rhs);
+                let lhs_ret = self.eval(lhs)?;
+                let rhs_ret = self.eval(rhs)?;
 
                 match op {
                     Operator::Add => lhs_ret + rhs_ret,
@@ -49,7 +143,21 @@ impl Eval {
                     Operator::Div => lhs_ret / rhs_ret,
                 }
             }
-        }
+            // calls were checked against the host before evaluating
+            Node::Call { name, args } => {
+                let mut values = Vec::with_capacity(args.len());
+                for arg in args {
+                    values.push(match arg {
+                        Node::Str(value) => Arg::Str(value),
+                        arg => Arg::Number(self.eval(arg)?),
+                    });
+                }
+                self.host.get(name).unwrap().call(&values)
+            }
+            Node::Str(value) => unreachable!("string \"{}\" outside of a call", value),
+        };
+        self.depth -= 1;
+        Ok(ret)
     }
     // ANCHOR_END: interpreter_eval
 }
@@ -57,12 +165,14 @@ impl Eval {
 
 #[cfg(test)]
 mod tests {
+    use calculator_ast_parser::{parser, testing, Optimizer};
+
     use super::*;
 
     #[test]
     fn basics() {
         assert_eq!(Interpreter::from_source("1 + 2").unwrap().to_string(), "3");
-        // assert_eq!(Interpreter::source("(1 + 2)").unwrap() as i32, 3);
+        assert_eq!(Interpreter::from_source("(1 + 2)").unwrap().to_string(), "3");
         assert_eq!(Interpreter::from_source("2 + (2 - 1)").unwrap().to_string(), "3");
         assert_eq!(Interpreter::from_source("(2 + 3) - 1").unwrap().to_string(), "4");
         assert_eq!(
@@ -73,6 +183,9 @@ mod tests {
         // float number
         assert_eq!(Interpreter::from_source("0.5 + 0.3").unwrap().to_string(), "0.8");
         assert_eq!(Interpreter::from_source("0.5 + 0.3 + 1").unwrap().to_string(), "1.8");
+
+        // script
+        assert_eq!(Interpreter::from_source("1 + 2;\n# comment\n3 * 4;").unwrap().to_string(), "12");
     }
     #[test]
     fn multiply() {
@@ -85,4 +198,77 @@ mod tests {
         // 0.5 here will return error, strange though, but acceptable
         assert_eq!(Interpreter::from_source("5/(3+7*1)").unwrap().to_string(), "0.50");
     }
+
+    #[test]
+    fn optimized() {
+        for (source, expected) in testing::sign_matrix() {
+            let optimized = Interpreter::from_ast_optimized(parser::parse(&source).unwrap(), &Optimizer::default());
+            assert_eq!(optimized.unwrap(), expected, "{}", source);
+        }
+    }
+
+    #[test]
+    fn signs() {
+        for (source, expected) in testing::sign_matrix() {
+            assert_eq!(Interpreter::from_source(&source).unwrap(), expected, "{}", source);
+        }
+    }
+
+    #[test]
+    fn host_functions() {
+        let mut host = Host::new();
+        host.register("lookup_rate", &[calculator_ast_parser::Type::Str], |args| match args[0].as_str() {
+            Some("EUR") => 1.25,
+            _ => f64::NAN,
+        })
+        .register("max", &[calculator_ast_parser::Type::Number; 2], |args| {
+            args[0].as_number().unwrap().max(args[1].as_number().unwrap())
+        });
+
+        let eval = |source: &str| {
+            let ast = calculator_ast_parser::parser::parse(source).unwrap();
+            Interpreter::eval_with_host(ast, &Limits::default(), &host)
+        };
+        assert_eq!(eval("100 * lookup_rate(\"EUR\")").unwrap(), 125.0);
+        assert_eq!(eval("max(1, -max(2, 3)) + max(4 * 2, 7)").unwrap(), 9.0);
+        assert!(eval("lookup_rate(\"USD\")").unwrap().is_nan());
+
+        let error = eval("1; max(1)").unwrap_err();
+        assert_eq!(error.to_string(), "`max` takes 2 arguments, 1 given");
+        let error = Interpreter::from_source("lookup_rate(\"EUR\")").unwrap_err();
+        assert_eq!(error.to_string(), "unknown function `lookup_rate`");
+        let error = Interpreter::from_source("1 + x").unwrap_err();
+        assert_eq!(error.to_string(), "unknown variable `x`");
+    }
+
+    #[test]
+    fn fuel() {
+        let ast = calculator_ast_parser::parser::parse("1 + 2 * -3").unwrap();
+        // 6 nodes
+        let limits = Limits { fuel: 6, ..Limits::default() };
+        assert_eq!(Interpreter::eval_with_limits(ast.clone(), &limits).unwrap(), -5.0);
+
+        let limits = Limits { fuel: 5, ..Limits::default() };
+        let error = Interpreter::eval_with_limits(ast, &limits).unwrap_err();
+        assert_eq!(error.downcast::<LimitError>().unwrap(), LimitError::OutOfFuel { fuel: 5 });
+    }
+
+    // ast nested deeper than the limits allow
+    #[test]
+    fn pathological() {
+        let max_depth = Limits::default().max_depth;
+        for source in testing::pathological_sources() {
+            assert!(AstExpr::compile(&source, Inputs::new(&["x"]), &Host::default()).is_err());
+        }
+
+        let error = Interpreter::from_ast(vec![testing::deep(max_depth + 1)]).unwrap_err();
+        assert_eq!(error.downcast::<LimitError>().unwrap(), LimitError::TooDeep { max: max_depth });
+        assert_eq!(Interpreter::from_ast(vec![testing::deep(max_depth)]).unwrap(), 1.0);
+
+        // right at the limit, through the optimizer as well
+        let (chain, expected) = testing::deepest_chain();
+        assert_eq!(Interpreter::from_source(&chain).unwrap(), expected);
+        let optimized = Interpreter::from_ast_optimized(parser::parse(&chain).unwrap(), &Optimizer::default());
+        assert_eq!(optimized.unwrap(), expected);
+    }
 }
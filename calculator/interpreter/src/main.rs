@@ -1,6 +1,5 @@
-mod interpreter;
-use calculator_ast_parser::Compile;
-use interpreter::Interpreter;
+use calculator_ast_parser::{parser, Compile, Optimizer};
+use calculator_interpreter::interpreter::Interpreter;
 
 use clap::Parser;
 
@@ -8,13 +7,18 @@ use clap::Parser;
 #[command(author, version)]
 #[command(about = "calculator - a simple CLI to calculate")]
 struct Cli {
-    operation: String
+    operation: String,
+    /// Skip the ast optimizer, to debug the backend against the raw ast
+    #[arg(long)]
+    no_optimize: bool,
 }
 
 fn main() {
     let cli = Cli::parse();
 
-    let out = Interpreter::from_source(&cli.operation).unwrap_or_else(
+    let optimizer = if cli.no_optimize { Optimizer::disabled() } else { Optimizer::default() };
+    let ast = parser::parse(&cli.operation).unwrap_or_else(|e| panic!("parse error: {}", e));
+    let out = Interpreter::from_ast_optimized(ast, &optimizer).unwrap_or_else(
         |e| {
             panic!("parse error: {}", e)
         }
@@ -0,0 +1,32 @@
+use calculator_ast_parser::{CompiledExpr, Host, Inputs};
+use calculator_vm::expr::VmExpr;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+// cargo bench --package calculator-vm --bench batch
+fn batch(c: &mut Criterion) {
+    let inputs = Inputs::new(&["price", "qty", "discount"]);
+    let mut formula = VmExpr::compile("price * qty * (1 - discount)", inputs, &Host::default()).unwrap();
+
+    let mut group = c.benchmark_group("batch");
+    for rows in [1_000, 100_000] {
+        let prices: Vec<f64> = (0..rows).map(|row| (row % 100) as f64 + 0.5).collect();
+        let quantities: Vec<f64> = (0..rows).map(|row| (row % 7) as f64).collect();
+        let discounts: Vec<f64> = (0..rows).map(|row| (row % 4) as f64 / 10.0).collect();
+        group.throughput(Throughput::Elements(rows as u64));
+
+        group.bench_function(BenchmarkId::new("row by row", rows), |b| {
+            b.iter(|| {
+                (0..rows)
+                    .map(|row| formula.eval(&[prices[row], quantities[row], discounts[row]]))
+                    .collect::<Vec<_>>()
+            })
+        });
+        group.bench_function(BenchmarkId::new("columns", rows), |b| {
+            b.iter(|| formula.eval_batch(&[&prices, &quantities, &discounts]))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, batch);
+criterion_main!(benches);
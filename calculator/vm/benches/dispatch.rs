@@ -0,0 +1,46 @@
+use calculator_ast_parser::{parser, Compile};
+use calculator_vm::{bytecode::Interpreter, decoded::DecodedVM, vm::VM};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+
+// 1 + 2 * 3 - 4 / 5 + ..., a flat chain mixing every operator
+fn generate(terms: usize) -> String {
+    let operators = ["+", "*", "-", "/"];
+    let mut source = "1".to_string();
+    for i in 1..terms {
+        source = format!("{} {} {}", source, operators[i % 4], i % 7 + 1);
+    }
+    source
+}
+
+// evaluates the same program over and over, as when scoring many rows
+// cargo bench --package calculator-vm --bench dispatch
+fn dispatch(c: &mut Criterion) {
+    let mut group = c.benchmark_group("dispatch");
+    for terms in [100, 1000] {
+        let source = generate(terms);
+        let bytecode = Interpreter::from_ast(parser::parse(&source).unwrap());
+
+        group.bench_with_input(BenchmarkId::new("byte loop", terms), &bytecode, |b, bytecode| {
+            b.iter_batched(
+                || VM::new(bytecode.clone()),
+                |mut vm| {
+                    vm.run().unwrap();
+                    vm.get_result()
+                },
+                BatchSize::SmallInput,
+            )
+        });
+
+        let mut decoded = DecodedVM::new(&bytecode);
+        group.bench_function(BenchmarkId::new("pre-decoded", terms), |b| {
+            b.iter(|| {
+                decoded.run();
+                decoded.get_result()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, dispatch);
+criterion_main!(benches);
@@ -0,0 +1,65 @@
+use calculator_ast_parser::Compile;
+use calculator_vm::{
+    bytecode::Interpreter,
+    register::{Compiler, RegisterVM},
+    vm::VM,
+};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+
+// a balanced tree of every operator, with signs sprinkled in
+fn generate(depth: usize, seed: usize) -> String {
+    if depth == 0 {
+        return format!("{}", seed % 9 + 1);
+    }
+    let operators = ["+", "-", "*", "/"];
+    let sign = ["-", "", "", "", ""][seed % 5];
+    format!(
+        "{}({} {} {})",
+        sign,
+        generate(depth - 1, seed * 2 + 1),
+        operators[seed % 4],
+        generate(depth - 1, seed * 2 + 2)
+    )
+}
+
+// cargo bench --package calculator-vm --bench engines
+fn engines(c: &mut Criterion) {
+    let mut group = c.benchmark_group("engines");
+    for depth in [6, 9] {
+        let source = generate(depth, 0);
+        let bytecode = Interpreter::from_source(&source);
+        let program = Compiler::from_source(&source);
+        println!(
+            "depth {}: stack vm runs {} instructions, register vm runs {} instructions with {} registers",
+            depth,
+            bytecode.decode().len(),
+            program.instructions.len(),
+            program.registers
+        );
+
+        group.bench_with_input(BenchmarkId::new("stack", depth), &bytecode, |b, bytecode| {
+            b.iter_batched(
+                || VM::new(bytecode.clone()),
+                |mut vm| {
+                    vm.run().unwrap();
+                    vm.get_result()
+                },
+                BatchSize::SmallInput,
+            )
+        });
+        group.bench_with_input(BenchmarkId::new("register", depth), &program, |b, program| {
+            b.iter_batched(
+                || RegisterVM::new(program.clone()),
+                |mut vm| {
+                    vm.run();
+                    vm.get_result()
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, engines);
+criterion_main!(benches);
@@ -0,0 +1,29 @@
+use calculator_ast_parser::{expr, Host, Inputs};
+use calculator_vm::expr::VmExpr;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+// cargo bench --package calculator-vm --bench parallel
+// the same batch on 1, 2, 4... threads, up to the number of cores
+fn parallel(c: &mut Criterion) {
+    let inputs = Inputs::new(&["price", "qty", "discount"]);
+    let formula = VmExpr::compile("price * qty * (1 - discount)", inputs, &Host::default()).unwrap();
+    let rows = 4_000_000;
+    let prices: Vec<f64> = (0..rows).map(|row| (row % 100) as f64 + 0.5).collect();
+    let quantities: Vec<f64> = (0..rows).map(|row| (row % 7) as f64).collect();
+    let discounts: Vec<f64> = (0..rows).map(|row| (row % 4) as f64 / 10.0).collect();
+    let cores = std::thread::available_parallelism().map_or(1, |cores| cores.get());
+
+    let mut group = c.benchmark_group("parallel");
+    group.throughput(Throughput::Elements(rows as u64));
+    let mut threads = 1;
+    while threads <= cores {
+        group.bench_function(BenchmarkId::new("threads", threads), |b| {
+            b.iter(|| expr::eval_batch_parallel(&formula, &[&prices, &quantities, &discounts], threads))
+        });
+        threads *= 2;
+    }
+    group.finish();
+}
+
+criterion_group!(benches, parallel);
+criterion_main!(benches);
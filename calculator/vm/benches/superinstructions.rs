@@ -0,0 +1,41 @@
+use calculator_ast_parser::{parser, Compile};
+use calculator_vm::{bytecode::Interpreter, vm::VM};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+
+// ((((1 + 2) * 3) - 4) / 5) + 6 ..., every operation has a literal
+// right hand side, the pattern superinstructions are made for
+fn generate(terms: usize) -> String {
+    let operators = ["+", "*", "-", "/"];
+    let mut source = "1".to_string();
+    for i in 1..terms {
+        source = format!("({} {} {})", source, operators[i % 4], i % 7 + 1);
+    }
+    source
+}
+
+// cargo bench --package calculator-vm --bench superinstructions
+fn superinstructions(c: &mut Criterion) {
+    let mut group = c.benchmark_group("superinstructions");
+    for terms in [100, 400] {
+        let source = generate(terms);
+        let plain = Interpreter::from_ast(parser::parse(&source).unwrap());
+        let fused = Interpreter::from_ast_with_superinstructions(parser::parse(&source).unwrap());
+
+        for (name, bytecode) in [("plain", plain), ("fused", fused)] {
+            group.bench_with_input(BenchmarkId::new(name, terms), &bytecode, |b, bytecode| {
+                b.iter_batched(
+                    || VM::new(bytecode.clone()),
+                    |mut vm| {
+                        vm.run().unwrap();
+                        vm.get_result()
+                    },
+                    BatchSize::SmallInput,
+                )
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, superinstructions);
+criterion_main!(benches);
@@ -1,20 +1,57 @@
 use std::convert::TryInto;
 
-use calculator_ast_parser::{Compile, Node, Sign, Operator};
+use calculator_ast_parser::{
+    visit::{walk_binary, walk_unary},
+    Compile, Host, HostError, HostFunction, Inputs, LimitError, Limits, Node, Operator, Sign, Type, Visitor,
+};
 
 use crate::opcode::OpCode;
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 // ANCHOR: bytecode
 pub struct Bytecode {
     pub instructions: Vec<u8>,
+    // host function calls, indexed by OpCall
+    pub calls: Vec<Call>,
+}
+
+// a call site: the host function to call and how its arguments are passed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Call {
+    pub name: String,
+    pub args: Vec<CallArg>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CallArg {
+    // taken from the stack, pushed in the order of the arguments
+    Number,
+    // a string literal is known when compiling, it never goes through the stack
+    Str(String),
+}
+
+impl Call {
+    // the function of the host this call reaches, if it takes the arguments
+    // the call passes; bytecode may be run with another host than the one
+    // it was compiled with, or with none at all
+    pub fn resolve<'a>(&self, host: &'a Host) -> Result<&'a HostFunction, HostError> {
+        let function = host.get(&self.name).ok_or_else(|| HostError::UnknownFunction(self.name.clone()))?;
+        let types: Vec<Type> = self
+            .args
+            .iter()
+            .map(|arg| match arg {
+                CallArg::Number => Type::Number,
+                CallArg::Str(_) => Type::Str,
+            })
+            .collect();
+        function.check_signature(&self.name, &types)?;
+        Ok(function)
+    }
 }
 
 impl Bytecode {
     fn new() -> Self {
-        Self {
-            instructions: Vec::new()
-        }
+        Self::default()
     }
 
     // based on position 0 or 1, we will get the constant value
@@ -28,18 +65,165 @@ impl Bytecode {
         let bytes: [u8;8] = self.instructions[first..end].try_into().unwrap();
         (f64::from_be_bytes(bytes), end)
     }
+
+    // the u32 operand of OpCall or OpLoad at position, with the offset following it
+    pub fn bytes_to_index(&self, position: usize) -> (u32, usize) {
+        let end = position + 4;
+        let bytes: [u8; 4] = self.instructions[position..end].try_into().unwrap();
+        (u32::from_be_bytes(bytes), end)
+    }
+
+    // splits the raw instructions back into op codes
+    pub fn decode(&self) -> Vec<OpCode> {
+        self.disassemble().into_iter().map(|(_, opcode)| opcode).collect()
+    }
+
+    // op codes along with the offset they start at
+    pub fn disassemble(&self) -> Vec<(usize, OpCode)> {
+        let mut opcodes = vec![];
+        let mut ip = 0;
+        while ip < self.instructions.len() {
+            let (opcode, next) = self.decode_at(ip);
+            opcodes.push((ip, opcode));
+            ip = next;
+        }
+        opcodes
+    }
+
+    // values an instruction pops, then pushes; None for a call
+    // missing from the calls table
+    pub fn stack_effect(&self, opcode: OpCode) -> Option<(usize, usize)> {
+        Some(match opcode {
+            OpCode::OpConstant(_) | OpCode::OpLoad(_) => (0, 1),
+            OpCode::OpPop => (1, 0),
+            OpCode::OpAdd | OpCode::OpSub | OpCode::OpMul | OpCode::OpDiv => (2, 1),
+            OpCode::OpPlus | OpCode::OpMinus => (1, 1),
+            OpCode::OpAddConst(_) | OpCode::OpSubConst(_) | OpCode::OpMulConst(_) | OpCode::OpDivConst(_) => (1, 1),
+            OpCode::OpCall(index) => {
+                let call = self.calls.get(index as usize)?;
+                (call.args.iter().filter(|arg| **arg == CallArg::Number).count(), 1)
+            }
+        })
+    }
+
+    // height of the stack once the instructions before offset end have run,
+    // with the highest it got on the way; None if an instruction pops
+    // more values than there are or calls outside the calls table
+    pub fn stack_height(&self, end: usize) -> Option<(usize, usize)> {
+        let (mut height, mut highest) = (0usize, 0);
+        for (_, opcode) in self.disassemble().into_iter().take_while(|(ip, _)| *ip < end) {
+            let (pops, pushes) = self.stack_effect(opcode)?;
+            height = height.checked_sub(pops)? + pushes;
+            highest = highest.max(height);
+        }
+        Some((height, highest))
+    }
+
+    // a vm with limits runs the whole program without a LimitError:
+    // bytecode never loops, so every instruction runs exactly once
+    pub fn check_limits(&self, limits: &Limits) -> Result<(), LimitError> {
+        let instructions = self.disassemble().len() as u64;
+        if instructions > limits.fuel {
+            return Err(LimitError::OutOfFuel { fuel: limits.fuel });
+        }
+        let max = limits.max_stack_depth.min(crate::vm::STACK_SIZE);
+        match self.stack_height(self.instructions.len()) {
+            Some((_, highest)) if highest > max => Err(LimitError::StackOverflow { max }),
+            _ => Ok(()),
+        }
+    }
+
+    // decodes the instruction starting at offset ip,
+    // returns it with the offset of the next one
+    pub fn decode_at(&self, ip: usize) -> (OpCode, usize) {
+        let opcode = self.instructions[ip];
+        if OpCode::has_index(opcode) {
+            let (index, next) = self.bytes_to_index(ip + 1);
+            (OpCode::with_index(opcode, index), next)
+        } else if OpCode::has_constant(opcode) {
+            let (val, next) = self.bytes_to_constants(ip + 1);
+            (OpCode::with_constant(opcode, val), next)
+        } else {
+            (OpCode::from(opcode), ip + 1)
+        }
+    }
+}
+
+impl std::iter::FromIterator<OpCode> for Bytecode {
+    // the calls table is left empty, OpCall needs one filled in separately
+    fn from_iter<T: IntoIterator<Item = OpCode>>(opcodes: T) -> Self {
+        Self {
+            instructions: opcodes.into_iter().flat_map(|opcode| opcode.bytes()).collect(),
+            calls: vec![],
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct Interpreter {
-    bytecode: Bytecode
+    bytecode: Bytecode,
+    superinstructions: bool,
+    // variables are loaded by their index in inputs
+    inputs: Inputs,
 }
 
 impl Interpreter {
     fn new() -> Self {
         Self{
-            bytecode: Bytecode::new()
+            bytecode: Bytecode::new(),
+            superinstructions: false,
+            inputs: Inputs::default(),
+        }
+    }
+
+    // same as Compile::from_ast, but a binary expression whose right hand side
+    // is a literal is emitted as one superinstruction: `x * 2` becomes
+    // OpMulConst(2) instead of OpConstant(2); OpMul
+    pub fn from_ast_with_superinstructions(ast: Vec<Node>) -> Bytecode {
+        let mut intepreter = Interpreter::new();
+        intepreter.superinstructions = true;
+        intepreter.compile(ast)
+    }
+
+    // same as Compile::from_ast, but an ast deeper than the limits allow is
+    // rejected upfront instead of overflowing the native stack while visiting it
+    pub fn try_from_ast(ast: Vec<Node>, limits: &Limits) -> Result<Bytecode, LimitError> {
+        limits.check_depth(&ast)?;
+        Ok(Interpreter::new().compile(ast))
+    }
+
+    // same as try_from_ast, every call is checked against the functions of host,
+    // which the vm running the bytecode must be given with VM::set_host
+    pub fn try_from_ast_with_host(ast: Vec<Node>, limits: &Limits, host: &Host) -> calculator_ast_parser::Result<Bytecode> {
+        Interpreter::try_from_ast_with_inputs(ast, limits, host, &Inputs::default())
+    }
+
+    // same as try_from_ast_with_host, a variable becomes OpLoad of its index in inputs,
+    // the vm running the bytecode must be given their values with VM::set_inputs
+    pub fn try_from_ast_with_inputs(
+        ast: Vec<Node>,
+        limits: &Limits,
+        host: &Host,
+        inputs: &Inputs,
+    ) -> calculator_ast_parser::Result<Bytecode> {
+        limits.check_depth(&ast)?;
+        host.check(&ast)?;
+        inputs.check(&ast)?;
+        let mut interpreter = Interpreter::new();
+        interpreter.inputs = inputs.clone();
+        Ok(interpreter.compile(ast))
+    }
+
+    fn compile(mut self, ast: Vec<Node>) -> Bytecode {
+        // travserse ast tree
+        for n in ast {
+            self.visit_node(&n);
+
+            // add end of instruction
+            self.add_instructions(OpCode::OpPop);
         }
+
+        self.bytecode
     }
 
     // adding instructions
@@ -47,28 +231,72 @@ impl Interpreter {
         self.bytecode.instructions.extend(opcode.bytes())
     }
 
-    fn eval_node(&mut self, n: Node) {
-        match n {
-            Node::Number(dec) => self.add_instructions(OpCode::OpConstant(dec)),
-            Node::UnaryExpr { op, child } => {
-                self.eval_node(*child);
+}
 
-                match op {
-                    Sign::Positive => self.add_instructions(OpCode::OpPlus),
-                    Sign::Negative => self.add_instructions(OpCode::OpMinus)
-                }
+// operands are pushed before the operator that consumes them
+impl Visitor for Interpreter {
+    fn visit_number(&mut self, dec: f64) {
+        self.add_instructions(OpCode::OpConstant(dec))
+    }
+
+    fn visit_unary(&mut self, op: Sign, child: &Node) {
+        walk_unary(self, op, child);
+
+        match op {
+            Sign::Positive => self.add_instructions(OpCode::OpPlus),
+            Sign::Negative => self.add_instructions(OpCode::OpMinus)
+        }
+    }
+
+    fn visit_binary(&mut self, op: Operator, lhs: &Node, rhs: &Node) {
+        if let (true, Node::Number(dec)) = (self.superinstructions, rhs) {
+            self.visit_node(lhs);
+
+            match op {
+                Operator::Add => self.add_instructions(OpCode::OpAddConst(*dec)),
+                Operator::Sub => self.add_instructions(OpCode::OpSubConst(*dec)),
+                Operator::Mul => self.add_instructions(OpCode::OpMulConst(*dec)),
+                Operator::Div => self.add_instructions(OpCode::OpDivConst(*dec))
             }
-            Node::BinaryExpr { op, lhs, rhs } => {
-                self.eval_node(*lhs);
-                self.eval_node(*rhs);
-
-                match op {
-                    Operator::Add => self.add_instructions(OpCode::OpAdd),
-                    Operator::Sub => self.add_instructions(OpCode::OpSub),
-                    Operator::Mul => self.add_instructions(OpCode::OpMul),
-                    Operator::Div => self.add_instructions(OpCode::OpDiv)
+            return;
+        }
+
+        walk_binary(self, op, lhs, rhs);
+
+        match op {
+            Operator::Add => self.add_instructions(OpCode::OpAdd),
+            Operator::Sub => self.add_instructions(OpCode::OpSub),
+            Operator::Mul => self.add_instructions(OpCode::OpMul),
+            Operator::Div => self.add_instructions(OpCode::OpDiv)
+        }
+    }
+
+    fn visit_call(&mut self, name: &str, args: &[Node]) {
+        let args = args
+            .iter()
+            .map(|arg| match arg {
+                Node::Str(value) => CallArg::Str(value.clone()),
+                arg => {
+                    self.visit_node(arg);
+                    CallArg::Number
                 }
-            }
+            })
+            .collect();
+        self.bytecode.calls.push(Call {
+            name: name.to_string(),
+            args,
+        });
+        self.add_instructions(OpCode::OpCall(self.bytecode.calls.len() as u32 - 1))
+    }
+
+    fn visit_str(&mut self, value: &str) {
+        panic!("string \"{}\" outside of a call", value)
+    }
+
+    fn visit_var(&mut self, name: &str) {
+        match self.inputs.index(name) {
+            Some(index) => self.add_instructions(OpCode::OpLoad(index as u32)),
+            None => panic!("unknown variable `{}`", name),
         }
     }
 }
@@ -77,22 +305,14 @@ impl Compile for Interpreter {
     type Output = Bytecode;
 
     fn from_ast(ast: Vec<calculator_ast_parser::Node>) -> Self::Output {
-        let mut intepreter = Interpreter::new();
-
-        // travserse ast tree
-        for n in ast {
-            intepreter.eval_node(n);
-
-            // add end of instruction
-            intepreter.add_instructions(OpCode::OpPop);
-        }
-
-        intepreter.bytecode.to_owned()
+        Interpreter::new().compile(ast)
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use calculator_ast_parser::Optimizer;
+
     use super::*;
 
     #[test]
@@ -101,6 +321,104 @@ mod tests {
         infix_template("-", OpCode::OpSub);
     }
 
+    #[test]
+    fn stack_height() {
+        // 1 | 1 2 | 1 2 3 | 1 6 | 7 | pop
+        let bytecode = Interpreter::from_ast(calculator_ast_parser::parser::parse("1 + 2 * 3").unwrap());
+        let end = bytecode.instructions.len();
+        assert_eq!(bytecode.stack_height(end), Some((0, 3)));
+        assert_eq!(bytecode.stack_height(end - 1), Some((1, 3)));
+        assert_eq!(bytecode.stack_height(0), Some((0, 0)));
+        assert_eq!(bytecode.check_limits(&Limits::default()), Ok(()));
+        let limits = Limits { max_stack_depth: 2, ..Limits::default() };
+        assert_eq!(bytecode.check_limits(&limits), Err(LimitError::StackOverflow { max: 2 }));
+        let limits = Limits { fuel: 5, ..Limits::default() };
+        assert_eq!(bytecode.check_limits(&limits), Err(LimitError::OutOfFuel { fuel: 5 }));
+
+        let underflow: Bytecode = vec![OpCode::OpConstant(1.0), OpCode::OpAdd].into_iter().collect();
+        assert_eq!(underflow.stack_height(underflow.instructions.len()), None);
+        let missing_call: Bytecode = vec![OpCode::OpCall(0)].into_iter().collect();
+        assert_eq!(missing_call.stack_height(missing_call.instructions.len()), None);
+    }
+
+    #[test]
+    fn optimized() {
+        let source = "-(1 + 2) * +3 / 1";
+        let ast = calculator_ast_parser::parser::parse(source).unwrap();
+        let bytecode = Interpreter::from_ast_optimized(ast.clone(), &Optimizer::default());
+        assert_eq!(
+            Bytecode {
+                instructions: vec![OpCode::OpConstant(-9.0), OpCode::OpPop]
+                    .into_iter()
+                    .flat_map(|a| a.bytes())
+                    .collect(),
+                calls: vec![],
+            },
+            bytecode
+        );
+        assert!(Interpreter::from_ast_optimized(ast, &Optimizer::disabled()) == Interpreter::from_source(source));
+    }
+
+    #[test]
+    fn superinstructions() {
+        let ast = calculator_ast_parser::parser::parse("(1 + 2) * 3 - -4 / 5").unwrap();
+        let bytecode = Interpreter::from_ast_with_superinstructions(ast);
+        assert_eq!(
+            bytecode.decode(),
+            vec![
+                OpCode::OpConstant(1.0),
+                OpCode::OpAddConst(2.0),
+                OpCode::OpMulConst(3.0),
+                OpCode::OpConstant(4.0),
+                OpCode::OpMinus,
+                OpCode::OpDivConst(5.0),
+                OpCode::OpSub,
+                OpCode::OpPop,
+            ]
+        );
+    }
+
+    #[test]
+    fn calls() {
+        let bytecode = Interpreter::from_source("f(1, \"a\", g()) - 2");
+        assert_eq!(
+            bytecode.decode(),
+            vec![
+                OpCode::OpConstant(1.0),
+                OpCode::OpCall(0),
+                OpCode::OpCall(1),
+                OpCode::OpConstant(2.0),
+                OpCode::OpSub,
+                OpCode::OpPop,
+            ]
+        );
+        assert_eq!(
+            bytecode.calls,
+            vec![
+                Call { name: "g".to_string(), args: vec![] },
+                Call {
+                    name: "f".to_string(),
+                    args: vec![CallArg::Number, CallArg::Str("a".to_string()), CallArg::Number]
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn variables() {
+        let ast = calculator_ast_parser::parser::parse("qty * price - qty").unwrap();
+        let inputs = Inputs::new(&["price", "qty"]);
+        let bytecode = Interpreter::try_from_ast_with_inputs(ast, &Limits::default(), &Host::default(), &inputs);
+        assert_eq!(
+            bytecode.unwrap().decode(),
+            vec![OpCode::OpLoad(1), OpCode::OpLoad(0), OpCode::OpMul, OpCode::OpLoad(1), OpCode::OpSub, OpCode::OpPop]
+        );
+
+        let ast = calculator_ast_parser::parser::parse("price * tax").unwrap();
+        let error = Interpreter::try_from_ast_with_inputs(ast, &Limits::default(), &Host::default(), &inputs);
+        assert_eq!(error.unwrap_err().to_string(), "unknown variable `tax`");
+    }
+
     fn infix_template(infix_str: &str, op_code: OpCode) {
         let input = format!("1 {} 2;", infix_str);
         let bytecode = Interpreter::from_source(&input);
@@ -118,6 +436,7 @@ mod tests {
         assert_eq!(
             Bytecode {
                 instructions: expected_instructions,
+                calls: vec![],
             },
             bytecode
         );
@@ -0,0 +1,186 @@
+use calculator_ast_parser::{Arg, Host, HostError, HostFunction};
+
+use crate::{
+    bytecode::{Bytecode, Call, CallArg},
+    opcode::OpCode,
+};
+
+// rows evaluated by every instruction, small enough for the columns of
+// a whole stack to stay in cache
+pub const CHUNK: usize = 1024;
+
+// ColumnVM runs the bytecode over many rows of inputs at once: every slot
+// of the stack is a column holding one value per row, and every instruction
+// is applied to a whole chunk of rows before moving on to the next one.
+// Dispatch is paid once per chunk instead of once per row, and the loop over
+// a chunk is simple enough for the compiler to vectorize.
+#[derive(Clone)]
+pub struct ColumnVM {
+    program: Vec<OpCode>,
+    calls: Vec<Call>,
+    // the host function of every call, by index
+    functions: Vec<HostFunction>,
+    stack: Vec<Vec<f64>>,
+    // columns popped off the stack, reused to avoid allocating
+    free: Vec<Vec<f64>>,
+}
+
+impl ColumnVM {
+    // fails if a call of the bytecode is missing from the host or does not
+    // match its signature, run cannot fail
+    pub fn new(bytecode: &Bytecode, host: &Host) -> Result<Self, HostError> {
+        let functions = bytecode.calls.iter().map(|call| call.resolve(host).cloned()).collect::<Result<_, _>>()?;
+        Ok(Self {
+            program: bytecode.decode(),
+            calls: bytecode.calls.clone(),
+            functions,
+            stack: vec![],
+            free: vec![],
+        })
+    }
+
+    // columns holds the values of every input, indexed like OpLoad,
+    // returns the value of the last statement for every row
+    pub fn run(&mut self, columns: &[&[f64]], rows: usize) -> Vec<f64> {
+        let mut results = Vec::with_capacity(rows);
+        for start in (0..rows).step_by(CHUNK) {
+            let end = rows.min(start + CHUNK);
+            let last = self.run_chunk(columns, start..end);
+            results.extend_from_slice(&last);
+            self.free.push(last);
+        }
+        results
+    }
+
+    fn run_chunk(&mut self, columns: &[&[f64]], rows: std::ops::Range<usize>) -> Vec<f64> {
+        let len = rows.len();
+        let mut last = vec![0.0; len];
+        for opcode in &self.program {
+            match *opcode {
+                OpCode::OpConstant(val) => {
+                    let mut column = take(&mut self.free, len);
+                    column.iter_mut().for_each(|slot| *slot = val);
+                    self.stack.push(column);
+                }
+                OpCode::OpLoad(index) => {
+                    let mut column = take(&mut self.free, len);
+                    column.copy_from_slice(&columns[index as usize][rows.clone()]);
+                    self.stack.push(column);
+                }
+                OpCode::OpPop => {
+                    let column = self.stack.pop().unwrap();
+                    self.free.push(std::mem::replace(&mut last, column));
+                }
+                OpCode::OpAdd => binary(&mut self.stack, &mut self.free, |lhs, rhs| lhs + rhs),
+                OpCode::OpSub => binary(&mut self.stack, &mut self.free, |lhs, rhs| lhs - rhs),
+                OpCode::OpMul => binary(&mut self.stack, &mut self.free, |lhs, rhs| lhs * rhs),
+                OpCode::OpDiv => binary(&mut self.stack, &mut self.free, |lhs, rhs| lhs / rhs),
+                OpCode::OpPlus => {}
+                OpCode::OpMinus => self.stack.last_mut().unwrap().iter_mut().for_each(|val| *val = -*val),
+                OpCode::OpAddConst(rhs) => self.stack.last_mut().unwrap().iter_mut().for_each(|val| *val += rhs),
+                OpCode::OpSubConst(rhs) => self.stack.last_mut().unwrap().iter_mut().for_each(|val| *val -= rhs),
+                OpCode::OpMulConst(rhs) => self.stack.last_mut().unwrap().iter_mut().for_each(|val| *val *= rhs),
+                OpCode::OpDivConst(rhs) => self.stack.last_mut().unwrap().iter_mut().for_each(|val| *val /= rhs),
+                OpCode::OpCall(index) => {
+                    let (call, function) = (&self.calls[index as usize], &self.functions[index as usize]);
+                    let column = call_host(call, function, &mut self.stack, &mut self.free, len);
+                    self.stack.push(column);
+                }
+            }
+        }
+        last
+    }
+}
+
+// host functions are called row by row, with the number arguments
+// taken from the top columns of the stack
+fn call_host(call: &Call, function: &HostFunction, stack: &mut Vec<Vec<f64>>, free: &mut Vec<Vec<f64>>, len: usize) -> Vec<f64> {
+    let numbers = call.args.iter().filter(|arg| **arg == CallArg::Number).count();
+    let operands = stack.split_off(stack.len() - numbers);
+
+    let mut column = take(free, len);
+    let mut args = Vec::with_capacity(call.args.len());
+    for (row, result) in column.iter_mut().enumerate() {
+        let mut operands = operands.iter();
+        args.clear();
+        args.extend(call.args.iter().map(|arg| match arg {
+            CallArg::Number => Arg::Number(operands.next().unwrap()[row]),
+            CallArg::Str(value) => Arg::Str(value),
+        }));
+        *result = function.call(&args);
+    }
+    free.extend(operands);
+    column
+}
+
+// a column of len values, recycled if possible; its content is garbage
+fn take(free: &mut Vec<Vec<f64>>, len: usize) -> Vec<f64> {
+    let mut column = free.pop().unwrap_or_default();
+    column.resize(len, 0.0);
+    column
+}
+
+// applies op to the two top columns, the result replaces the lhs column
+fn binary(stack: &mut Vec<Vec<f64>>, free: &mut Vec<Vec<f64>>, op: impl Fn(f64, f64) -> f64) {
+    let rhs = stack.pop().unwrap();
+    let lhs = stack.last_mut().unwrap();
+    for (lhs, rhs) in lhs.iter_mut().zip(&rhs) {
+        *lhs = op(*lhs, *rhs);
+    }
+    free.push(rhs);
+}
+
+#[cfg(test)]
+mod tests {
+    use calculator_ast_parser::{parser, Inputs, Limits, Type};
+
+    use super::*;
+    use crate::{bytecode::Interpreter, vm::VM};
+
+    #[test]
+    fn same_results_as_vm() {
+        let mut host = Host::new();
+        host.register("clamp", &[Type::Number, Type::Str, Type::Number], |args| {
+            args[0].as_number().unwrap().min(args[2].as_number().unwrap())
+        });
+        let inputs = Inputs::new(&["x", "y"]);
+        let rows = CHUNK * 2 + 3;
+        let xs: Vec<f64> = (0..rows).map(|row| row as f64 / 7.0 - 100.0).collect();
+        let ys: Vec<f64> = (0..rows).map(|row| (row % 13) as f64 - 6.0).collect();
+
+        let sources = [
+            "x",
+            "2",
+            "x * y - -(x / y)",
+            "-(x + 2) * +3 / y",
+            "1; clamp(x * 2, \"max\", y + 1) - 1",
+        ];
+        let mut programs: Vec<(&str, Bytecode)> = sources
+            .iter()
+            .map(|source| {
+                let ast = parser::parse(source).unwrap();
+                (*source, Interpreter::try_from_ast_with_inputs(ast, &Limits::default(), &host, &inputs).unwrap())
+            })
+            .collect();
+        let fused = "(1 + 2) * 3 - -4 / 5";
+        programs.push((fused, Interpreter::from_ast_with_superinstructions(parser::parse(fused).unwrap())));
+
+        for (source, bytecode) in programs {
+            let results = ColumnVM::new(&bytecode, &host).unwrap().run(&[&xs, &ys], rows);
+            assert_eq!(results.len(), rows);
+            let mut vm = VM::new(bytecode);
+            vm.set_host(host.clone());
+            for (row, result) in results.into_iter().enumerate() {
+                vm.set_inputs(&[xs[row], ys[row]]);
+                vm.run().unwrap();
+                let expected = vm.get_result();
+                assert!(
+                    expected.to_bits() == result.to_bits() || expected.is_nan() && result.is_nan(),
+                    "{} at row {}",
+                    source,
+                    row
+                );
+            }
+        }
+    }
+}
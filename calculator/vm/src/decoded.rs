@@ -0,0 +1,111 @@
+use crate::{bytecode::Bytecode, opcode::OpCode};
+
+// DecodedVM translates the bytecode once into op codes with their
+// constants already unpacked from the big-endian bytes, so evaluating
+// the same program again and again skips decoding entirely.
+// The stack keeps its capacity between runs.
+pub struct DecodedVM {
+    program: Vec<OpCode>,
+    stack: Vec<f64>,
+    // most recently popped value
+    last: f64,
+}
+
+impl DecodedVM {
+    pub fn new(bytecode: &Bytecode) -> Self {
+        Self {
+            program: bytecode.decode(),
+            stack: Vec::with_capacity(crate::vm::STACK_SIZE),
+            last: 0.0,
+        }
+    }
+
+    pub fn run(&mut self) {
+        let stack = &mut self.stack;
+        stack.clear();
+
+        for opcode in &self.program {
+            match *opcode {
+                OpCode::OpConstant(val) => stack.push(val),
+                OpCode::OpPop => self.last = stack.pop().unwrap(),
+                OpCode::OpAdd => {
+                    let rhs = stack.pop().unwrap();
+                    let lhs = stack.last_mut().unwrap();
+                    *lhs += rhs;
+                }
+                OpCode::OpSub => {
+                    let rhs = stack.pop().unwrap();
+                    let lhs = stack.last_mut().unwrap();
+                    *lhs -= rhs;
+                }
+                OpCode::OpMul => {
+                    let rhs = stack.pop().unwrap();
+                    let lhs = stack.last_mut().unwrap();
+                    *lhs *= rhs;
+                }
+                OpCode::OpDiv => {
+                    let rhs = stack.pop().unwrap();
+                    let lhs = stack.last_mut().unwrap();
+                    *lhs /= rhs;
+                }
+                OpCode::OpPlus => {}
+                OpCode::OpMinus => {
+                    let child = stack.last_mut().unwrap();
+                    *child = -*child;
+                }
+                OpCode::OpAddConst(rhs) => *stack.last_mut().unwrap() += rhs,
+                OpCode::OpSubConst(rhs) => *stack.last_mut().unwrap() -= rhs,
+                OpCode::OpMulConst(rhs) => *stack.last_mut().unwrap() *= rhs,
+                OpCode::OpDivConst(rhs) => *stack.last_mut().unwrap() /= rhs,
+                OpCode::OpCall(_) => panic!("host functions are not supported by DecodedVM"),
+                OpCode::OpLoad(_) => panic!("variables are not supported by DecodedVM"),
+            }
+        }
+    }
+
+    pub fn get_result(&self) -> f64 {
+        self.last
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use calculator_ast_parser::{parser, Compile};
+
+    use super::*;
+    use crate::{bytecode::Interpreter, vm::VM};
+
+    #[test]
+    fn same_results_as_vm() {
+        let sources = [
+            "1 + 2",
+            "-(1 + 2) * +3",
+            "1 - (2 - (3 - (4 - 5)))",
+            "2 * -3 / +4 - --5",
+            "0.1 + 0.2 * (0.3 - 0.4) / -0.5",
+            "0 / 0",
+            "1; 2; 3 * 4",
+        ];
+        for source in sources {
+            let plain = Interpreter::from_source(source);
+            let fused = Interpreter::from_ast_with_superinstructions(parser::parse(source).unwrap());
+
+            for bytecode in [plain, fused] {
+                let mut decoded = DecodedVM::new(&bytecode);
+                let mut vm = VM::new(bytecode);
+                vm.run().unwrap();
+                // repeated runs start over from an empty stack
+                for _ in 0..3 {
+                    decoded.run();
+                    let (expected, actual) = (vm.get_result(), decoded.get_result());
+                    // the stack vm canonicalizes NaNs, only their bits may differ
+                    assert!(
+                        expected.to_bits() == actual.to_bits() || expected.is_nan() && actual.is_nan(),
+                        "{}",
+                        source
+                    );
+                }
+            }
+        }
+    }
+}
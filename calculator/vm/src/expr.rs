@@ -0,0 +1,113 @@
+use std::sync::Arc;
+
+use calculator_ast_parser::{expr, CompiledExpr, Host, Inputs, Limits, Result};
+
+use crate::{
+    bytecode::{Bytecode, Interpreter},
+    column::ColumnVM,
+    peephole,
+    vm::VM,
+};
+
+// a formula compiled to bytecode once, every eval runs it again in the same vm
+// and eval_batch runs it over whole columns in a ColumnVM.
+// Forks share the bytecode and get vms of their own
+pub struct VmExpr {
+    bytecode: Arc<Bytecode>,
+    host: Host,
+    vm: VM,
+    columns: ColumnVM,
+    inputs: Inputs,
+}
+
+impl VmExpr {
+    pub fn compile(source: &str, inputs: Inputs, host: &Host) -> Result<Self> {
+        let ast = expr::prepare(source, &inputs, host)?;
+        let bytecode = Interpreter::try_from_ast_with_inputs(ast, &Limits::default(), host, &inputs)?;
+        let (bytecode, _) = peephole::optimize(&bytecode);
+        bytecode.check_limits(&Limits::default())?;
+        let columns = ColumnVM::new(&bytecode, host)?;
+        Ok(Self::with_bytecode(Arc::new(bytecode), host.clone(), columns, inputs))
+    }
+
+    fn with_bytecode(bytecode: Arc<Bytecode>, host: Host, columns: ColumnVM, inputs: Inputs) -> Self {
+        let mut vm = VM::new(bytecode.clone());
+        vm.set_host(host.clone());
+        Self {
+            bytecode,
+            host,
+            vm,
+            columns,
+            inputs,
+        }
+    }
+}
+
+impl CompiledExpr for VmExpr {
+    fn inputs(&self) -> &Inputs {
+        &self.inputs
+    }
+
+    fn fork(&self) -> Box<dyn CompiledExpr> {
+        Box::new(Self::with_bytecode(
+            self.bytecode.clone(),
+            self.host.clone(),
+            self.columns.clone(),
+            self.inputs.clone(),
+        ))
+    }
+
+    fn eval(&mut self, values: &[f64]) -> f64 {
+        assert_eq!(values.len(), self.inputs.len(), "wrong number of inputs");
+        self.vm.set_inputs(values);
+        // compile checked the bytecode against the default limits and the host
+        self.vm.run().expect("formula within limits");
+        self.vm.get_result()
+    }
+
+    fn eval_batch(&mut self, columns: &[&[f64]]) -> Vec<f64> {
+        let rows = expr::rows(&self.inputs, columns);
+        self.columns.run(columns, rows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use calculator_ast_parser::LimitError;
+
+    use super::*;
+
+    #[test]
+    fn eval_batch() {
+        let inputs = Inputs::new(&["price", "qty", "discount"]);
+        let mut formula = VmExpr::compile("price * qty * (1 - discount)", inputs, &Host::default()).unwrap();
+        let prices: Vec<f64> = (0..3000).map(|row| row as f64).collect();
+        let quantities = vec![2.0; 3000];
+        let discounts: Vec<f64> = (0..3000).map(|row| (row % 2) as f64 / 2.0).collect();
+        let results = formula.eval_batch(&[&prices, &quantities, &discounts]);
+        assert_eq!(results.len(), 3000);
+        for (row, result) in results.into_iter().enumerate() {
+            assert_eq!(result, formula.eval(&[prices[row], quantities[row], discounts[row]]));
+        }
+        assert_eq!(formula.eval_batch(&[&[], &[], &[]]), vec![]);
+    }
+
+    #[test]
+    fn stack_limit() {
+        // fits the depth and nesting limits, not the stack of the vm
+        let source = "x + x * (".repeat(255) + "x + x * x" + &")".repeat(255);
+        let error = VmExpr::compile(&source, Inputs::new(&["x"]), &Host::default()).err().unwrap();
+        assert_eq!(error.downcast::<LimitError>().unwrap(), LimitError::StackOverflow { max: 512 });
+
+        let source = "x + x * (".repeat(200) + "x" + &")".repeat(200);
+        let mut formula = VmExpr::compile(&source, Inputs::new(&["x"]), &Host::default()).unwrap();
+        assert_eq!(formula.eval(&[1.0]), 201.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "wrong number of inputs")]
+    fn missing_input() {
+        let mut formula = VmExpr::compile("x + y", Inputs::new(&["x", "y"]), &Host::default()).unwrap();
+        formula.eval(&[1.0]);
+    }
+}
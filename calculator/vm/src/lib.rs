@@ -0,0 +1,12 @@
+pub mod bytecode;
+pub mod column;
+pub mod decoded;
+pub mod expr;
+pub mod opcode;
+pub mod peephole;
+pub mod profile;
+pub mod register;
+pub mod snapshot;
+pub mod trace;
+pub mod value;
+pub mod vm;
@@ -1,29 +1,101 @@
-mod opcode;
-mod bytecode;
-mod vm;
+use calculator_ast_parser::{parser, Compile, Limits, Optimizer};
+use calculator_vm::{
+    bytecode::Interpreter,
+    decoded::DecodedVM,
+    peephole, register,
+    trace::{JsonTracer, TextTracer},
+    vm::VM,
+};
+use clap::{Parser, ValueEnum};
 
-use calculator_ast_parser::Compile;
-use clap::Parser;
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum Engine {
+    /// Stack machine running bytecode
+    Stack,
+    /// Stack machine running bytecode decoded once upfront
+    Decoded,
+    /// Register machine running three-address instructions
+    Register,
+}
 
-use crate::{vm::VM, bytecode::Interpreter};
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum TraceFormat {
+    /// One aligned line per instruction
+    Text,
+    /// One JSON object per instruction
+    Json,
+}
 
 #[derive(Debug, Parser)]
 #[command(author, version)]
 #[command(about = "calculator - a simple CLI to calculate")]
 struct Cli {
-    operation: String
+    operation: String,
+    /// Skip the ast and bytecode optimizers, to debug the backend against the raw ast
+    #[arg(long)]
+    no_optimize: bool,
+    /// Execution engine
+    #[arg(long, value_enum, default_value_t = Engine::Stack)]
+    engine: Engine,
+    /// Number of instructions the stack engine may execute before giving up
+    #[arg(long)]
+    fuel: Option<u64>,
+    /// Log every instruction the stack engine executes to stderr,
+    /// with its operand and the stack before and after
+    #[arg(long, value_enum)]
+    trace: Option<TraceFormat>,
 }
 
 // cargo run --package calculator-vm --bin main
 fn main() {
     let cli = Cli::parse();
 
+    if cli.engine == Engine::Register {
+        let optimizer = if cli.no_optimize { Optimizer::disabled() } else { Optimizer::default() };
+        let program = register::Compiler::from_ast_optimized(parser::parse(&cli.operation).unwrap(), &optimizer);
+        eprintln!("{} instructions, {} registers", program.instructions.len(), program.registers);
+
+        let mut vm = register::RegisterVM::new(program);
+        vm.run();
+        println!("result is {}", vm.get_result());
+        return;
+    }
+
     // create new byte code
-    let bytecode = Interpreter::from_source(&cli.operation);
+    let mut bytecode = if cli.no_optimize {
+        Interpreter::from_source(&cli.operation)
+    } else {
+        let ast = Optimizer::default().optimize(parser::parse(&cli.operation).unwrap());
+        Interpreter::from_ast_with_superinstructions(ast)
+    };
+    if !cli.no_optimize {
+        // diagnostics go to stderr, stdout only holds the result
+        let (optimized, report) = peephole::optimize(&bytecode);
+        eprintln!("peephole saved {} of {} instructions", report.saved(), report.before);
+        bytecode = optimized;
+    }
+
+    eprintln!("{} instructions", bytecode.decode().len());
+
+    if cli.engine == Engine::Decoded {
+        let mut vm = DecodedVM::new(&bytecode);
+        vm.run();
+        println!("result is {}", vm.get_result());
+        return;
+    }
 
     // create new vm
-    let mut vm = VM::new(bytecode);
-    vm.run();
+    let mut limits = Limits::default();
+    if let Some(fuel) = cli.fuel {
+        limits.fuel = fuel;
+    }
+    let mut vm = VM::with_limits(bytecode, limits);
+    match cli.trace {
+        Some(TraceFormat::Text) => vm.set_tracer(Box::new(TextTracer::new(std::io::stderr()))),
+        Some(TraceFormat::Json) => vm.set_tracer(Box::new(JsonTracer::new(std::io::stderr()))),
+        None => {}
+    }
+    vm.run().unwrap_or_else(|e| panic!("runtime error: {}", e));
     let out = vm.get_result();
 
     println!("result is {}", out)
@@ -1,7 +1,6 @@
-use std::default;
-
 // define op code
-#[derive(Debug, Copy, Clone)]
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum OpCode {
     OpConstant(f64), // pointer to constant table
     OpPop,           // pop is needed for execution 
@@ -10,7 +9,18 @@ pub enum OpCode {
     OpMul,
     OpDiv,
     OpPlus,
-    OpMinus
+    OpMinus,
+    // superinstructions, fusing `OpConstant(x); OpAdd` and friends into a
+    // single dispatch where the constant is the right hand side operand
+    OpAddConst(f64),
+    OpSubConst(f64),
+    OpMulConst(f64),
+    OpDivConst(f64),
+    // calls the host function described by Bytecode::calls[index],
+    // its number arguments are on the stack and replaced by its result
+    OpCall(u32),
+    // pushes the value of the input variable at index
+    OpLoad(u32),
 }
 
 // const byte op will be in this format [0x01, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]
@@ -20,6 +30,13 @@ fn make_const_byte_op(code: u8, data: f64) -> Vec<u8> {
     output
 }
 
+// index byte op will be in this format [0x20, 0xff, 0xff, 0xff, 0xff]
+fn make_index_byte_op(code: u8, index: u32) -> Vec<u8> {
+    let mut output = vec![code];
+    output.extend(index.to_be_bytes());
+    output
+}
+
 impl OpCode {
     pub fn bytes(self) -> Vec<u8>{
         match self {
@@ -31,6 +48,72 @@ impl OpCode {
             OpCode::OpDiv => vec![0x06],  // decimal repr is 6
             OpCode::OpPlus => vec![0x0A], // decimal repr is 10
             OpCode::OpMinus => vec![0x0B], // decimal repr is 11
+            OpCode::OpAddConst(arg) => make_const_byte_op(0x11, arg),
+            OpCode::OpSubConst(arg) => make_const_byte_op(0x12, arg),
+            OpCode::OpMulConst(arg) => make_const_byte_op(0x13, arg),
+            OpCode::OpDivConst(arg) => make_const_byte_op(0x14, arg),
+            OpCode::OpCall(index) => make_index_byte_op(0x20, index),
+            OpCode::OpLoad(index) => make_index_byte_op(0x21, index),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            OpCode::OpConstant(_) => "OpConstant",
+            OpCode::OpPop => "OpPop",
+            OpCode::OpAdd => "OpAdd",
+            OpCode::OpSub => "OpSub",
+            OpCode::OpMul => "OpMul",
+            OpCode::OpDiv => "OpDiv",
+            OpCode::OpPlus => "OpPlus",
+            OpCode::OpMinus => "OpMinus",
+            OpCode::OpAddConst(_) => "OpAddConst",
+            OpCode::OpSubConst(_) => "OpSubConst",
+            OpCode::OpMulConst(_) => "OpMulConst",
+            OpCode::OpDivConst(_) => "OpDivConst",
+            OpCode::OpCall(_) => "OpCall",
+            OpCode::OpLoad(_) => "OpLoad",
+        }
+    }
+
+    // the f64 operand encoded after the op code, if it has one
+    pub fn operand(self) -> Option<f64> {
+        match self {
+            OpCode::OpConstant(arg)
+            | OpCode::OpAddConst(arg)
+            | OpCode::OpSubConst(arg)
+            | OpCode::OpMulConst(arg)
+            | OpCode::OpDivConst(arg) => Some(arg),
+            _ => None,
+        }
+    }
+
+    // op codes followed by an 8 bytes f64 operand
+    pub fn has_constant(code: u8) -> bool {
+        matches!(code, 0x01 | 0x11..=0x14)
+    }
+
+    pub fn with_constant(code: u8, arg: f64) -> Self {
+        match code {
+            0x01 => OpCode::OpConstant(arg),
+            0x11 => OpCode::OpAddConst(arg),
+            0x12 => OpCode::OpSubConst(arg),
+            0x13 => OpCode::OpMulConst(arg),
+            0x14 => OpCode::OpDivConst(arg),
+            _ => panic!("not recognized opcode")
+        }
+    }
+
+    // op codes followed by a 4 bytes u32 index
+    pub fn has_index(code: u8) -> bool {
+        matches!(code, 0x20 | 0x21)
+    }
+
+    pub fn with_index(code: u8, index: u32) -> Self {
+        match code {
+            0x20 => OpCode::OpCall(index),
+            0x21 => OpCode::OpLoad(index),
+            _ => panic!("not recognized opcode")
         }
     }
 }
@@ -68,4 +151,21 @@ mod tests {
     fn make_op_add() {
         assert_eq!(vec![0x03], OpCode::OpAdd.bytes());
     }
+
+    #[test]
+    fn make_op_add_const() {
+        assert_eq!(vec![0x11, 64, 239, 255, 192, 0, 0, 0, 0], OpCode::OpAddConst(65534.0).bytes());
+        assert_eq!(OpCode::OpAddConst(65534.0), OpCode::with_constant(0x11, 65534.0));
+    }
+
+    #[test]
+    fn make_op_call() {
+        assert_eq!(vec![0x20, 0, 0, 1, 2], OpCode::OpCall(258).bytes());
+    }
+
+    #[test]
+    fn make_op_load() {
+        assert_eq!(vec![0x21, 0, 0, 0, 3], OpCode::OpLoad(3).bytes());
+        assert_eq!(OpCode::OpLoad(3), OpCode::with_index(0x21, 3));
+    }
 }
\ No newline at end of file
@@ -0,0 +1,164 @@
+use crate::{bytecode::Bytecode, opcode::OpCode};
+
+// number of instructions before and after a peephole pass
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Report {
+    pub before: usize,
+    pub after: usize,
+}
+
+impl Report {
+    pub fn saved(&self) -> usize {
+        self.before - self.after
+    }
+}
+
+// Rewrites short windows of instructions into cheaper equivalents
+// until nothing changes anymore:
+// - OpPlus is a no-op and is removed
+// - OpMinus; OpMinus cancel each other
+// - OpConstant x; OpMinus becomes OpConstant -x
+// - OpConstant a; OpConstant b; OpAdd (or any other infix op code) becomes OpConstant a + b
+// - OpConstant a; OpAddConst b (or any other superinstruction) becomes OpConstant a + b
+//
+// every rewrite yields the exact same f64 as the vm would compute
+pub fn optimize(bytecode: &Bytecode) -> (Bytecode, Report) {
+    let mut opcodes = bytecode.decode();
+    let before = opcodes.len();
+
+    loop {
+        let rewritten = rewrite(&opcodes);
+        if rewritten.len() == opcodes.len() {
+            break;
+        }
+        opcodes = rewritten;
+    }
+
+    let report = Report {
+        before,
+        after: opcodes.len(),
+    };
+    // no rewrite touches OpCall, the calls keep their index
+    let mut optimized: Bytecode = opcodes.into_iter().collect();
+    optimized.calls = bytecode.calls.clone();
+    (optimized, report)
+}
+
+// a single pass over the instructions, the output is shorter whenever a rewrite applied
+fn rewrite(opcodes: &[OpCode]) -> Vec<OpCode> {
+    let mut out: Vec<OpCode> = Vec::with_capacity(opcodes.len());
+    for opcode in opcodes {
+        // the window is the tail of the instructions emitted so far
+        match (out.as_slice(), opcode) {
+            (_, OpCode::OpPlus) => {}
+            ([.., OpCode::OpMinus], OpCode::OpMinus) => {
+                out.pop();
+            }
+            ([.., OpCode::OpConstant(val)], OpCode::OpMinus) => {
+                let val = -*val;
+                out.pop();
+                out.push(OpCode::OpConstant(val));
+            }
+            ([.., OpCode::OpConstant(lhs), OpCode::OpConstant(rhs)], infix) if fold(*infix).is_some() => {
+                let val = fold(*infix).unwrap()(*lhs, *rhs);
+                out.truncate(out.len() - 2);
+                out.push(OpCode::OpConstant(val));
+            }
+            ([.., OpCode::OpConstant(lhs)], fused) if unfuse(*fused).is_some() => {
+                let (infix, rhs) = unfuse(*fused).unwrap();
+                let val = fold(infix).unwrap()(*lhs, rhs);
+                out.pop();
+                out.push(OpCode::OpConstant(val));
+            }
+            (_, opcode) => out.push(*opcode),
+        }
+    }
+    out
+}
+
+fn fold(opcode: OpCode) -> Option<fn(f64, f64) -> f64> {
+    match opcode {
+        OpCode::OpAdd => Some(|lhs, rhs| lhs + rhs),
+        OpCode::OpSub => Some(|lhs, rhs| lhs - rhs),
+        OpCode::OpMul => Some(|lhs, rhs| lhs * rhs),
+        OpCode::OpDiv => Some(|lhs, rhs| lhs / rhs),
+        _ => None,
+    }
+}
+
+// splits a superinstruction into its infix op code and constant
+fn unfuse(opcode: OpCode) -> Option<(OpCode, f64)> {
+    match opcode {
+        OpCode::OpAddConst(rhs) => Some((OpCode::OpAdd, rhs)),
+        OpCode::OpSubConst(rhs) => Some((OpCode::OpSub, rhs)),
+        OpCode::OpMulConst(rhs) => Some((OpCode::OpMul, rhs)),
+        OpCode::OpDivConst(rhs) => Some((OpCode::OpDiv, rhs)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use calculator_ast_parser::Compile;
+
+    use super::*;
+    use crate::{bytecode::Interpreter, vm::VM};
+
+    fn assert_optimized(source: &str, expected: Vec<OpCode>, saved: usize) {
+        let (bytecode, report) = optimize(&Interpreter::from_source(source));
+        assert_eq!(bytecode.decode(), expected, "{}", source);
+        assert_eq!(report.saved(), saved, "{}", source);
+    }
+
+    #[test]
+    fn rewrites() {
+        assert_optimized("+1", vec![OpCode::OpConstant(1.0), OpCode::OpPop], 1);
+        assert_optimized("-2", vec![OpCode::OpConstant(-2.0), OpCode::OpPop], 1);
+        assert_optimized("--2", vec![OpCode::OpConstant(2.0), OpCode::OpPop], 2);
+        assert_optimized("1 + 2", vec![OpCode::OpConstant(3.0), OpCode::OpPop], 2);
+        assert_optimized("-(1 + 2) * +3", vec![OpCode::OpConstant(-9.0), OpCode::OpPop], 6);
+        assert_optimized(
+            "1; -2",
+            vec![OpCode::OpConstant(1.0), OpCode::OpPop, OpCode::OpConstant(-2.0), OpCode::OpPop],
+            1,
+        );
+        assert_optimized("", vec![], 0);
+
+        // a call is opaque, constants around it still fold
+        let (bytecode, _) = optimize(&Interpreter::from_source("f(1 + 2, \"a\") * (3 - 4)"));
+        assert_eq!(
+            bytecode.decode(),
+            vec![OpCode::OpConstant(3.0), OpCode::OpCall(0), OpCode::OpConstant(-1.0), OpCode::OpMul, OpCode::OpPop]
+        );
+        assert_eq!(bytecode.calls, Interpreter::from_source("f(0, \"a\")").calls);
+
+        let ast = calculator_ast_parser::parser::parse("(1 + 2) * -3").unwrap();
+        let (bytecode, report) = optimize(&Interpreter::from_ast_with_superinstructions(ast));
+        assert_eq!(bytecode.decode(), vec![OpCode::OpConstant(-9.0), OpCode::OpPop]);
+        assert_eq!(report.saved(), 4);
+    }
+
+    #[test]
+    fn results_unchanged() {
+        let sources = [
+            "1 + 2 * 3 - 4 / 5",
+            "-(1 + 2) * 3",
+            "--1 - -+-2",
+            "1 / 0",
+            "-1 / 0",
+            "0 * -1",
+            "0.1 + 0.2 - 0.3",
+            "1; 2; 3 * (4 - -5)",
+        ];
+        for source in sources {
+            let bytecode = Interpreter::from_source(source);
+            let (optimized, _) = optimize(&bytecode);
+
+            let mut vm = VM::new(bytecode);
+            vm.run().unwrap();
+            let mut optimized_vm = VM::new(optimized);
+            optimized_vm.run().unwrap();
+            assert_eq!(vm.get_result().to_bits(), optimized_vm.get_result().to_bits(), "{}", source);
+        }
+    }
+}
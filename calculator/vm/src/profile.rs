@@ -0,0 +1,119 @@
+use std::{collections::HashMap, time::Duration};
+
+use crate::opcode::OpCode;
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Counter {
+    pub count: u64,
+    pub time: Duration,
+}
+
+impl Counter {
+    fn add(&mut self, other: Counter) {
+        self.count += other.count;
+        self.time += other.time;
+    }
+
+    pub fn mean(&self) -> Duration {
+        if self.count == 0 {
+            return Duration::ZERO;
+        }
+        // dividing by a u32 would truncate the count
+        Duration::from_nanos((self.time.as_nanos() / self.count as u128) as u64)
+    }
+}
+
+// Profiler counts how many times each instruction runs and how long it takes,
+// grouped by op code and by bytecode offset. It keeps adding up across runs,
+// so a program evaluated many times gets a profile of all the evaluations.
+#[derive(Debug, Clone, Default)]
+pub struct Profiler {
+    by_offset: HashMap<usize, (OpCode, Counter)>,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, offset: usize, opcode: OpCode, time: Duration) {
+        let (_, counter) = self.by_offset.entry(offset).or_insert((opcode, Counter::default()));
+        counter.add(Counter { count: 1, time });
+    }
+
+    pub fn total(&self) -> Counter {
+        let mut total = Counter::default();
+        for (_, counter) in self.by_offset.values() {
+            total.add(*counter);
+        }
+        total
+    }
+
+    // most expensive op code first
+    pub fn by_opcode(&self) -> Vec<(&'static str, Counter)> {
+        let mut by_opcode: HashMap<&'static str, Counter> = HashMap::new();
+        for (opcode, counter) in self.by_offset.values() {
+            by_opcode.entry(opcode.name()).or_default().add(*counter);
+        }
+        let mut by_opcode: Vec<_> = by_opcode.into_iter().collect();
+        by_opcode.sort_by(|(lhs_name, lhs), (rhs_name, rhs)| {
+            (rhs.time, rhs.count, lhs_name).cmp(&(lhs.time, lhs.count, rhs_name))
+        });
+        by_opcode
+    }
+
+    // most expensive instruction first
+    pub fn by_offset(&self) -> Vec<(usize, OpCode, Counter)> {
+        let mut by_offset: Vec<_> = self
+            .by_offset
+            .iter()
+            .map(|(offset, (opcode, counter))| (*offset, *opcode, *counter))
+            .collect();
+        by_offset.sort_by(|(lhs_offset, _, lhs), (rhs_offset, _, rhs)| {
+            (rhs.time, rhs.count, lhs_offset).cmp(&(lhs.time, lhs.count, rhs_offset))
+        });
+        by_offset
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use calculator_ast_parser::Compile;
+
+    use super::*;
+    use crate::{bytecode::Interpreter, vm::VM};
+
+    #[test]
+    fn counts() {
+        let mut vm = VM::new(Interpreter::from_source("1 + 2 + 3; 4"));
+        vm.enable_profiler();
+        vm.run().unwrap();
+        vm.run().unwrap();
+
+        let profiler = vm.profiler().unwrap();
+        assert_eq!(profiler.total().count, 2 * 8);
+
+        let mut by_opcode: Vec<_> = profiler.by_opcode().into_iter().map(|(name, counter)| (name, counter.count)).collect();
+        by_opcode.sort();
+        assert_eq!(by_opcode, vec![("OpAdd", 4), ("OpConstant", 8), ("OpPop", 4)]);
+
+        let mut by_offset: Vec<_> = profiler.by_offset().into_iter().map(|(offset, opcode, counter)| (offset, opcode, counter.count)).collect();
+        by_offset.sort_by_key(|(offset, _, _)| *offset);
+        assert_eq!(by_offset[..3], [(0, OpCode::OpConstant(1.0), 2), (9, OpCode::OpConstant(2.0), 2), (18, OpCode::OpAdd, 2)]);
+
+        // sorted by time, the total adds up
+        let by_offset = profiler.by_offset();
+        assert!(by_offset.windows(2).all(|pair| pair[0].2.time >= pair[1].2.time));
+        assert_eq!(by_offset.iter().map(|(_, _, counter)| counter.time).sum::<Duration>(), profiler.total().time);
+    }
+
+    #[test]
+    fn mean() {
+        let counter = Counter { count: 4, time: Duration::from_nanos(100) };
+        assert_eq!(counter.mean(), Duration::from_nanos(25));
+        assert_eq!(Counter::default().mean(), Duration::ZERO);
+        // counts past u32::MAX
+        let counter = Counter { count: 1 << 32, time: Duration::from_secs(1 << 32) };
+        assert_eq!(counter.mean(), Duration::from_secs(1));
+    }
+}
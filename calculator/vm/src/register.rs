@@ -0,0 +1,243 @@
+use calculator_ast_parser::{Compile, LimitError, Limits, Node, Operator, Sign, Visitor};
+
+// index into the register file of the RegisterVM
+pub type Register = usize;
+
+// an instruction operand is either a register or an immediate constant,
+// so literals never need an instruction of their own
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Operand {
+    Register(Register),
+    Constant(f64),
+}
+
+// three-address instructions: dst = lhs <op> rhs
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Instruction {
+    Neg { dst: Register, src: Operand },
+    Binary { op: Operator, dst: Register, lhs: Operand, rhs: Operand },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    pub instructions: Vec<Instruction>,
+    // size of the register file needed to run the program
+    pub registers: usize,
+    // where the value of the last expression ends up
+    pub result: Operand,
+}
+
+// Compiler allocates virtual registers while walking the ast.
+// A register is released as soon as its value has been consumed,
+// so the register file only grows with the depth of the expression.
+#[derive(Debug)]
+pub struct Compiler {
+    instructions: Vec<Instruction>,
+    free: Vec<Register>,
+    registers: usize,
+    // operands of the expressions visited so far, consumed by their parent
+    operands: Vec<Operand>,
+}
+
+impl Compiler {
+    fn new() -> Self {
+        Self {
+            instructions: vec![],
+            free: vec![],
+            registers: 0,
+            operands: vec![],
+        }
+    }
+
+    fn allocate(&mut self) -> Register {
+        self.free.pop().unwrap_or_else(|| {
+            self.registers += 1;
+            self.registers - 1
+        })
+    }
+
+    fn release(&mut self, operand: Operand) {
+        if let Operand::Register(register) = operand {
+            self.free.push(register);
+        }
+    }
+
+    // same as Compile::from_ast, but an ast deeper than the limits allow is
+    // rejected upfront instead of overflowing the native stack while visiting it
+    pub fn try_from_ast(ast: Vec<Node>, limits: &Limits) -> Result<Program, LimitError> {
+        limits.check_depth(&ast)?;
+        Ok(Compiler::from_ast(ast))
+    }
+
+    fn compile(&mut self, node: &Node) -> Operand {
+        self.visit_node(node);
+        self.operands.pop().unwrap()
+    }
+}
+
+impl Visitor for Compiler {
+    fn visit_number(&mut self, value: f64) {
+        self.operands.push(Operand::Constant(value))
+    }
+
+    fn visit_unary(&mut self, op: Sign, child: &Node) {
+        let src = self.compile(child);
+        let operand = match op {
+            Sign::Positive => src,
+            Sign::Negative => {
+                self.release(src);
+                let dst = self.allocate();
+                self.instructions.push(Instruction::Neg { dst, src });
+                Operand::Register(dst)
+            }
+        };
+        self.operands.push(operand)
+    }
+
+    fn visit_binary(&mut self, op: Operator, lhs: &Node, rhs: &Node) {
+        let lhs = self.compile(lhs);
+        let rhs = self.compile(rhs);
+        self.release(rhs);
+        self.release(lhs);
+
+        let dst = self.allocate();
+        self.instructions.push(Instruction::Binary { op, dst, lhs, rhs });
+        self.operands.push(Operand::Register(dst))
+    }
+
+    fn visit_call(&mut self, name: &str, _args: &[Node]) {
+        panic!("host functions are not supported by RegisterVM, cannot call {}", name)
+    }
+
+    fn visit_var(&mut self, name: &str) {
+        panic!("variables are not supported by RegisterVM, cannot load {}", name)
+    }
+}
+
+impl Compile for Compiler {
+    type Output = Program;
+
+    fn from_ast(ast: Vec<Node>) -> Self::Output {
+        let mut compiler = Compiler::new();
+
+        let mut result = Operand::Constant(0.0);
+        for node in ast {
+            // every expression starts from an empty register file
+            compiler.release(result);
+            result = compiler.compile(&node);
+        }
+
+        Program {
+            instructions: compiler.instructions,
+            registers: compiler.registers,
+            result,
+        }
+    }
+}
+
+// RegisterVM executes a Program over a register file of f64 values,
+// the alternative to the stack based vm::VM
+pub struct RegisterVM {
+    program: Program,
+    registers: Vec<f64>,
+}
+
+impl RegisterVM {
+    pub fn new(program: Program) -> Self {
+        let registers = vec![0.0; program.registers];
+        Self { program, registers }
+    }
+
+    fn read(&self, operand: Operand) -> f64 {
+        match operand {
+            Operand::Register(register) => self.registers[register],
+            Operand::Constant(value) => value,
+        }
+    }
+
+    pub fn run(&mut self) {
+        for ip in 0..self.program.instructions.len() {
+            match self.program.instructions[ip] {
+                Instruction::Neg { dst, src } => self.registers[dst] = -self.read(src),
+                Instruction::Binary { op, dst, lhs, rhs } => {
+                    let (lhs, rhs) = (self.read(lhs), self.read(rhs));
+                    self.registers[dst] = match op {
+                        Operator::Add => lhs + rhs,
+                        Operator::Sub => lhs - rhs,
+                        Operator::Mul => lhs * rhs,
+                        Operator::Div => lhs / rhs,
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn get_result(&self) -> f64 {
+        self.read(self.program.result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{bytecode::Interpreter, vm::VM};
+
+    #[test]
+    fn allocation() {
+        let program = Compiler::from_source("(1 + 2) * -(3 - 4)");
+        assert_eq!(
+            program,
+            Program {
+                instructions: vec![
+                    Instruction::Binary {
+                        op: Operator::Add,
+                        dst: 0,
+                        lhs: Operand::Constant(1.0),
+                        rhs: Operand::Constant(2.0)
+                    },
+                    Instruction::Binary {
+                        op: Operator::Sub,
+                        dst: 1,
+                        lhs: Operand::Constant(3.0),
+                        rhs: Operand::Constant(4.0)
+                    },
+                    Instruction::Neg { dst: 1, src: Operand::Register(1) },
+                    Instruction::Binary {
+                        op: Operator::Mul,
+                        dst: 0,
+                        lhs: Operand::Register(0),
+                        rhs: Operand::Register(1)
+                    },
+                ],
+                registers: 2,
+                result: Operand::Register(0),
+            }
+        );
+
+        let program = Compiler::from_source("+2");
+        assert_eq!(program.instructions, vec![]);
+        assert_eq!(program.result, Operand::Constant(2.0));
+    }
+
+    #[test]
+    fn same_results_as_stack_vm() {
+        let sources = [
+            "1 + 2",
+            "-(1 + 2) * 3",
+            "1 - (2 - (3 - (4 - 5)))",
+            "((1 - 2) - 3) - 4",
+            "2 * -3 / +4 - --5",
+            "0.1 + 0.2 * (0.3 - 0.4) / -0.5",
+            "1 / 0",
+            "1; 2; 3 * 4",
+        ];
+        for source in sources {
+            let mut vm = VM::new(Interpreter::from_source(source));
+            vm.run().unwrap();
+
+            let mut register_vm = RegisterVM::new(Compiler::from_source(source));
+            register_vm.run();
+            assert_eq!(vm.get_result(), register_vm.get_result(), "{}", source);
+        }
+    }
+}
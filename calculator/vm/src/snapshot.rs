@@ -0,0 +1,472 @@
+use std::{convert::TryInto, fmt};
+
+use calculator_ast_parser::Limits;
+
+use crate::{
+    bytecode::{Bytecode, Call, CallArg},
+    value::Value,
+    vm::STACK_SIZE,
+};
+
+// A snapshot is the state of a paused VM as bytes, so a program stopped
+// by a breakpoint or by running out of fuel can be resumed later, in
+// another process if need be. The host functions are not part of it, the
+// restored vm needs VM::set_host again, the values of the inputs are. Integers are big endian, like the
+// constants of the bytecode, strings are a u64 length and UTF-8 bytes:
+//
+//   magic      b"CVMS"
+//   version    u32
+//   limits     fuel, max_stack_depth, max_nesting_depth, max_depth
+//              and max_source_len, u64 each
+//   fuel left  u64
+//   ip         u64
+//   bytecode   u64 length, then the instructions
+//   calls      u64 count, then per call its name, the u64 count of its
+//              arguments and per argument a u8 tag, 0 for a number taken
+//              from the stack, 1 for a string followed by the string
+//   stack      u64 length, then the bits of every live value, bottom first
+//   last       bits of the most recently popped value, read by pop_last
+//   inputs     u64 count, then the bits of every f64 value OpLoad reads
+//
+// Anything that changes this layout bumps VERSION.
+const MAGIC: &[u8; 4] = b"CVMS";
+pub const VERSION: u32 = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnapshotError {
+    BadMagic,
+    UnsupportedVersion(u32),
+    Truncated,
+    TrailingBytes(usize),
+    InvalidValue(u64),
+    InvalidBytecode(usize),
+    InvalidString,
+    InvalidCallArg(u8),
+    InvalidIp(u64),
+    StackTooDeep { len: u64, max: usize },
+    StackMismatch { len: u64, expected: usize },
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::BadMagic => write!(f, "not a vm snapshot"),
+            SnapshotError::UnsupportedVersion(version) => {
+                write!(f, "snapshot version {} is not supported, expected {}", version, VERSION)
+            }
+            SnapshotError::Truncated => write!(f, "snapshot is truncated"),
+            SnapshotError::TrailingBytes(len) => write!(f, "{} unexpected bytes at the end of the snapshot", len),
+            SnapshotError::InvalidValue(bits) => write!(f, "{:#x} is not a valid value", bits),
+            SnapshotError::InvalidBytecode(offset) => write!(f, "invalid instruction at offset {}", offset),
+            SnapshotError::InvalidString => write!(f, "string is not valid UTF-8"),
+            SnapshotError::InvalidCallArg(tag) => write!(f, "{:#x} is not a valid call argument", tag),
+            SnapshotError::InvalidIp(ip) => write!(f, "ip {} is not the start of an instruction", ip),
+            SnapshotError::StackTooDeep { len, max } => {
+                write!(f, "stack of {} values does not fit in {}", len, max)
+            }
+            SnapshotError::StackMismatch { len, expected } => {
+                write!(f, "stack of {} values where the bytecode leaves {}", len, expected)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+// everything a snapshot holds, the vm converts to and from it
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct State {
+    pub limits: Limits,
+    pub fuel_left: u64,
+    pub ip: usize,
+    pub bytecode: Bytecode,
+    pub stack: Vec<Value>,
+    pub last: Value,
+    pub inputs: Vec<f64>,
+}
+
+impl State {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&VERSION.to_be_bytes());
+        for val in [
+            self.limits.fuel,
+            self.limits.max_stack_depth as u64,
+            self.limits.max_nesting_depth as u64,
+            self.limits.max_depth as u64,
+            self.limits.max_source_len as u64,
+            self.fuel_left,
+            self.ip as u64,
+            self.bytecode.instructions.len() as u64,
+        ] {
+            bytes.extend_from_slice(&val.to_be_bytes());
+        }
+        bytes.extend_from_slice(&self.bytecode.instructions);
+        bytes.extend_from_slice(&(self.bytecode.calls.len() as u64).to_be_bytes());
+        for call in &self.bytecode.calls {
+            write_str(&mut bytes, &call.name);
+            bytes.extend_from_slice(&(call.args.len() as u64).to_be_bytes());
+            for arg in &call.args {
+                match arg {
+                    CallArg::Number => bytes.push(0),
+                    CallArg::Str(value) => {
+                        bytes.push(1);
+                        write_str(&mut bytes, value);
+                    }
+                }
+            }
+        }
+        bytes.extend_from_slice(&(self.stack.len() as u64).to_be_bytes());
+        for value in self.stack.iter().chain([self.last].iter()) {
+            bytes.extend_from_slice(&value.to_bits().to_be_bytes());
+        }
+        bytes.extend_from_slice(&(self.inputs.len() as u64).to_be_bytes());
+        for value in &self.inputs {
+            bytes.extend_from_slice(&value.to_bits().to_be_bytes());
+        }
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, SnapshotError> {
+        let mut reader = Reader { bytes };
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        let version = u32::from_be_bytes(reader.take(4)?.try_into().unwrap());
+        if version != VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+
+        let limits = Limits {
+            fuel: reader.u64()?,
+            max_stack_depth: reader.usize()?,
+            max_nesting_depth: reader.usize()?,
+            max_depth: reader.usize()?,
+            max_source_len: reader.usize()?,
+        };
+        let fuel_left = reader.u64()?;
+        let ip = reader.u64()?;
+        let len = reader.usize()?;
+        let instructions = reader.take(len)?.to_vec();
+        let count = reader.u64()?;
+        let mut calls = vec![];
+        for _ in 0..count {
+            let name = reader.str()?;
+            let count = reader.u64()?;
+            let mut args = vec![];
+            for _ in 0..count {
+                args.push(match reader.take(1)?[0] {
+                    0 => CallArg::Number,
+                    1 => CallArg::Str(reader.str()?),
+                    tag => return Err(SnapshotError::InvalidCallArg(tag)),
+                });
+            }
+            calls.push(Call { name, args });
+        }
+        let bytecode = Bytecode { instructions, calls };
+        let starts = instruction_starts(&bytecode)?;
+        let ip = match ip.try_into() {
+            Ok(ip) if ip == bytecode.instructions.len() || starts.contains(&ip) => ip,
+            _ => return Err(SnapshotError::InvalidIp(ip)),
+        };
+
+        let expected = stack_height_at(&bytecode, &starts, ip)?;
+
+        let len = reader.u64()?;
+        let max = limits.max_stack_depth.min(STACK_SIZE);
+        if len > max as u64 {
+            return Err(SnapshotError::StackTooDeep { len, max });
+        }
+        if len != expected as u64 {
+            return Err(SnapshotError::StackMismatch { len, expected });
+        }
+        // operators only ever push numbers
+        let stack = (0..len)
+            .map(|_| match reader.value()? {
+                value if value.is_number() => Ok(value),
+                value => Err(SnapshotError::InvalidValue(value.to_bits())),
+            })
+            .collect::<Result<_, _>>()?;
+        let last = reader.value()?;
+        let len = reader.u64()?;
+        let inputs = (0..len).map(|_| reader.u64().map(f64::from_bits)).collect::<Result<_, _>>()?;
+        if !reader.bytes.is_empty() {
+            return Err(SnapshotError::TrailingBytes(reader.bytes.len()));
+        }
+
+        Ok(Self {
+            limits,
+            fuel_left,
+            ip,
+            bytecode,
+            stack,
+            last,
+            inputs,
+        })
+    }
+}
+
+// offsets every instruction starts at, refusing bytecode the vm could not execute
+fn instruction_starts(bytecode: &Bytecode) -> Result<Vec<usize>, SnapshotError> {
+    let instructions = &bytecode.instructions;
+    let mut starts = vec![];
+    let mut ip = 0;
+    while ip < instructions.len() {
+        let len = match instructions[ip] {
+            0x01 | 0x11..=0x14 => 9,
+            0x02..=0x06 | 0x0A | 0x0B => 1,
+            0x20 | 0x21 => 5,
+            _ => return Err(SnapshotError::InvalidBytecode(ip)),
+        };
+        if ip + len > instructions.len() {
+            return Err(SnapshotError::InvalidBytecode(ip));
+        }
+        // a call must refer to an entry of the calls table
+        if instructions[ip] == 0x20 && bytecode.bytes_to_index(ip + 1).0 as usize >= bytecode.calls.len() {
+            return Err(SnapshotError::InvalidBytecode(ip));
+        }
+        starts.push(ip);
+        ip += len;
+    }
+    Ok(starts)
+}
+
+// the height of the stack once the instructions before ip have run. The
+// whole program is checked, so that resuming never pops an empty stack
+fn stack_height_at(bytecode: &Bytecode, starts: &[usize], ip: usize) -> Result<usize, SnapshotError> {
+    let mut height = 0usize;
+    let mut at_ip = None;
+    for &start in starts {
+        if start == ip {
+            at_ip = Some(height);
+        }
+        let (opcode, _) = bytecode.decode_at(start);
+        let (pops, pushes) = bytecode.stack_effect(opcode).ok_or(SnapshotError::InvalidBytecode(start))?;
+        height = height.checked_sub(pops).ok_or(SnapshotError::InvalidBytecode(start))? + pushes;
+    }
+    // ip past the last instruction
+    Ok(at_ip.unwrap_or(height))
+}
+
+fn write_str(bytes: &mut Vec<u8>, value: &str) {
+    bytes.extend_from_slice(&(value.len() as u64).to_be_bytes());
+    bytes.extend_from_slice(value.as_bytes());
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], SnapshotError> {
+        if self.bytes.len() < len {
+            return Err(SnapshotError::Truncated);
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u64(&mut self) -> Result<u64, SnapshotError> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    // a length or limit too large for this platform cannot come from a valid snapshot
+    fn usize(&mut self) -> Result<usize, SnapshotError> {
+        self.u64()?.try_into().map_err(|_| SnapshotError::Truncated)
+    }
+
+    fn str(&mut self) -> Result<String, SnapshotError> {
+        let len = self.usize()?;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| SnapshotError::InvalidString)
+    }
+
+    fn value(&mut self) -> Result<Value, SnapshotError> {
+        let bits = self.u64()?;
+        Value::from_bits(bits).ok_or(SnapshotError::InvalidValue(bits))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use calculator_ast_parser::{Compile, LimitError};
+
+    use super::*;
+    use crate::{
+        bytecode::Interpreter,
+        vm::{RunError, Stop, VM},
+    };
+
+    const SOURCE: &str = "1 + 2 * 3; (4 - 5) / -(6 + 7 * (8 - 9)); 10 * 11 + 12";
+
+    fn uninterrupted(bytecode: &Bytecode) -> f64 {
+        let mut vm = VM::new(bytecode.clone());
+        vm.run().unwrap();
+        vm.get_result()
+    }
+
+    #[test]
+    fn resume_after_out_of_fuel() {
+        let bytecode = Interpreter::from_source(SOURCE);
+        let expected = uninterrupted(&bytecode);
+        let steps = bytecode.disassemble().len() as u64;
+
+        // pause at every possible instruction, then go on in a fresh vm
+        for fuel in 0..steps {
+            let limits = Limits { fuel, ..Limits::default() };
+            let mut vm = VM::with_limits(bytecode.clone(), limits);
+            assert_eq!(vm.run(), Err(RunError::Limit(LimitError::OutOfFuel { fuel })));
+            let snapshot = vm.snapshot();
+            drop(vm);
+
+            let mut vm = VM::restore(&snapshot).unwrap();
+            assert_eq!(vm.ip(), bytecode.disassemble()[fuel as usize].0);
+            vm.refuel(steps - fuel);
+            assert_eq!(vm.resume(), Ok(Stop::Halted));
+            assert_eq!(vm.get_result(), expected, "paused after {} instructions", fuel);
+        }
+
+        // a little fuel at a time, through a snapshot after every pause
+        let mut snapshot = VM::with_limits(bytecode.clone(), Limits { fuel: 0, ..Limits::default() }).snapshot();
+        let mut pauses = 0;
+        let result = loop {
+            let mut vm = VM::restore(&snapshot).unwrap();
+            vm.refuel(2);
+            match vm.resume() {
+                Ok(Stop::Halted) => break vm.get_result(),
+                Err(RunError::Limit(LimitError::OutOfFuel { .. })) => snapshot = vm.snapshot(),
+                other => panic!("unexpected {:?}", other),
+            }
+            pauses += 1;
+        };
+        assert_eq!(result, expected);
+        assert_eq!(pauses, (steps - 1) / 2);
+    }
+
+    #[test]
+    fn round_trip() {
+        let bytecode = Interpreter::from_source(SOURCE);
+        let limits = Limits {
+            fuel: 100,
+            max_stack_depth: 8,
+            ..Limits::default()
+        };
+        let mut vm = VM::with_limits(bytecode.clone(), limits);
+        for _ in 0..12 {
+            vm.step().unwrap();
+        }
+
+        let restored = VM::restore(&vm.snapshot()).unwrap();
+        assert_eq!(restored.ip(), vm.ip());
+        assert_eq!(restored.stack(), vm.stack());
+        assert_eq!(restored.pop_last(), vm.pop_last());
+        assert_eq!(restored.bytecode(), &bytecode);
+        assert_eq!(restored.snapshot(), vm.snapshot());
+
+        // a halted vm keeps its result
+        vm.run().unwrap();
+        let restored = VM::restore(&vm.snapshot()).unwrap();
+        assert!(restored.is_halted());
+        assert_eq!(restored.get_result(), uninterrupted(&bytecode));
+    }
+
+    #[test]
+    fn invalid() {
+        let mut vm = VM::new(Interpreter::from_source("1 + 2"));
+        vm.step().unwrap();
+        let snapshot = vm.snapshot();
+        let error = |bytes: &[u8]| VM::restore(bytes).err().unwrap();
+
+        assert_eq!(error(b"nope"), SnapshotError::BadMagic);
+        assert_eq!(error(&snapshot[..snapshot.len() - 1]), SnapshotError::Truncated);
+        assert_eq!(error(&[&snapshot[..], &[0]].concat()), SnapshotError::TrailingBytes(1));
+
+        let mut bytes = snapshot.clone();
+        bytes[4..8].copy_from_slice(&(VERSION + 1).to_be_bytes());
+        assert_eq!(error(&bytes), SnapshotError::UnsupportedVersion(VERSION + 1));
+
+        // after the 8 bytes of header come the limits and the fuel left, then ip
+        let ip = 8 + 6 * 8;
+        let mut bytes = snapshot.clone();
+        bytes[ip..ip + 8].copy_from_slice(&3u64.to_be_bytes());
+        assert_eq!(error(&bytes), SnapshotError::InvalidIp(3));
+
+        let code = 8 + 8 * 8;
+        let mut bytes = snapshot.clone();
+        bytes[code + 9] = 0xFF;
+        assert_eq!(error(&bytes), SnapshotError::InvalidBytecode(9));
+
+        let invalid = Value::NIL.to_bits() + 10;
+        // the last value is followed by the count of inputs, none here
+        let mut bytes = snapshot.clone();
+        let last = bytes.len() - 16;
+        bytes[last..last + 8].copy_from_slice(&invalid.to_be_bytes());
+        assert_eq!(error(&bytes), SnapshotError::InvalidValue(invalid));
+
+        // a valid value but not a number, on the stack right before the last value
+        let mut bytes = snapshot.clone();
+        bytes[last - 8..last].copy_from_slice(&Value::TRUE.to_bits().to_be_bytes());
+        assert_eq!(error(&bytes), SnapshotError::InvalidValue(Value::TRUE.to_bits()));
+
+        // OpAdd with an empty stack
+        let mut bytes = VM::new(Interpreter::from_source("1 + 2")).snapshot();
+        bytes[ip..ip + 8].copy_from_slice(&18u64.to_be_bytes());
+        assert_eq!(error(&bytes), SnapshotError::StackMismatch { len: 0, expected: 2 });
+
+        // OpCall(1) with a single entry in the calls table
+        let snapshot = VM::new(Interpreter::from_source("f()")).snapshot();
+        let mut bytes = snapshot.clone();
+        bytes[code + 4] = 1;
+        assert_eq!(error(&bytes), SnapshotError::InvalidBytecode(0));
+        // the name of the call follows OpCall, OpPop, the count of calls and its length
+        let mut bytes = snapshot;
+        bytes[code + 6 + 8 + 8] = 0xFF;
+        assert_eq!(error(&bytes), SnapshotError::InvalidString);
+        // the tag of the argument follows OpConstant, OpCall, OpPop, the calls, the name and the count of arguments
+        let mut bytes = VM::new(Interpreter::from_source("f(1)")).snapshot();
+        bytes[code + 15 + 8 + 8 + 1 + 8] = 7;
+        assert_eq!(error(&bytes), SnapshotError::InvalidCallArg(7));
+    }
+
+    #[test]
+    fn host_calls() {
+        let mut host = calculator_ast_parser::Host::new();
+        host.register("rate", &[calculator_ast_parser::Type::Str, calculator_ast_parser::Type::Number], |args| {
+            match args[0].as_str() {
+                Some("EUR") => 1.25 * args[1].as_number().unwrap(),
+                _ => f64::NAN,
+            }
+        });
+        let bytecode = Interpreter::from_source("rate(\"EUR\", 2) + rate(\"EUR\", 4 * 2)");
+
+        let mut vm = VM::with_limits(bytecode, Limits { fuel: 3, ..Limits::default() });
+        vm.set_host(host.clone());
+        assert!(vm.run().is_err());
+
+        // the calls table goes along, the host functions do not
+        let mut vm = VM::restore(&vm.snapshot()).unwrap();
+        assert!(vm.host().get("rate").is_none());
+        vm.set_host(host);
+        vm.refuel(10);
+        assert_eq!(vm.resume(), Ok(Stop::Halted));
+        assert_eq!(vm.get_result(), 12.5);
+    }
+
+    #[test]
+    fn inputs() {
+        let ast = calculator_ast_parser::parser::parse("x * 2 - y").unwrap();
+        let inputs = calculator_ast_parser::Inputs::new(&["x", "y"]);
+        let host = calculator_ast_parser::Host::default();
+        let bytecode = Interpreter::try_from_ast_with_inputs(ast, &Limits::default(), &host, &inputs).unwrap();
+
+        let mut vm = VM::with_limits(bytecode, Limits { fuel: 2, ..Limits::default() });
+        vm.set_inputs(&[4.0, 0.5]);
+        assert!(vm.run().is_err());
+
+        let mut vm = VM::restore(&vm.snapshot()).unwrap();
+        vm.refuel(10);
+        assert_eq!(vm.resume(), Ok(Stop::Halted));
+        assert_eq!(vm.get_result(), 7.5);
+    }
+}
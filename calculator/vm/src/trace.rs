@@ -0,0 +1,188 @@
+use std::io::Write;
+
+use crate::{
+    opcode::OpCode,
+    value::{Primitive, Value},
+};
+
+// one executed instruction
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trace<'a> {
+    pub offset: usize,
+    pub opcode: OpCode,
+    // live stack, bottom first
+    pub before: &'a [Value],
+    pub after: &'a [Value],
+}
+
+// Tracer is called by the VM after every instruction it executes.
+// A VM without a tracer runs its usual loop, so tracing costs nothing
+// unless it is turned on. A vm can be moved to another thread with its tracer.
+pub trait Tracer: Send {
+    fn trace(&mut self, trace: &Trace);
+}
+
+// a line per instruction for humans:
+//     9  OpAddConst 2      [1] -> [3]
+pub struct TextTracer<W: Write> {
+    out: W,
+}
+
+impl<W: Write> TextTracer<W> {
+    pub fn new(out: W) -> Self {
+        Self { out }
+    }
+}
+
+fn text_stack(stack: &[Value]) -> String {
+    let values: Vec<String> = stack.iter().map(|value| Primitive::from(*value).to_string()).collect();
+    format!("[{}]", values.join(", "))
+}
+
+impl<W: Write + Send> Tracer for TextTracer<W> {
+    fn trace(&mut self, trace: &Trace) {
+        let opcode = match trace.opcode.operand() {
+            Some(operand) => format!("{} {}", trace.opcode.name(), operand),
+            None => trace.opcode.name().to_string(),
+        };
+        // a trace that cannot be written must not abort the program it traces
+        let _ = writeln!(
+            self.out,
+            "{:>6}  {:<20} {} -> {}",
+            trace.offset,
+            opcode,
+            text_stack(trace.before),
+            text_stack(trace.after)
+        );
+    }
+}
+
+// a JSON object per line, for tools:
+// {"offset":9,"opcode":"OpAddConst","operand":2,"before":[1],"after":[3]}
+pub struct JsonTracer<W: Write> {
+    out: W,
+}
+
+impl<W: Write> JsonTracer<W> {
+    pub fn new(out: W) -> Self {
+        Self { out }
+    }
+}
+
+// JSON has no NaN nor infinities, they are written as strings
+fn json_number(val: f64) -> String {
+    if val.is_finite() {
+        format!("{}", val)
+    } else {
+        format!("\"{}\"", val)
+    }
+}
+
+fn json_stack(stack: &[Value]) -> String {
+    let values: Vec<String> = stack
+        .iter()
+        .map(|value| match Primitive::from(*value) {
+            Primitive::Number(val) => json_number(val),
+            Primitive::Bool(val) => val.to_string(),
+            Primitive::Nil => "null".to_string(),
+            Primitive::Function(index) => format!("{{\"function\":{}}}", index),
+        })
+        .collect();
+    format!("[{}]", values.join(","))
+}
+
+impl<W: Write + Send> Tracer for JsonTracer<W> {
+    fn trace(&mut self, trace: &Trace) {
+        let operand = match trace.opcode.operand() {
+            Some(operand) => json_number(operand),
+            None => "null".to_string(),
+        };
+        // a trace that cannot be written must not abort the program it traces
+        let _ = writeln!(
+            self.out,
+            "{{\"offset\":{},\"opcode\":\"{}\",\"operand\":{},\"before\":{},\"after\":{}}}",
+            trace.offset,
+            trace.opcode.name(),
+            operand,
+            json_stack(trace.before),
+            json_stack(trace.after)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use calculator_ast_parser::Compile;
+
+    use super::*;
+    use crate::{bytecode::Interpreter, vm::VM};
+
+    // keeps the output reachable once the tracer is owned by the vm
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Shared {
+        fn lines(&self) -> Vec<String> {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap().lines().map(String::from).collect()
+        }
+    }
+
+    #[test]
+    fn text() {
+        let out = Shared::default();
+        let bytecode = Interpreter::from_ast_with_superinstructions(calculator_ast_parser::parser::parse("-1 + 2").unwrap());
+        let mut vm = VM::new(bytecode);
+        vm.set_tracer(Box::new(TextTracer::new(out.clone())));
+        vm.run().unwrap();
+        assert_eq!(
+            out.lines(),
+            vec![
+                "     0  OpConstant 1         [] -> [1]",
+                "     9  OpMinus              [1] -> [-1]",
+                "    10  OpAddConst 2         [-1] -> [1]",
+                "    19  OpPop                [1] -> []",
+            ]
+        );
+    }
+
+    #[test]
+    fn json() {
+        let out = Shared::default();
+        let mut vm = VM::new(Interpreter::from_source("1 / 0 * 0"));
+        vm.set_tracer(Box::new(JsonTracer::new(out.clone())));
+        vm.run().unwrap();
+        assert_eq!(
+            out.lines(),
+            vec![
+                r#"{"offset":0,"opcode":"OpConstant","operand":1,"before":[],"after":[1]}"#,
+                r#"{"offset":9,"opcode":"OpConstant","operand":0,"before":[1],"after":[1,0]}"#,
+                r#"{"offset":18,"opcode":"OpDiv","operand":null,"before":[1,0],"after":["inf"]}"#,
+                r#"{"offset":19,"opcode":"OpConstant","operand":0,"before":["inf"],"after":["inf",0]}"#,
+                r#"{"offset":28,"opcode":"OpMul","operand":null,"before":["inf",0],"after":["NaN"]}"#,
+                r#"{"offset":29,"opcode":"OpPop","operand":null,"before":["NaN"],"after":[]}"#,
+            ]
+        );
+
+        // stepping traces too, and the vm runs silently once the tracer is gone
+        let out = Shared::default();
+        let mut vm = VM::new(Interpreter::from_source("1"));
+        vm.set_tracer(Box::new(JsonTracer::new(out.clone())));
+        vm.step().unwrap();
+        assert_eq!(out.lines().len(), 1);
+        vm.clear_tracer();
+        vm.run().unwrap();
+        assert_eq!(out.lines().len(), 1);
+    }
+}
@@ -0,0 +1,219 @@
+use std::fmt;
+
+// Every value the vm can hold, as exposed to users of the vm.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Primitive {
+    Number(f64),
+    Bool(bool),
+    Nil,
+    // index into a table of functions
+    Function(u32),
+}
+
+impl fmt::Display for Primitive {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Primitive::Number(val) => write!(f, "{}", val),
+            Primitive::Bool(val) => write!(f, "{}", val),
+            Primitive::Nil => write!(f, "nil"),
+            Primitive::Function(index) => write!(f, "<function {}>", index),
+        }
+    }
+}
+
+// Value is a NaN-boxed Primitive packed into 64 bits, so the stack holds
+// plain u64 instead of heap allocated nodes.
+//
+// Any f64 is stored as is, except NaNs that are all collapsed into a single
+// canonical NaN. The other quiet NaN bit patterns are free to encode the
+// remaining primitives:
+//
+//   0x7FFC_0000_0000_0001       nil
+//   0x7FFC_0000_0000_0002       false
+//   0x7FFC_0000_0000_0003       true
+//   0xFFFC_0000_xxxx_xxxx       function, xxxx_xxxx is its index
+//
+// Bit 50 is set on every boxed primitive, it is clear on the canonical NaN
+// and on the NaN produced by 0 / 0 (0x7FF8... or 0xFFF8... depending on the cpu).
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct Value(u64);
+
+const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+const QUIET_NAN: u64 = 0x7FFC_0000_0000_0000;
+
+const TAG_NIL: u64 = 1;
+const TAG_FALSE: u64 = 2;
+const TAG_TRUE: u64 = 3;
+
+impl Value {
+    pub const NIL: Value = Value(QUIET_NAN | TAG_NIL);
+    pub const FALSE: Value = Value(QUIET_NAN | TAG_FALSE);
+    pub const TRUE: Value = Value(QUIET_NAN | TAG_TRUE);
+
+    pub fn number(val: f64) -> Self {
+        if val.is_nan() {
+            Value(f64::NAN.to_bits())
+        } else {
+            Value(val.to_bits())
+        }
+    }
+
+    pub fn bool(val: bool) -> Self {
+        if val {
+            Value::TRUE
+        } else {
+            Value::FALSE
+        }
+    }
+
+    pub fn function(index: u32) -> Self {
+        Value(SIGN_BIT | QUIET_NAN | index as u64)
+    }
+
+    pub fn is_number(self) -> bool {
+        self.0 & QUIET_NAN != QUIET_NAN
+    }
+
+    pub fn as_number(self) -> Option<f64> {
+        if self.is_number() {
+            Some(f64::from_bits(self.0))
+        } else {
+            None
+        }
+    }
+
+    pub fn to_bits(self) -> u64 {
+        self.0
+    }
+
+    // the inverse of to_bits, None for bits no primitive is boxed as
+    pub fn from_bits(bits: u64) -> Option<Self> {
+        let value = Value(bits);
+        let is_function = bits & !0xFFFF_FFFF == SIGN_BIT | QUIET_NAN;
+        if value.is_number() || is_function || matches!(value, Value::NIL | Value::FALSE | Value::TRUE) {
+            Some(value)
+        } else {
+            None
+        }
+    }
+}
+
+impl Default for Value {
+    fn default() -> Self {
+        Value::number(0.0)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(val: f64) -> Self {
+        Value::number(val)
+    }
+}
+
+impl From<Primitive> for Value {
+    fn from(primitive: Primitive) -> Self {
+        match primitive {
+            Primitive::Number(val) => Value::number(val),
+            Primitive::Bool(val) => Value::bool(val),
+            Primitive::Nil => Value::NIL,
+            Primitive::Function(index) => Value::function(index),
+        }
+    }
+}
+
+impl From<Value> for Primitive {
+    fn from(value: Value) -> Self {
+        if let Some(val) = value.as_number() {
+            return Primitive::Number(val);
+        }
+        match value {
+            Value::NIL => Primitive::Nil,
+            Value::FALSE => Primitive::Bool(false),
+            Value::TRUE => Primitive::Bool(true),
+            Value(bits) if bits & SIGN_BIT != 0 => Primitive::Function(bits as u32),
+            Value(bits) => panic!("not a valid value: {:#x}", bits),
+        }
+    }
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Value({:?})", Primitive::from(*self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(primitive: Primitive) -> Primitive {
+        Primitive::from(Value::from(primitive))
+    }
+
+    #[test]
+    fn size() {
+        assert_eq!(std::mem::size_of::<Value>(), 8);
+    }
+
+    #[test]
+    fn numbers() {
+        for val in [0.0, 1.0, -2.5, f64::MAX, f64::MIN_POSITIVE, f64::EPSILON, 1e-320, f64::INFINITY, f64::NEG_INFINITY] {
+            assert_eq!(round_trip(Primitive::Number(val)), Primitive::Number(val));
+            assert_eq!(Value::number(val).to_bits(), val.to_bits());
+        }
+
+        // the sign of zero is kept
+        let zero = Value::number(-0.0).as_number().unwrap();
+        assert!(zero == 0.0 && zero.is_sign_negative());
+    }
+
+    #[test]
+    fn nans() {
+        let zero = 0.0;
+        let nans = [
+            zero / zero,
+            -(zero / zero),
+            f64::NAN,
+            f64::INFINITY - f64::INFINITY,
+            // NaNs whose payload would otherwise read as a boxed primitive
+            f64::from_bits(Value::TRUE.to_bits()),
+            f64::from_bits(Value::function(7).to_bits()),
+            f64::from_bits(0x7FF0_0000_0000_0001),
+        ];
+        for nan in nans {
+            let value = Value::number(nan);
+            assert!(value.is_number());
+            assert!(value.as_number().unwrap().is_nan());
+            assert!(matches!(Primitive::from(value), Primitive::Number(n) if n.is_nan()));
+        }
+    }
+
+    #[test]
+    fn other_primitives() {
+        for primitive in [
+            Primitive::Nil,
+            Primitive::Bool(true),
+            Primitive::Bool(false),
+            Primitive::Function(0),
+            Primitive::Function(42),
+            Primitive::Function(u32::MAX),
+        ] {
+            assert_eq!(round_trip(primitive), primitive);
+            assert_eq!(Value::from(primitive).as_number(), None);
+        }
+
+        assert_ne!(Value::NIL, Value::FALSE);
+        assert_ne!(Value::function(0), Value::NIL);
+        assert_ne!(Value::function(1), Value::number(0.0));
+    }
+
+    #[test]
+    fn from_bits() {
+        for value in [Value::number(-2.5), Value::number(f64::NAN), Value::NIL, Value::TRUE, Value::function(u32::MAX)] {
+            assert_eq!(Value::from_bits(value.to_bits()), Some(value));
+        }
+        assert_eq!(Value::from_bits(QUIET_NAN), None);
+        assert_eq!(Value::from_bits(QUIET_NAN | 4), None);
+        assert_eq!(Value::from_bits(SIGN_BIT | QUIET_NAN | 1 << 32), None);
+    }
+}
@@ -1,132 +1,782 @@
-use calculator_ast_parser::Node;
+use std::{
+    collections::BTreeSet,
+    fmt,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
 
-use crate::bytecode::Bytecode;
+use calculator_ast_parser::{Arg, Fuel, Host, HostError, LimitError, Limits};
 
-const STACK_SIZE: usize = 512;
+use crate::{
+    bytecode::{Bytecode, CallArg},
+    opcode::OpCode,
+    profile::Profiler,
+    snapshot::{SnapshotError, State},
+    trace::{Trace, Tracer},
+    value::{Primitive, Value},
+};
+
+pub(crate) const STACK_SIZE: usize = 512;
 pub struct VM {
-    bytecode: Bytecode,
-    stack: [Node; STACK_SIZE],
-    stack_ptr: usize
+    // shared with the vms running the same program, on other threads
+    bytecode: Arc<Bytecode>,
+    stack: [Value; STACK_SIZE],
+    stack_ptr: usize,
+    // value of the last statement, the most recent value removed by OpPop
+    last: Value,
+    limits: Limits,
+    // instruction pointer, offset of the next instruction to execute
+    ip: usize,
+    fuel: Fuel,
+    // offsets where resume stops
+    breakpoints: BTreeSet<usize>,
+    // only ever reached through &mut self, the mutex is there to keep the vm
+    // Sync with a tracer that is only Send and is never locked
+    tracer: Option<Mutex<Box<dyn Tracer>>>,
+    profiler: Option<Profiler>,
+    // functions OpCall looks up by name
+    host: Host,
+    // values OpLoad pushes, by index
+    inputs: Vec<f64>,
+}
+
+// why a run stopped before the end of the program
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RunError {
+    Limit(LimitError),
+    // OpCall reached a function the host lacks or takes other arguments,
+    // the bytecode was compiled against another host than the one set
+    Host(HostError),
+    // OpLoad read an input VM::set_inputs gave no value for
+    MissingInput { index: usize },
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunError::Limit(error) => error.fmt(f),
+            RunError::Host(error) => error.fmt(f),
+            RunError::MissingInput { index } => write!(f, "input {} is not set", index),
+        }
+    }
+}
+
+impl std::error::Error for RunError {}
+
+impl From<LimitError> for RunError {
+    fn from(error: LimitError) -> Self {
+        RunError::Limit(error)
+    }
+}
+
+impl From<HostError> for RunError {
+    fn from(error: HostError) -> Self {
+        RunError::Host(error)
+    }
+}
+
+// why resume returned
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Stop {
+    // about to execute the instruction at this offset
+    Breakpoint(usize),
+    // every instruction has been executed
+    Halted,
 }
 
 impl VM {
-    pub fn new(bytecode: Bytecode) -> Self {
+    pub fn new(bytecode: impl Into<Arc<Bytecode>>) -> Self {
+        Self::with_limits(bytecode, Limits::default())
+    }
+
+    // the stack can never grow past STACK_SIZE, whatever the limits say
+    pub fn with_limits(bytecode: impl Into<Arc<Bytecode>>, limits: Limits) -> Self {
         Self {
-            bytecode,
-            stack: unsafe { std::mem::zeroed() },
-            stack_ptr: 0
+            bytecode: bytecode.into(),
+            stack: [Value::default(); STACK_SIZE],
+            stack_ptr: 0,
+            last: Value::NIL,
+            limits,
+            ip: 0,
+            fuel: Fuel::new(limits.fuel),
+            breakpoints: BTreeSet::new(),
+            tracer: None,
+            profiler: None,
+            host: Host::default(),
+            inputs: vec![],
         }
     }
 
-    pub fn run(&mut self) {
-        // instruction pointer
-        let mut ip = 0;
+    // back to the start of the program with an empty stack, no result and
+    // a full tank; breakpoints, the tracer and the profiler are kept
+    pub fn reset(&mut self) {
+        self.ip = 0;
+        self.stack_ptr = 0;
+        self.last = Value::NIL;
+        self.fuel = Fuel::new(self.limits.fuel);
+    }
+
+    // swaps the program, the breakpoints of the previous one are dropped
+    pub fn load(&mut self, bytecode: impl Into<Arc<Bytecode>>) {
+        self.bytecode = bytecode.into();
+        self.breakpoints.clear();
+        self.reset();
+    }
+
+    // runs another program in this vm and returns its result,
+    // reusing the instruction buffer of the previous one unless it is shared
+    pub fn eval(&mut self, bytecode: &Bytecode) -> Result<Primitive, RunError> {
+        let own = Arc::make_mut(&mut self.bytecode);
+        own.instructions.clear();
+        own.instructions.extend_from_slice(&bytecode.instructions);
+        own.calls.clone_from(&bytecode.calls);
+        self.breakpoints.clear();
+        self.run()?;
+        Ok(self.pop_last())
+    }
+
+    // runs the whole program from the start, every instruction costs one unit of fuel
+    pub fn run(&mut self) -> Result<(), RunError> {
+        self.reset();
+        // the plain loop stays free of any tracing or profiling check
+        if self.is_instrumented() {
+            while self.ip < self.bytecode.instructions.len() {
+                self.execute_instrumented()?;
+            }
+            return Ok(());
+        }
+
         // fetch instructions
-        while ip < self.bytecode.instructions.len() {
-            let opcode = self.bytecode.instructions[ip];
-            ip += 1;
-
-            match opcode {
-                0x01 => {
-                    let (val, next) = self.bytecode.bytes_to_constants(ip);
-                    ip = next;
-                    self.push(Node::Number(val));
-                },
-                0x02 => _ = self.pop(),
-                0x03 => {
-                    match (self.pop(), self.pop()) {
-                        (Node::Number(rhs), Node::Number(lhs)) => self.push(Node::Number(lhs + rhs)),
-                        _ => panic!("Unknown types to OpAdd"),
-                    }
+        while self.ip < self.bytecode.instructions.len() {
+            self.execute()?;
+        }
+        Ok(())
+    }
+
+    // executes the instruction at ip,
+    // returns false without doing anything once the program is over
+    pub fn step(&mut self) -> Result<bool, RunError> {
+        if self.is_halted() {
+            return Ok(false);
+        }
+        self.execute_instrumented()?;
+        Ok(true)
+    }
+
+    // runs until the next breakpoint or the end of the program,
+    // a breakpoint at the current ip does not stop it again
+    pub fn resume(&mut self) -> Result<Stop, RunError> {
+        if !self.step()? {
+            return Ok(Stop::Halted);
+        }
+        while !self.is_halted() {
+            if self.breakpoints.contains(&self.ip) {
+                return Ok(Stop::Breakpoint(self.ip));
+            }
+            self.execute_instrumented()?;
+        }
+        Ok(Stop::Halted)
+    }
+
+    // a fresh budget for the instructions still to execute,
+    // typically before resuming a vm that ran out of fuel
+    pub fn refuel(&mut self, fuel: u64) {
+        self.fuel = Fuel::new(fuel);
+    }
+
+    // the paused state as bytes, see the snapshot module for the layout;
+    // breakpoints, the tracer and the profiler are not part of it
+    pub fn snapshot(&self) -> Vec<u8> {
+        State {
+            limits: self.limits,
+            fuel_left: self.fuel.left(),
+            ip: self.ip,
+            bytecode: (*self.bytecode).clone(),
+            stack: self.stack().to_vec(),
+            last: self.last,
+            inputs: self.inputs.clone(),
+        }
+        .encode()
+    }
+
+    // a vm paused where the snapshot was taken, resume goes on from there
+    pub fn restore(snapshot: &[u8]) -> Result<Self, SnapshotError> {
+        let state = State::decode(snapshot)?;
+        let mut vm = Self::with_limits(state.bytecode, state.limits);
+        vm.ip = state.ip;
+        vm.fuel = Fuel::new(state.fuel_left);
+        vm.stack_ptr = state.stack.len();
+        vm.stack[..vm.stack_ptr].copy_from_slice(&state.stack);
+        vm.last = state.last;
+        vm.inputs = state.inputs;
+        Ok(vm)
+    }
+
+    // returns false if offset is not the start of an instruction
+    pub fn set_breakpoint(&mut self, offset: usize) -> bool {
+        if !self.bytecode.disassemble().iter().any(|(ip, _)| *ip == offset) {
+            return false;
+        }
+        self.breakpoints.insert(offset)
+    }
+
+    pub fn remove_breakpoint(&mut self, offset: usize) -> bool {
+        self.breakpoints.remove(&offset)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    pub fn ip(&self) -> usize {
+        self.ip
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.ip >= self.bytecode.instructions.len()
+    }
+
+    // the instruction at ip, the next one to execute
+    pub fn current_opcode(&self) -> Option<OpCode> {
+        if self.is_halted() {
+            return None;
+        }
+        Some(self.bytecode.decode_at(self.ip).0)
+    }
+
+    // live values, bottom of the stack first
+    pub fn stack(&self) -> &[Value] {
+        &self.stack[..self.stack_ptr]
+    }
+
+    pub fn bytecode(&self) -> &Bytecode {
+        &self.bytecode
+    }
+
+    // the functions the calls of the bytecode resolve to, see
+    // Interpreter::try_from_ast_with_host to check the calls when compiling
+    pub fn set_host(&mut self, host: Host) {
+        self.host = host;
+    }
+
+    pub fn host(&self) -> &Host {
+        &self.host
+    }
+
+    // the values of the variables, in the order of the inputs the bytecode
+    // was compiled with by Interpreter::try_from_ast_with_inputs
+    pub fn set_inputs(&mut self, values: &[f64]) {
+        self.inputs.clear();
+        self.inputs.extend_from_slice(values);
+    }
+
+    // every instruction executed from now on is reported to the tracer
+    pub fn set_tracer(&mut self, tracer: Box<dyn Tracer>) {
+        self.tracer = Some(Mutex::new(tracer));
+    }
+
+    pub fn clear_tracer(&mut self) {
+        self.tracer = None;
+    }
+
+    // counts and times every instruction executed from now on,
+    // on top of what an already enabled profiler has recorded
+    pub fn enable_profiler(&mut self) {
+        self.profiler.get_or_insert_with(Profiler::new);
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    pub fn take_profiler(&mut self) -> Option<Profiler> {
+        self.profiler.take()
+    }
+
+    fn is_instrumented(&self) -> bool {
+        self.tracer.is_some() || self.profiler.is_some()
+    }
+
+    // same as execute, reporting the instruction to the tracer and the profiler
+    fn execute_instrumented(&mut self) -> Result<(), RunError> {
+        if !self.is_instrumented() {
+            return self.execute();
+        }
+
+        let offset = self.ip;
+        let opcode = self.bytecode.decode_at(offset).0;
+        let before = match self.tracer {
+            Some(_) => self.stack().to_vec(),
+            None => vec![],
+        };
+        let start = Instant::now();
+        self.execute()?;
+        let elapsed = start.elapsed();
+
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.record(offset, opcode, elapsed);
+        }
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.get_mut().unwrap().trace(&Trace {
+                offset,
+                opcode,
+                before: &before,
+                after: &self.stack[..self.stack_ptr],
+            });
+        }
+        Ok(())
+    }
+
+    fn execute(&mut self) -> Result<(), RunError> {
+        self.fuel.consume()?;
+        let mut ip = self.ip;
+        let opcode = self.bytecode.instructions[ip];
+        ip += 1;
+
+        match opcode {
+            0x01 => {
+                let (val, next) = self.bytecode.bytes_to_constants(ip);
+                ip = next;
+                self.push(Value::number(val))?;
+            },
+            0x02 => self.last = self.pop(),
+            0x03 => {
+                match (self.pop().as_number(), self.pop().as_number()) {
+                    (Some(rhs), Some(lhs)) => self.push(Value::number(lhs + rhs))?,
+                    _ => panic!("Unknown types to OpAdd"),
+                }
+            }
+            0x04 => {
+                match (self.pop().as_number(), self.pop().as_number()) {
+                    (Some(rhs), Some(lhs)) => self.push(Value::number(lhs - rhs))?,
+                    _ => panic!("Unknown types to OpSub"),
+                }
+            }
+            0x05 => {
+                match (self.pop().as_number(), self.pop().as_number()) {
+                    (Some(rhs), Some(lhs)) => self.push(Value::number(lhs * rhs))?,
+                    _ => panic!("Unknown types to OpMul"),
+                }
+            }
+            0x06 => {
+                match (self.pop().as_number(), self.pop().as_number()) {
+                    (Some(rhs), Some(lhs)) => self.push(Value::number(lhs / rhs))?,
+                    _ => panic!("Unknown types to OpDiv"),
+                }
+            }
+            0x0A => {
+                match self.pop().as_number() {
+                    Some(child) => self.push(Value::number(child))?,
+                    _ => panic!("Unknown types to OpPlus"),
+                }
+            }
+            0x0B => {
+                match self.pop().as_number() {
+                    Some(child) => self.push(Value::number(-child))?,
+                    _ => panic!("Unknown types to OpMinus"),
                 }
-                0x04 => {
-                    match (self.pop(), self.pop()) {
-                        (Node::Number(rhs), Node::Number(lhs)) => self.push(Node::Number(lhs - rhs)),
-                        _ => panic!("Unknown types to OpSub"),
-                    }
+            }
+            0x11 => {
+                let (rhs, next) = self.bytecode.bytes_to_constants(ip);
+                ip = next;
+                match self.pop().as_number() {
+                    Some(lhs) => self.push(Value::number(lhs + rhs))?,
+                    _ => panic!("Unknown types to OpAddConst"),
                 }
-                0x05 => {
-                    match (self.pop(), self.pop()) {
-                        (Node::Number(rhs), Node::Number(lhs)) => self.push(Node::Number(lhs * rhs)),
-                        _ => panic!("Unknown types to OpMul"),
-                    }
+            }
+            0x12 => {
+                let (rhs, next) = self.bytecode.bytes_to_constants(ip);
+                ip = next;
+                match self.pop().as_number() {
+                    Some(lhs) => self.push(Value::number(lhs - rhs))?,
+                    _ => panic!("Unknown types to OpSubConst"),
                 }
-                0x06 => {
-                    match (self.pop(), self.pop()) {
-                        (Node::Number(rhs), Node::Number(lhs)) => self.push(Node::Number(lhs / rhs)),
-                        _ => panic!("Unknown types to OpDiv"),
-                    }
+            }
+            0x13 => {
+                let (rhs, next) = self.bytecode.bytes_to_constants(ip);
+                ip = next;
+                match self.pop().as_number() {
+                    Some(lhs) => self.push(Value::number(lhs * rhs))?,
+                    _ => panic!("Unknown types to OpMulConst"),
                 }
-                0x0A => {
-                    match self.pop() {
-                        Node::Number(child) => self.push(Node::Number(child)),
-                        _ => panic!("Unknown types to OpPlus"),
-                    }
+            }
+            0x14 => {
+                let (rhs, next) = self.bytecode.bytes_to_constants(ip);
+                ip = next;
+                match self.pop().as_number() {
+                    Some(lhs) => self.push(Value::number(lhs / rhs))?,
+                    _ => panic!("Unknown types to OpDivConst"),
                 }
-                0x0B => {
-                    match self.pop() {
-                        Node::Number(child) => self.push(Node::Number(-child)),
-                        _ => panic!("Unknown types to OpMinus"),
-                    }
+            }
+            0x20 => {
+                let (index, next) = self.bytecode.bytes_to_index(ip);
+                ip = next;
+                let result = self.call_host(index as usize)?;
+                self.push(Value::number(result))?;
+            }
+            0x21 => {
+                let (index, next) = self.bytecode.bytes_to_index(ip);
+                ip = next;
+                match self.inputs.get(index as usize) {
+                    Some(val) => self.push(Value::number(*val))?,
+                    None => return Err(RunError::MissingInput { index: index as usize }),
                 }
-                _ => panic!("unrecognized opcode")
             }
+            _ => panic!("unrecognized opcode")
         }
+        self.ip = ip;
+        Ok(())
+    }
+
+    // pops the number arguments of the call and returns the result of the host function
+    fn call_host(&mut self, index: usize) -> Result<f64, HostError> {
+        let call = &self.bytecode.calls[index];
+        let function = call.resolve(&self.host)?;
+
+        let numbers = call.args.iter().filter(|arg| **arg == CallArg::Number).count();
+        let start = self.stack_ptr - numbers;
+        let mut stack = self.stack[start..self.stack_ptr].iter();
+        let args: Vec<Arg> = call
+            .args
+            .iter()
+            .map(|arg| match arg {
+                CallArg::Number => match stack.next().unwrap().as_number() {
+                    Some(val) => Arg::Number(val),
+                    None => panic!("Unknown types to OpCall"),
+                },
+                CallArg::Str(value) => Arg::Str(value),
+            })
+            .collect();
+
+        let result = function.call(&args);
+        self.stack_ptr = start;
+        Ok(result)
     }
 
-    fn push(&mut self, node: Node) {
-        self.stack[self.stack_ptr] = node;
+    fn push(&mut self, value: Value) -> Result<(), LimitError> {
+        let max = self.limits.max_stack_depth.min(STACK_SIZE);
+        if self.stack_ptr >= max {
+            return Err(LimitError::StackOverflow { max });
+        }
+        self.stack[self.stack_ptr] = value;
         self.stack_ptr += 1;
+        Ok(())
     }
 
-    // original stack is preserved
-    fn pop(&mut self) -> Node {
-        let node = self.stack[self.stack_ptr - 1].clone();
+    fn pop(&mut self) -> Value {
         self.stack_ptr -= 1;
-        node
+        self.stack[self.stack_ptr]
     }
 
-    pub fn pop_last(&self) -> &Node {
-        // the stack pointer points to the next "free" space,
-        // which also holds the most recently popped element
-        &self.stack[self.stack_ptr]
+    // the value of the last statement executed, which is the result of the
+    // program once it halted; nil until a statement has completed
+    pub fn pop_last(&self) -> Primitive {
+        self.last.into()
     }
 
+    // pop_last, for programs known to compute a number
     pub fn get_result(&self) -> f64 {
-        let node = self.pop_last();
-        match node {
-            Node::Number(dec) => *dec,
-            Node::BinaryExpr { op, lhs, rhs } => panic!("not a number"),
-            Node::UnaryExpr { op, child } => panic!("not a"),
+        match self.pop_last() {
+            Primitive::Number(dec) => dec,
+            other => panic!("not a number: {}", other),
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use calculator_ast_parser::Compile;
+    use calculator_ast_parser::{testing, Compile, Type};
 
-    use crate::bytecode::Interpreter;
+    use crate::{bytecode::Interpreter, column::ColumnVM, opcode::OpCode};
 
     use super::*;
 
-    fn assert_pop_last(source: &str, node: Node) {
+    fn assert_pop_last(source: &str, value: Primitive) {
         let byte_code = Interpreter::from_source(source);
         println!("byte code: {:?}", byte_code);
         let mut vm = VM::new(byte_code);
-        vm.run();
-        assert_eq!(&node, vm.pop_last());
+        vm.run().unwrap();
+        assert_eq!(value, vm.pop_last());
     }
 
     #[test]
     fn unary() {
-        assert_pop_last("+1", Node::Number(1.0));
-        assert_pop_last("-2", Node::Number(-2.0));
+        assert_pop_last("+1", Primitive::Number(1.0));
+        assert_pop_last("-2", Primitive::Number(-2.0));
     }
 
     #[test]
     fn binary() {
-        assert_pop_last("1 + 2;", Node::Number(3.0));
-        assert_pop_last("1 - 2;", Node::Number(-1.0));
+        assert_pop_last("1 + 2;", Primitive::Number(3.0));
+        assert_pop_last("1 - 2;", Primitive::Number(-1.0));
+    }
+
+    #[test]
+    fn script() {
+        assert_pop_last("1 + 2;\n# comment\n3 * 4;", Primitive::Number(12.0));
+    }
+
+    #[test]
+    fn nan() {
+        // the NaN of 0 / 0 must not be mistaken for a boxed value
+        for source in ["0 / 0", "-(0 / 0)", "0 / 0 * 2 + 1", "(0 / 0) / 0"] {
+            let mut vm = VM::new(Interpreter::from_source(source));
+            vm.run().unwrap();
+            assert!(matches!(vm.pop_last(), Primitive::Number(n) if n.is_nan()), "{}", source);
+        }
+    }
+
+    #[test]
+    fn limits() {
+        // 3 constants, 2 operators and the final pop
+        let bytecode = Interpreter::from_source("1 + 2 * 3");
+        let mut vm = VM::with_limits(bytecode.clone(), Limits { fuel: 6, ..Limits::default() });
+        assert_eq!(vm.run(), Ok(()));
+        assert_eq!(vm.get_result(), 7.0);
+
+        let mut vm = VM::with_limits(bytecode.clone(), Limits { fuel: 5, ..Limits::default() });
+        assert_eq!(vm.run(), Err(RunError::Limit(LimitError::OutOfFuel { fuel: 5 })));
+
+        // 1, 2 and 3 are all on the stack before the first operator
+        let mut vm = VM::with_limits(bytecode, Limits { max_stack_depth: 2, ..Limits::default() });
+        assert_eq!(vm.run(), Err(RunError::Limit(LimitError::StackOverflow { max: 2 })));
+
+        // a huge limit is capped by the size of the stack
+        let bytecode: Bytecode = (0..=STACK_SIZE).map(|_| OpCode::OpConstant(1.0)).collect();
+        let limits = Limits { max_stack_depth: usize::MAX, ..Limits::default() };
+        let mut vm = VM::with_limits(bytecode, limits);
+        assert_eq!(vm.run(), Err(RunError::Limit(LimitError::StackOverflow { max: STACK_SIZE })));
+    }
+
+    #[test]
+    fn debugger() {
+        // 0: OpConstant(1), 9: OpConstant(2), 18: OpConstant(3), 27: OpMul, 28: OpAdd, 29: OpPop
+        let mut vm = VM::new(Interpreter::from_source("1 + 2 * 3"));
+        assert_eq!(vm.ip(), 0);
+        assert_eq!(vm.current_opcode(), Some(OpCode::OpConstant(1.0)));
+
+        assert_eq!(vm.step(), Ok(true));
+        assert_eq!(vm.ip(), 9);
+        assert_eq!(vm.stack(), &[Value::number(1.0)]);
+
+        assert!(!vm.set_breakpoint(10));
+        assert!(vm.set_breakpoint(27));
+        assert!(vm.set_breakpoint(29));
+        assert_eq!(vm.resume(), Ok(Stop::Breakpoint(27)));
+        assert_eq!(vm.current_opcode(), Some(OpCode::OpMul));
+        assert_eq!(vm.stack(), &[Value::number(1.0), Value::number(2.0), Value::number(3.0)]);
+
+        assert_eq!(vm.resume(), Ok(Stop::Breakpoint(29)));
+        assert_eq!(vm.stack(), &[Value::number(7.0)]);
+
+        assert!(vm.remove_breakpoint(29));
+        assert_eq!(vm.breakpoints().collect::<Vec<_>>(), vec![27]);
+        assert_eq!(vm.resume(), Ok(Stop::Halted));
+        assert!(vm.is_halted());
+        assert_eq!(vm.current_opcode(), None);
+        assert_eq!(vm.step(), Ok(false));
+        assert_eq!(vm.get_result(), 7.0);
+
+        // running again starts over and ignores breakpoints
+        vm.run().unwrap();
+        assert_eq!(vm.get_result(), 7.0);
+    }
+
+    #[test]
+    fn result() {
+        // 0: OpConstant(1), 9: OpPop, 10: OpConstant(2), 19: OpConstant(3), 28: OpAdd, 29: OpPop
+        let mut vm = VM::new(Interpreter::from_source("1; 2 + 3"));
+        assert_eq!(vm.pop_last(), Primitive::Nil);
+        vm.step().unwrap();
+        assert_eq!(vm.pop_last(), Primitive::Nil);
+        vm.step().unwrap();
+        assert_eq!(vm.pop_last(), Primitive::Number(1.0));
+
+        // popping operands does not change the result, only the end of a statement does
+        for _ in 0..3 {
+            vm.step().unwrap();
+        }
+        assert_eq!(vm.current_opcode(), Some(OpCode::OpPop));
+        assert_eq!(vm.pop_last(), Primitive::Number(1.0));
+        vm.step().unwrap();
+        assert_eq!(vm.get_result(), 5.0);
+
+        // a full stack has no free slot above it to read
+        let bytecode: Bytecode = (0..STACK_SIZE).map(|_| OpCode::OpConstant(1.0)).collect();
+        let mut vm = VM::new(bytecode);
+        vm.limits.max_stack_depth = STACK_SIZE;
+        vm.run().unwrap();
+        assert_eq!(vm.pop_last(), Primitive::Nil);
+    }
+
+    #[test]
+    fn reuse() {
+        let sources = ["1 + 2", "2 * 3 * 4", "-5", "1 / 0", "7; 8"];
+        let mut vm = VM::with_limits(Bytecode::default(), Limits { fuel: 6, ..Limits::default() });
+        for source in sources.iter().chain(sources.iter()) {
+            let bytecode = Interpreter::from_source(source);
+            let mut fresh = VM::new(bytecode.clone());
+            fresh.run().unwrap();
+            assert_eq!(vm.eval(&bytecode), Ok(fresh.pop_last()), "{}", source);
+        }
+        // the fuel is per program, not for the lifetime of the vm
+        assert_eq!(
+            vm.eval(&Interpreter::from_source("1 + 2 * 3 + 4")),
+            Err(RunError::Limit(LimitError::OutOfFuel { fuel: 6 }))
+        );
+
+        // reset goes back to the start of the same program
+        let mut vm = VM::new(Interpreter::from_source("1 + 2 * 3"));
+        assert!(vm.set_breakpoint(27));
+        assert_eq!(vm.resume(), Ok(Stop::Breakpoint(27)));
+        vm.reset();
+        assert_eq!((vm.ip(), vm.stack(), vm.pop_last()), (0, &[][..], Primitive::Nil));
+        assert_eq!(vm.resume(), Ok(Stop::Breakpoint(27)));
+
+        // load swaps the program and forgets breakpoints that belonged to the old one
+        vm.load(Interpreter::from_source("4 - 5"));
+        assert_eq!(vm.breakpoints().count(), 0);
+        assert_eq!(vm.resume(), Ok(Stop::Halted));
+        assert_eq!(vm.get_result(), -1.0);
+    }
+
+    #[test]
+    fn host_functions() {
+        use calculator_ast_parser::parser;
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        };
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let mut host = Host::new();
+        host.register("lookup_rate", &[Type::Str], |args| match args[0].as_str() {
+            Some("EUR") => 1.25,
+            _ => f64::NAN,
+        })
+        .register("clamp", &[Type::Number, Type::Str, Type::Number], move |args| {
+            counter.fetch_add(1, Ordering::Relaxed);
+            let (val, max) = (args[0].as_number().unwrap(), args[2].as_number().unwrap());
+            assert_eq!(args[1].as_str(), Some("max"));
+            val.min(max)
+        });
+
+        let source = "100 * lookup_rate(\"EUR\"); 2 * clamp(7 + 8, \"max\", clamp(3, \"max\", 10)) - 1";
+        let ast = parser::parse(source).unwrap();
+        let bytecode = Interpreter::try_from_ast_with_host(ast, &Limits::default(), &host).unwrap();
+        let mut vm = VM::new(bytecode);
+        vm.set_host(host.clone());
+        vm.run().unwrap();
+        assert_eq!(vm.get_result(), 5.0);
+        assert_eq!(vm.stack(), &[]);
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+
+        // the calls are checked when compiling
+        let error = Interpreter::try_from_ast_with_host(parser::parse("clamp(1, 2, 3)").unwrap(), &Limits::default(), &host);
+        assert_eq!(error.unwrap_err().to_string(), "argument 2 of `clamp` must be a string");
+
+        // a call costs one unit of fuel, its arguments are on the stack
+        let bytecode = Interpreter::from_source("lookup_rate(\"EUR\") + clamp(1, \"max\", 2)");
+        let mut vm = VM::with_limits(bytecode.clone(), Limits { fuel: 6, ..Limits::default() });
+        vm.set_host(host.clone());
+        assert_eq!(vm.eval(&bytecode), Ok(Primitive::Number(2.25)));
+        let mut vm = VM::with_limits(bytecode, Limits { max_stack_depth: 2, ..Limits::default() });
+        vm.set_host(host);
+        assert_eq!(vm.run(), Err(RunError::Limit(LimitError::StackOverflow { max: 2 })));
+    }
+
+    #[test]
+    fn missing_input() {
+        let ast = calculator_ast_parser::parser::parse("x * y").unwrap();
+        let inputs = calculator_ast_parser::Inputs::new(&["x", "y"]);
+        let bytecode = Interpreter::try_from_ast_with_inputs(ast, &Limits::default(), &Host::default(), &inputs).unwrap();
+        let mut vm = VM::new(bytecode);
+        assert_eq!(vm.run(), Err(RunError::MissingInput { index: 0 }));
+        vm.set_inputs(&[2.0]);
+        assert_eq!(vm.run(), Err(RunError::MissingInput { index: 1 }));
+        assert_eq!(vm.run().unwrap_err().to_string(), "input 1 is not set");
+        vm.set_inputs(&[2.0, 3.0]);
+        vm.run().unwrap();
+        assert_eq!(vm.get_result(), 6.0);
+    }
+
+    // bytecode run without the host it was compiled with
+    #[test]
+    fn unknown_host_function() {
+        let bytecode = Interpreter::from_source("lookup_rate(\"EUR\")");
+        let error = HostError::UnknownFunction("lookup_rate".to_string());
+        assert_eq!(VM::new(bytecode.clone()).run(), Err(RunError::Host(error.clone())));
+        assert_eq!(ColumnVM::new(&bytecode, &Host::default()).err(), Some(error));
+
+        let mut host = Host::new();
+        host.register("lookup_rate", &[Type::Number], |args| args[0].as_number().unwrap());
+        let error = HostError::WrongType { name: "lookup_rate".to_string(), position: 0, expected: Type::Number };
+        let mut vm = VM::new(bytecode.clone());
+        vm.set_host(host.clone());
+        assert_eq!(vm.run(), Err(RunError::Host(error.clone())));
+        assert_eq!(vm.stack(), &[]);
+        assert_eq!(ColumnVM::new(&bytecode, &host).err(), Some(error));
+
+        let mut host = Host::new();
+        host.register("lookup_rate", &[], |_| 1.0);
+        let mut vm = VM::new(bytecode);
+        vm.set_host(host);
+        let error = HostError::WrongArity { name: "lookup_rate".to_string(), expected: 0, found: 1 };
+        assert_eq!(vm.run().unwrap_err().to_string(), error.to_string());
+    }
+
+    // would overflow the native stack without the depth limits,
+    // goes through every engine of the crate
+    #[test]
+    fn pathological() {
+        use crate::{decoded::DecodedVM, register};
+        use calculator_ast_parser::{parser, Host, Inputs};
+
+        let max_depth = Limits::default().max_depth;
+        for source in testing::pathological_sources() {
+            assert!(crate::expr::VmExpr::compile(&source, Inputs::new(&["x"]), &Host::default()).is_err());
+        }
+
+        let limits = Limits::default();
+        let error = LimitError::TooDeep { max: max_depth };
+        assert_eq!(Interpreter::try_from_ast(vec![testing::deep(max_depth + 1)], &limits), Err(error.clone()));
+        assert_eq!(register::Compiler::try_from_ast(vec![testing::deep(max_depth + 1)], &limits), Err(error));
+
+        // right at the limit
+        let (chain, expected) = testing::deepest_chain();
+        let ast = parser::parse(&chain).unwrap();
+
+        let bytecode = Interpreter::try_from_ast(ast.clone(), &limits).unwrap();
+        let mut decoded = DecodedVM::new(&bytecode);
+        decoded.run();
+        assert_eq!(decoded.get_result(), expected);
+        let mut vm = VM::new(bytecode);
+        vm.run().unwrap();
+        assert_eq!(vm.get_result(), expected);
+
+        let program = register::Compiler::try_from_ast(ast.clone(), &limits).unwrap();
+        let mut register_vm = register::RegisterVM::new(program);
+        register_vm.run();
+        assert_eq!(register_vm.get_result(), expected);
+
+        let fused = Interpreter::from_ast_with_superinstructions(ast);
+        let mut vm = VM::new(fused);
+        vm.run().unwrap();
+        assert_eq!(vm.get_result(), expected);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn signs() {
+        for (source, expected) in testing::sign_matrix() {
+            let mut vm = VM::new(Interpreter::from_source(&source));
+            vm.run().unwrap();
+            assert_eq!(vm.get_result(), expected, "{}", source);
+
+            let ast = calculator_ast_parser::parser::parse(&source).unwrap();
+            let mut vm = VM::new(Interpreter::from_ast_with_superinstructions(ast));
+            vm.run().unwrap();
+            assert_eq!(vm.get_result(), expected, "{}", source);
+        }
+    }
+}